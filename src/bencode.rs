@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+// nesting limit, protects the decoder from stack exhaustion on hostile input
+const MAX_DEPTH: usize = 64;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("Unexpected end of input")]
+    Eof,
+    #[error("Invalid integer at {0}")]
+    Int(usize),
+    #[error("Invalid byte string length at {0}")]
+    Len(usize),
+    #[error("Unexpected byte {0:#04x} at {1}")]
+    Unexpected(u8, usize),
+    #[error("Duplicate dictionary key at {0}")]
+    DuplicateKey(usize),
+    #[error("Nesting deeper than {MAX_DEPTH}")]
+    Depth,
+    #[error("Trailing data at {0}")]
+    Trailing(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    // decode exactly one value, the whole buffer must be consumed
+    pub fn decode(buf: &[u8]) -> Result<Value, Error> {
+        let (value, len) = Self::decode_prefix(buf)?;
+        if len != buf.len() {
+            return Err(Error::Trailing(len));
+        }
+        Ok(value)
+    }
+
+    // decode one value from the start of buffer, returns value and consumed length
+    pub fn decode_prefix(buf: &[u8]) -> Result<(Value, usize), Error> {
+        let mut decoder = Decoder { buf, pos: 0 };
+        let value = decoder.value(0)?;
+        Ok((value, decoder.pos))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(b) => {
+                out.extend_from_slice(b.len().to_string().as_bytes());
+                out.push(b':');
+                out.extend_from_slice(b);
+            }
+            Value::List(l) => {
+                out.push(b'l');
+                for v in l {
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(d) => {
+                out.push(b'd');
+                for (k, v) in d {
+                    out.extend_from_slice(k.len().to_string().as_bytes());
+                    out.push(b':');
+                    out.extend_from_slice(k);
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+// builds a dictionary value from (key, value) pairs
+pub fn dict<const N: usize>(pairs: [(&str, Value); N]) -> Value {
+    Value::Dict(
+        pairs
+            .into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v))
+            .collect(),
+    )
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, Error> {
+        self.buf.get(self.pos).copied().ok_or(Error::Eof)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Depth);
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let start = self.pos;
+                let i = self.int_until(b'e')?;
+                // leading zeros and negative zero are not allowed
+                let raw = &self.buf[start..self.pos - 1];
+                if raw.starts_with(b"-0") || (raw.len() > 1 && raw[0] == b'0') {
+                    return Err(Error::Int(start));
+                }
+                Ok(Value::Int(i))
+            }
+            b'0'..=b'9' => self.bytes().map(|b| Value::Bytes(b.to_vec())),
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key_pos = self.pos;
+                    let key = self.bytes()?.to_vec();
+                    let value = self.value(depth + 1)?;
+                    // some clients don't sort keys, tolerate it but not duplicates
+                    if dict.insert(key, value).is_some() {
+                        return Err(Error::DuplicateKey(key_pos));
+                    }
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b => Err(Error::Unexpected(b, self.pos)),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let start = self.pos;
+        let len = self.int_until(b':')?;
+        let len = usize::try_from(len).map_err(|_| Error::Len(start))?;
+        let end = self.pos.checked_add(len).ok_or(Error::Len(start))?;
+        if end > self.buf.len() {
+            return Err(Error::Eof);
+        }
+        let b = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn int_until(&mut self, term: u8) -> Result<i64, Error> {
+        let start = self.pos;
+        let end = self.buf[start..]
+            .iter()
+            .position(|&b| b == term)
+            .ok_or(Error::Eof)?
+            + start;
+        let i = std::str::from_utf8(&self.buf[start..end])
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(Error::Int(start))?;
+        self.pos = end + 1;
+        Ok(i)
+    }
+}
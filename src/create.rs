@@ -0,0 +1,230 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use parking_lot::Mutex;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    bencode::Value,
+    metainfo::{self, MetaInfo, PieceLayers},
+    storage::{FileSpan, Storage},
+    BLOCK_SIZE,
+};
+
+// automatic piece lengths aim at that many pieces
+const TARGET_PIECES: u64 = 2048;
+const MAX_PIECE_LENGTH: u64 = 16 << 20;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot read files: {0}")]
+    Io(#[from] io::Error),
+    #[error("Nothing to share")]
+    Empty,
+    #[error("Piece length must be a power of two of at least 16 KiB")]
+    PieceLength,
+    #[error("Created torrent does not parse: {0}")]
+    MetaInfo(#[from] metainfo::Error),
+}
+
+// what goes into a new torrent besides the files
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // None picks one from the total size
+    pub piece_length: Option<u64>,
+    // tiers of trackers, the first one is also the announce url
+    pub trackers: Vec<Vec<String>>,
+    // url-list, see BEP 19
+    pub web_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // unix time
+    pub creation_date: Option<i64>,
+    // see BEP 27
+    pub private: bool,
+    // makes the info hash differ between trackers of a cross seeded torrent
+    pub source: Option<String>,
+    // hashing threads, 0 uses every cpu
+    pub threads: usize,
+}
+
+// v1 torrent of a file or of every file below a directory, files are
+// sorted by path like other tools do so the same data and options give
+// the same info hash
+pub fn create<P: AsRef<Path>>(path: P, options: &CreateOptions) -> Result<MetaInfo, Error> {
+    let path = path.as_ref().canonicalize()?;
+    let name = path
+        .file_name()
+        .ok_or(Error::Empty)?
+        .to_string_lossy()
+        .into_owned();
+    let root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let single = fs::metadata(&path)?.is_file();
+    // paths start with the name, as in storage
+    let mut files = Vec::new();
+    if single {
+        files.push((PathBuf::from(&name), fs::metadata(&path)?.len()));
+    } else {
+        walk(&path, &mut PathBuf::from(&name), &mut files)?;
+        files.sort();
+    }
+    if files.is_empty() {
+        return Err(Error::Empty);
+    }
+    let length = files.iter().map(|(_, len)| len).sum::<u64>();
+    let piece_length = match options.piece_length {
+        Some(l) if l.is_power_of_two() && l >= BLOCK_SIZE as u64 => l,
+        Some(_) => return Err(Error::PieceLength),
+        None => piece_length(length),
+    };
+
+    let mut offset = 0;
+    let spans = files
+        .iter()
+        .map(|(file, len)| {
+            let span = FileSpan {
+                path: file.clone(),
+                offset,
+                len: *len,
+            };
+            offset += len;
+            span
+        })
+        .collect();
+    let storage = Storage {
+        root,
+        files: spans,
+        piece_length,
+        length,
+    };
+    let pieces = hash_pieces(&storage, options.threads)?;
+
+    let mut info = BTreeMap::new();
+    if single {
+        info.insert("length", Value::Int(length as i64));
+    } else {
+        let files = files
+            .iter()
+            .map(|(path, len)| {
+                let path = path
+                    .iter()
+                    .skip(1)
+                    .map(|c| Value::from(c.to_string_lossy().as_ref()))
+                    .collect();
+                Value::Dict(BTreeMap::from([
+                    (b"length".to_vec(), Value::Int(*len as i64)),
+                    (b"path".to_vec(), Value::List(path)),
+                ]))
+            })
+            .collect();
+        info.insert("files", Value::List(files));
+    }
+    info.insert("name", Value::from(name.as_str()));
+    info.insert("piece length", Value::Int(piece_length as i64));
+    info.insert("pieces", Value::Bytes(pieces.concat()));
+    if options.private {
+        info.insert("private", Value::Int(1));
+    }
+    if let Some(source) = &options.source {
+        info.insert("source", Value::from(source.as_str()));
+    }
+
+    let info_bytes = dict(info).encode();
+    let trackers = options
+        .trackers
+        .iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect::<Vec<_>>();
+    Ok(MetaInfo {
+        announce: trackers.first().map(|tier| tier[0].clone()),
+        // a single tracker needs no list
+        announce_list: match trackers.iter().map(Vec::len).sum::<usize>() {
+            0 | 1 => Vec::new(),
+            _ => trackers,
+        },
+        url_list: options.web_seeds.clone(),
+        http_seeds: Vec::new(),
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date: options.creation_date,
+        ..MetaInfo::from_info(info_bytes, PieceLayers::new())?
+    })
+}
+
+// regular files below dir with their path under rel, links are followed
+fn walk(dir: &Path, rel: &mut PathBuf, files: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = fs::metadata(entry.path())?;
+        rel.push(entry.file_name());
+        if meta.is_dir() {
+            walk(&entry.path(), rel, files)?;
+        } else if meta.is_file() {
+            files.push((rel.clone(), meta.len()));
+        }
+        rel.pop();
+    }
+    Ok(())
+}
+
+// power of two giving about TARGET_PIECES pieces
+fn piece_length(length: u64) -> u64 {
+    (length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(BLOCK_SIZE as u64, MAX_PIECE_LENGTH)
+}
+
+// threads take the next piece to hash until none is left
+fn hash_pieces(storage: &Storage, threads: usize) -> Result<Vec<[u8; 20]>, Error> {
+    let count = storage.piece_count();
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(count.max(1));
+    let next = AtomicUsize::new(0);
+    let pieces = Mutex::new(vec![[0; 20]; count]);
+    thread::scope(|s| {
+        let workers = (0..threads)
+            .map(|_| {
+                s.spawn(|| -> io::Result<()> {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= count {
+                            return Ok(());
+                        }
+                        let data = match storage.read(index, 0, storage.piece_len(index)) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                // the others stop at their next piece
+                                next.store(count, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        };
+                        pieces.lock()[index] = Sha1::digest(data).into();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .try_for_each(|w| w.join().unwrap_or(Err(io::ErrorKind::Other.into())))
+    })?;
+    Ok(pieces.into_inner())
+}
+
+fn dict(map: BTreeMap<&str, Value>) -> Value {
+    Value::Dict(
+        map.into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v))
+            .collect(),
+    )
+}
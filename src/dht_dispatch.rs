@@ -1,57 +1,990 @@
-use std::{net::SocketAddr, thread};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use bittorrent_peer_proto::message;
-use crossbeam_channel::{Receiver, Sender};
+use crate::{
+    bencode::Value,
+    dht_item::{self, Item},
+    dht_proto::{self, Body, DhtProto, Message, Node, NodeId, Query, Response, Want},
+    peer_proto::message,
+    routing_table::{self, distance, RoutingTable, K},
+};
+use flume::{Receiver, Sender};
+use parking_lot::Mutex;
+use rand::seq::IteratorRandom;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::lookup_host,
+    task::{JoinHandle, JoinSet},
+    time::{interval, sleep},
+};
 
+const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+// parallel queries of one lookup
+const ALPHA: usize = 3;
+const MAX_LOOKUP_QUERIES: usize = 128;
+// peers of active torrents are looked up again after it
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// lookup which found nothing, routing table may still be filling
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+// bootstraps which add no node are retried less and less often, down to it
+const MAX_BOOTSTRAP_BACKOFF: Duration = Duration::from_secs(60 * 60);
+const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// announced peers are forgotten after it, see BEP 5
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// values per get_peers answer, keeps it within one datagram
+const MAX_VALUES: usize = 50;
+const MAX_STORED_TORRENTS: usize = 4096;
+const MAX_STORED_PEERS: usize = 512;
+// stored items are dropped after it unless put again, see BEP 44
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_STORED_ITEMS: usize = 1024;
+// a node from a peer port message is not pinged again before it
+const PORT_RETRY: Duration = Duration::from_secs(15 * 60);
+// queries of one ip beyond it within the window are dropped
+const MAX_QUERIES_PER_IP: u32 = 25;
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+// nodes answering with garbage are ignored for it
+const BAN_TIME: Duration = Duration::from_secs(30 * 60);
+// distinct nodes telling the same external ip before we take an id for it
+const EXTERNAL_IP_VOTES: usize = 5;
+const MAX_VOTED_IPS: usize = 32;
+// infohashes per sample_infohashes answer and how long they are kept,
+// see BEP 51
+const MAX_SAMPLES: usize = 20;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// longest interval a node may ask the crawler to wait, and the wait for
+// nodes which did not answer
+const MAX_SAMPLE_INTERVAL: u64 = 6 * 60 * 60;
+const CRAWL_RETRY: Duration = Duration::from_secs(15 * 60);
+const CRAWL_PARALLEL: usize = 8;
+const MAX_CRAWL_QUEUE: usize = 4096;
+
+// one address family of the dht with its own socket and routing table,
+// see BEP 32
+struct Net {
+    proto: DhtProto,
+    table: Mutex<RoutingTable>,
+}
+
+// node state shared by the dht tasks, see BEP 5
+struct Dht {
+    v4: Net,
+    v6: Option<Net>,
+    // peer listen port we announce
+    port: u16,
+    implied_port: bool,
+    // peers other nodes announced to us
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddr, Instant>>>,
+    // items other nodes put to us
+    items: Mutex<HashMap<NodeId, (Item, Instant)>>,
+    // infohashes of the current sample_infohashes answers
+    samples: Mutex<Option<(Instant, Vec<[u8; 20]>)>>,
+    // current and previous token secret
+    secrets: Mutex<[[u8; 20]; 2]>,
+    // queries per ip in the current window
+    limits: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    banned: Mutex<HashMap<IpAddr, Instant>>,
+    // our external ip as told by the nodes we query, with who told it
+    votes: Mutex<HashMap<IpAddr, HashSet<IpAddr>>>,
+    // routing table is loaded from and saved to it
+    state: Option<PathBuf>,
+}
+
+impl Dht {
+    // both tables share it
+    fn id(&self) -> NodeId {
+        self.v4.table.lock().id
+    }
+
+    fn net(&self, v6: bool) -> Option<&Net> {
+        match v6 {
+            false => Some(&self.v4),
+            true => self.v6.as_ref(),
+        }
+    }
+
+    fn nets(&self) -> impl Iterator<Item = &Net> {
+        [Some(&self.v4), self.v6.as_ref()].into_iter().flatten()
+    }
+
+    // every family we have a socket for
+    fn families(&self) -> Want {
+        Want {
+            n4: true,
+            n6: self.v6.is_some(),
+        }
+    }
+
+    fn table_len(&self, v6: bool) -> usize {
+        self.net(v6).map_or(0, |net| net.table.lock().len())
+    }
+
+    // the routing table learns from every outcome
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, dht_proto::Error> {
+        let Some(net) = self.net(addr.is_ipv6()) else {
+            return Err(io::Error::from(io::ErrorKind::AddrNotAvailable).into());
+        };
+        if self.banned.lock().contains_key(&addr.ip()) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied).into());
+        }
+        let local = self.id();
+        let res = net.proto.query(addr, local, query).await;
+        match &res {
+            Ok(r) if garbage(r, &local) => {
+                net.table.lock().remove(addr);
+                self.banned.lock().insert(addr.ip(), Instant::now());
+                return Err(dht_proto::Error::Malformed);
+            }
+            Ok(r) => {
+                net.table.lock().insert(Node { id: r.id, addr });
+                if let (Some(ip), true) = (r.ip, addr.is_ipv4()) {
+                    self.vote(ip.ip(), addr.ip());
+                }
+            }
+            Err(dht_proto::Error::Timeout) => net.table.lock().failed(addr),
+            Err(_) => (),
+        }
+        res
+    }
+
+    // enough agreeing nodes make us take a BEP 42 id for the ip, ipv6
+    // answers do not vote so the id stays bound to the ipv4 one
+    fn vote(&self, external: IpAddr, voter: IpAddr) {
+        {
+            let mut votes = self.votes.lock();
+            if votes.len() >= MAX_VOTED_IPS && !votes.contains_key(&external) {
+                votes.clear();
+            }
+            let voters = votes.entry(external).or_default();
+            voters.insert(voter);
+            if voters.len() < EXTERNAL_IP_VOTES {
+                return;
+            }
+            votes.clear();
+        }
+        if !routing_table::is_secure(&self.id(), external) {
+            let id = routing_table::secure_id(external);
+            for net in self.nets() {
+                net.table.lock().set_id(id);
+            }
+        }
+    }
+
+    // false once ip sent too many queries within the window
+    fn allow(&self, ip: IpAddr) -> bool {
+        if self.banned.lock().contains_key(&ip) {
+            return false;
+        }
+        let mut limits = self.limits.lock();
+        let (start, count) = limits.entry(ip).or_insert((Instant::now(), 0));
+        if start.elapsed() >= RATE_WINDOW {
+            *start = Instant::now();
+            *count = 0;
+        }
+        *count += 1;
+        *count <= MAX_QUERIES_PER_IP
+    }
+
+    fn token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        Sha1::new().chain_update(secret).chain_update(ip).finalize()[..8].to_vec()
+    }
+
+    // tokens of the previous secret stay valid until the next rotation
+    fn valid_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        self.secrets
+            .lock()
+            .iter()
+            .any(|s| Self::token(s, ip) == token)
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], addr: SocketAddr) {
+        let mut peers = self.peers.lock();
+        if peers.len() >= MAX_STORED_TORRENTS && !peers.contains_key(&info_hash) {
+            return;
+        }
+        let torrent = peers.entry(info_hash).or_default();
+        if torrent.len() < MAX_STORED_PEERS || torrent.contains_key(&addr) {
+            torrent.insert(addr, Instant::now());
+        }
+    }
+
+    // closest nodes of the families asked for, by default the family the
+    // query came over
+    fn closest(&self, target: &NodeId, want: Want, addr: SocketAddr) -> (Vec<Node>, Vec<Node>) {
+        let want = match want {
+            Want {
+                n4: false,
+                n6: false,
+            } => Want {
+                n4: addr.is_ipv4(),
+                n6: addr.is_ipv6(),
+            },
+            want => want,
+        };
+        let closest = |v6, wanted| match self.net(v6) {
+            Some(net) if wanted => net.table.lock().closest(target, K),
+            _ => Vec::new(),
+        };
+        (closest(false, want.n4), closest(true, want.n6))
+    }
+
+    // infohashes we hand out with the seconds until they are drawn again
+    fn samples(&self) -> (i64, Vec<[u8; 20]>) {
+        let mut samples = self.samples.lock();
+        if samples
+            .as_ref()
+            .is_none_or(|(t, _)| t.elapsed() >= SAMPLE_INTERVAL)
+        {
+            let drawn = self
+                .peers
+                .lock()
+                .keys()
+                .copied()
+                .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLES);
+            *samples = Some((Instant::now(), drawn));
+        }
+        let (drawn_at, drawn) = samples.as_ref().unwrap();
+        let left = SAMPLE_INTERVAL.saturating_sub(drawn_at.elapsed());
+        (left.as_secs() as i64, drawn.clone())
+    }
+
+    // checks an item put to us, a mutable one replaces an older one
+    fn store_item(&self, item: Item, cas: Option<i64>) -> Result<(), (i64, String)> {
+        item.verify().map_err(|e| (e.code(), e.to_string()))?;
+        let target = item.target();
+        let mut items = self.items.lock();
+        if let (Some(seq), Some((old, _))) = (item.seq(), items.get(&target)) {
+            let old = old.seq().unwrap_or(0);
+            if cas.is_some_and(|cas| cas != old) {
+                return Err((301, "CAS mismatch".to_string()));
+            }
+            if seq < old {
+                return Err((302, "Sequence number less than current".to_string()));
+            }
+        }
+        if items.len() < MAX_STORED_ITEMS || items.contains_key(&target) {
+            items.insert(target, (item, Instant::now()));
+        }
+        Ok(())
+    }
+
+    // answers a query of another node
+    fn respond(&self, id: NodeId, query: Query, addr: SocketAddr) -> Body {
+        if let Some(net) = self.net(addr.is_ipv6()).filter(|_| valid_addr(&addr)) {
+            net.table.lock().insert(Node { id, addr });
+        }
+        let local = self.id();
+        let mut response = match query {
+            Query::Ping => Response {
+                id: local,
+                ..Default::default()
+            },
+            Query::FindNode { target, want } => {
+                let (nodes, nodes6) = self.closest(&target, want, addr);
+                Response {
+                    id: local,
+                    nodes,
+                    nodes6,
+                    ..Default::default()
+                }
+            }
+            Query::GetPeers { info_hash, want } => {
+                // peers of the family the query came over
+                let values = self
+                    .peers
+                    .lock()
+                    .get(&info_hash)
+                    .map(|p| {
+                        p.keys()
+                            .filter(|p| p.is_ipv6() == addr.is_ipv6())
+                            .take(MAX_VALUES)
+                            .copied()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let (nodes, nodes6) = self.closest(&info_hash, want, addr);
+                Response {
+                    id: local,
+                    nodes,
+                    nodes6,
+                    values,
+                    token: Some(Self::token(&self.secrets.lock()[0], addr.ip())),
+                    ..Default::default()
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if !self.valid_token(&token, addr.ip()) {
+                    return Body::Error {
+                        code: 203,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { addr.port() } else { port };
+                self.store_peer(info_hash, SocketAddr::new(addr.ip(), port));
+                Response {
+                    id: local,
+                    ..Default::default()
+                }
+            }
+            Query::Get { target, seq } => {
+                let (nodes, nodes6) = self.closest(&target, Want::default(), addr);
+                let mut response = Response {
+                    id: local,
+                    nodes,
+                    nodes6,
+                    token: Some(Self::token(&self.secrets.lock()[0], addr.ip())),
+                    ..Default::default()
+                };
+                if let Some((item, _)) = self.items.lock().get(&target) {
+                    response.seq = item.seq();
+                    // the asking node already has this one or a newer one
+                    let newer = match (&item.mutable, seq) {
+                        (Some(m), Some(seq)) => m.seq > seq,
+                        _ => true,
+                    };
+                    if newer {
+                        response.v = Some(item.v.clone());
+                        response.k = item.mutable.as_ref().map(|m| m.k);
+                        response.sig = item.mutable.as_ref().map(|m| m.sig);
+                    }
+                }
+                response
+            }
+            Query::Put { token, item, cas } => {
+                if !self.valid_token(&token, addr.ip()) {
+                    return Body::Error {
+                        code: 203,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                if let Err((code, message)) = self.store_item(item, cas) {
+                    return Body::Error { code, message };
+                }
+                Response {
+                    id: local,
+                    ..Default::default()
+                }
+            }
+            Query::SampleInfohashes { target, want } => {
+                let (nodes, nodes6) = self.closest(&target, want, addr);
+                let (interval, samples) = self.samples();
+                Response {
+                    id: local,
+                    nodes,
+                    nodes6,
+                    interval: Some(interval),
+                    num: Some(self.peers.lock().len() as i64),
+                    samples,
+                    ..Default::default()
+                }
+            }
+            Query::Unknown(_) => {
+                return Body::Error {
+                    code: 204,
+                    message: "Method Unknown".to_string(),
+                }
+            }
+        };
+        response.ip = Some(addr);
+        Body::Response(response)
+    }
+
+    async fn serve(self: Arc<Self>, v6: bool) {
+        let Some(net) = self.net(v6) else {
+            return;
+        };
+        loop {
+            let (msg, addr) = match net.proto.recv().await {
+                Ok(received) => received,
+                // the shared utp socket is gone
+                Err(dht_proto::Error::Io(e)) if e.kind() == io::ErrorKind::NotConnected => return,
+                Err(_) => continue,
+            };
+            if !self.allow(addr.ip()) {
+                continue;
+            }
+            if let Body::Query { id, query } = msg.body {
+                let body = self.respond(id, query, addr);
+                #[allow(unused_must_use)]
+                {
+                    net.proto.send(addr, &Message { t: msg.t, body }).await;
+                }
+            }
+        }
+    }
+
+    // iterative search of one family for the nodes closest to target,
+    // sending query to each and handing every answer to on_response;
+    // returns the closest nodes which answered along with their tokens
+    async fn lookup(
+        self: &Arc<Self>,
+        v6: bool,
+        target: NodeId,
+        query: Query,
+        mut on_response: impl FnMut(&Response),
+    ) -> Vec<(Node, Option<Vec<u8>>)> {
+        let Some(net) = self.net(v6) else {
+            return Vec::new();
+        };
+        let local = self.id();
+        let mut candidates = net
+            .table
+            .lock()
+            .closest(&target, K)
+            .into_iter()
+            .map(|n| (distance(&n.id, &target), n))
+            .collect::<BTreeMap<_, _>>();
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut queries = JoinSet::new();
+        loop {
+            while queries.len() < ALPHA && queried.len() < MAX_LOOKUP_QUERIES {
+                let Some((d, node)) = candidates.pop_first() else {
+                    break;
+                };
+                // farther than the k closest answers so far, so are the rest
+                if answered.keys().nth(K - 1).is_some_and(|kth| d > *kth) {
+                    candidates.clear();
+                    break;
+                }
+                if !queried.insert(node.addr) {
+                    continue;
+                }
+                let query = query.clone();
+                let dht = self.clone();
+                queries.spawn(async move { (node.addr, dht.query(node.addr, query).await) });
+            }
+            let Some(res) = queries.join_next().await else {
+                break;
+            };
+            let Ok((addr, Ok(r))) = res else {
+                continue;
+            };
+            on_response(&r);
+            let nodes = if v6 { r.nodes6 } else { r.nodes };
+            for n in nodes {
+                if n.id != local && !queried.contains(&n.addr) {
+                    candidates.insert(distance(&n.id, &target), n);
+                }
+            }
+            let node = Node { id: r.id, addr };
+            answered.insert(distance(&node.id, &target), (node, r.token));
+        }
+        answered.into_values().take(K).collect()
+    }
+
+    // tells the closest nodes of a lookup that we have the torrent, each
+    // with the token it gave us
+    async fn announce(self: &Arc<Self>, info_hash: [u8; 20], nodes: Vec<(Node, Option<Vec<u8>>)>) {
+        let mut queries = JoinSet::new();
+        for (node, token) in nodes {
+            let Some(token) = token else {
+                continue;
+            };
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: self.port,
+                token,
+                implied_port: self.implied_port,
+            };
+            let dht = self.clone();
+            queries.spawn(async move { dht.query(node.addr, query).await });
+        }
+        while queries.join_next().await.is_some() {}
+    }
+
+    // closest nodes of the wanted families, found with a find_node lookup
+    async fn find_node(self: &Arc<Self>, target: NodeId, want: Want) {
+        let query = Query::FindNode {
+            target,
+            want: Want::default(),
+        };
+        let lookup = |v6: bool, wanted: bool| {
+            let query = query.clone();
+            async move {
+                if wanted {
+                    self.lookup(v6, target, query, |_| ()).await;
+                }
+            }
+        };
+        tokio::join!(lookup(false, want.n4), lookup(true, want.n6));
+    }
+
+    // valid items stored at target in both families, along with the
+    // closest nodes and their put tokens
+    async fn get(
+        self: &Arc<Self>,
+        target: NodeId,
+        salt: &[u8],
+    ) -> (Vec<Item>, Vec<(Node, Option<Vec<u8>>)>) {
+        if self.nets().all(|net| net.table.lock().is_empty()) {
+            self.bootstrap(self.families()).await;
+        }
+        let query = Query::Get { target, seq: None };
+        let (mut items, mut items6) = (Vec::new(), Vec::new());
+        let collect = |items: &mut Vec<Item>, r: &Response| {
+            let item = Item::from_response(r, salt)
+                .filter(|item| item.target() == target && item.verify().is_ok());
+            items.extend(item);
+        };
+        let (nodes, nodes6) = tokio::join!(
+            self.lookup(false, target, query.clone(), |r| collect(&mut items, r)),
+            self.lookup(true, target, query, |r| collect(&mut items6, r)),
+        );
+        items.append(&mut items6);
+        (items, [nodes, nodes6].concat())
+    }
+
+    // puts item to the closest nodes of target, returns how many took it
+    async fn put(self: &Arc<Self>, item: Item, cas: Option<i64>) -> usize {
+        let salt = item.mutable.as_ref().map(|m| m.salt.clone());
+        let (_, nodes) = self.get(item.target(), &salt.unwrap_or_default()).await;
+        let mut queries = JoinSet::new();
+        for (node, token) in nodes {
+            let Some(token) = token else {
+                continue;
+            };
+            let query = Query::Put {
+                token,
+                item: item.clone(),
+                cas,
+            };
+            let dht = self.clone();
+            queries.spawn(async move { dht.query(node.addr, query).await });
+        }
+        let mut stored = 0;
+        while let Some(res) = queries.join_next().await {
+            if let Ok(Ok(_)) = res {
+                stored += 1;
+            }
+        }
+        stored
+    }
+
+    // asks nodes all over the keyspace for samples of their infohashes,
+    // every new one goes to send; ends once send is disconnected
+    async fn crawl(self: Arc<Self>, send: Sender<[u8; 20]>) {
+        let mut found = HashSet::new();
+        // nodes asked, with when they may be asked again
+        let mut asked = HashMap::<SocketAddr, Instant>::new();
+        let mut queue = VecDeque::new();
+        let mut queries = JoinSet::new();
+        let want = Want {
+            n4: true,
+            n6: self.v6.is_some(),
+        };
+        if self.nets().all(|net| net.table.lock().is_empty()) {
+            self.bootstrap(self.families()).await;
+        }
+        while !send.is_disconnected() {
+            if queue.is_empty() && queries.is_empty() {
+                asked.retain(|_, t| *t > Instant::now());
+                let target = rand::random();
+                for net in self.nets() {
+                    let nodes = net.table.lock().closest(&target, K);
+                    queue.extend(nodes.into_iter().map(|n| n.addr));
+                }
+                queue.retain(|addr| !asked.contains_key(addr));
+                if queue.is_empty() {
+                    sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            }
+            while queries.len() < CRAWL_PARALLEL {
+                let Some(addr) = queue.pop_front() else {
+                    break;
+                };
+                asked.insert(addr, Instant::now() + CRAWL_RETRY);
+                let query = Query::SampleInfohashes {
+                    target: rand::random(),
+                    want,
+                };
+                let dht = self.clone();
+                queries.spawn(async move { (addr, dht.query(addr, query).await) });
+            }
+            let Some(res) = queries.join_next().await else {
+                continue;
+            };
+            let Ok((addr, Ok(r))) = res else {
+                continue;
+            };
+            if let Some(interval) = r.interval {
+                let interval = interval.clamp(0, MAX_SAMPLE_INTERVAL as i64) as u64;
+                asked.insert(addr, Instant::now() + Duration::from_secs(interval));
+            }
+            for info_hash in r.samples {
+                if found.insert(info_hash) && send.send_async(info_hash).await.is_err() {
+                    return;
+                }
+            }
+            for n in r.nodes.iter().chain(&r.nodes6) {
+                if queue.len() < MAX_CRAWL_QUEUE && !asked.contains_key(&n.addr) {
+                    queue.push_back(n.addr);
+                }
+            }
+        }
+    }
+
+    async fn ping(self: &Arc<Self>, addrs: Vec<SocketAddr>) {
+        let mut queries = JoinSet::new();
+        for addr in addrs {
+            let dht = self.clone();
+            queries.spawn(async move { dht.query(addr, Query::Ping).await });
+        }
+        while queries.join_next().await.is_some() {}
+    }
+
+    // fills the routing tables of the wanted families through well known
+    // routers, each family is asked over its own socket
+    async fn bootstrap(self: &Arc<Self>, want: Want) {
+        let id = self.id();
+        let wanted = |v6: bool| if v6 { want.n6 } else { want.n4 };
+        let mut queries = JoinSet::new();
+        for host in BOOTSTRAP_NODES {
+            let Ok(addrs) = lookup_host(host).await else {
+                continue;
+            };
+            for addr in addrs.filter(|a| wanted(a.is_ipv6()) && self.net(a.is_ipv6()).is_some()) {
+                let dht = self.clone();
+                let query = Query::FindNode { target: id, want };
+                queries.spawn(async move { dht.query(addr, query).await });
+            }
+        }
+        while let Some(res) = queries.join_next().await {
+            if let Ok(Ok(r)) = res {
+                let addrs = r.nodes.iter().chain(&r.nodes6).map(|n| n.addr).collect();
+                self.ping(addrs).await;
+            }
+        }
+        self.find_node(id, want).await;
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.state else {
+            return Ok(());
+        };
+        let tables = self.nets().map(|net| net.table.lock()).collect::<Vec<_>>();
+        let tables = tables.iter().map(|t| &**t).collect::<Vec<_>>();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, routing_table::to_state(&tables))?;
+        fs::rename(tmp, path)
+    }
+
+    async fn maintain(self: Arc<Self>) {
+        let mut tick = interval(MAINTENANCE_INTERVAL);
+        let mut rotated = Instant::now();
+        let mut saved = Instant::now();
+        // per family, when to bootstrap a short table again
+        let mut backoff = [MAINTENANCE_INTERVAL; 2];
+        let mut next_bootstrap = [Instant::now(); 2];
+        loop {
+            tick.tick().await;
+            if rotated.elapsed() >= TOKEN_ROTATION {
+                let mut secrets = self.secrets.lock();
+                *secrets = [rand::random(), secrets[0]];
+                rotated = Instant::now();
+            }
+            self.peers.lock().retain(|_, peers| {
+                peers.retain(|_, t| t.elapsed() < PEER_TTL);
+                !peers.is_empty()
+            });
+            self.limits
+                .lock()
+                .retain(|_, (t, _)| t.elapsed() < RATE_WINDOW);
+            self.banned.lock().retain(|_, t| t.elapsed() < BAN_TIME);
+            self.items.lock().retain(|_, (_, t)| t.elapsed() < ITEM_TTL);
+
+            // a family without a route never fills, it must not keep
+            // bootstrapping the other one
+            let short = [false, true].map(|v6| {
+                self.net(v6).is_some()
+                    && self.table_len(v6) < K
+                    && next_bootstrap[v6 as usize] <= Instant::now()
+            });
+            if short.contains(&true) {
+                let before = [false, true].map(|v6| self.table_len(v6));
+                self.bootstrap(Want {
+                    n4: short[0],
+                    n6: short[1],
+                })
+                .await;
+                for v6 in [false, true].into_iter().filter(|&v6| short[v6 as usize]) {
+                    let i = v6 as usize;
+                    backoff[i] = match self.table_len(v6) > before[i] {
+                        true => MAINTENANCE_INTERVAL,
+                        false => (backoff[i] * 2).min(MAX_BOOTSTRAP_BACKOFF),
+                    };
+                    next_bootstrap[i] = Instant::now() + backoff[i];
+                }
+            }
+            for v6 in [false, true] {
+                let Some(net) = self.net(v6) else {
+                    continue;
+                };
+                let (questionable, targets) = {
+                    let table = net.table.lock();
+                    if table.len() < K {
+                        continue;
+                    }
+                    (table.questionable(), table.refresh_targets())
+                };
+                let addrs = questionable.into_iter().map(|n| n.addr).collect();
+                self.ping(addrs).await;
+                for target in targets {
+                    let query = Query::FindNode {
+                        target,
+                        want: Want::default(),
+                    };
+                    self.lookup(v6, target, query, |_| ()).await;
+                }
+            }
+            // the process may be killed without dropping us
+            if saved.elapsed() >= SAVE_INTERVAL {
+                #[allow(unused_must_use)]
+                {
+                    self.save();
+                }
+                saved = Instant::now();
+            }
+        }
+    }
+}
+
+// port 0, unspecified, multicast or broadcast addresses are never nodes
+// or peers
+fn valid_addr(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    addr.port() != 0
+        && !ip.is_unspecified()
+        && !ip.is_multicast()
+        && ip != IpAddr::V4(Ipv4Addr::BROADCAST)
+}
+
+// answers no honest node gives
+fn garbage(r: &Response, local: &NodeId) -> bool {
+    r.id == *local
+        || !r.nodes.iter().chain(&r.nodes6).all(|n| valid_addr(&n.addr))
+        || !r.values.iter().all(valid_addr)
+}
+
+// one dht node serves every torrent of the session
 pub struct DhtDispatch {
     pub msg_port_recv: Receiver<(SocketAddr, message::Port)>,
     pub msg_port_send: Sender<(SocketAddr, message::Port)>,
-    pub info_hash: [u8; 20],
+    dht: Arc<Dht>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    // periodic peer lookup of each torrent
+    lookups: Mutex<HashMap<[u8; 20], JoinHandle<()>>>,
 }
 
 impl DhtDispatch {
-    pub fn new(info_hash: [u8; 20]) -> DhtDispatch {
-        let (msg_port_send, msg_port_recv) = crossbeam_channel::unbounded();
+    // port is the peer listen port announced for our torrents, without
+    // proto6 the node stays on ipv4; a missing or broken state file starts
+    // a fresh node
+    pub fn new(
+        proto: DhtProto,
+        proto6: Option<DhtProto>,
+        port: u16,
+        implied_port: bool,
+        state: Option<PathBuf>,
+    ) -> DhtDispatch {
+        let (msg_port_send, msg_port_recv) = flume::unbounded();
+        let (table, table6) = state
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|buf| routing_table::from_state(&buf))
+            .unwrap_or_else(|| {
+                let id = rand::random();
+                (RoutingTable::new(id), RoutingTable::new(id))
+            });
+        let dht = Dht {
+            v4: Net {
+                proto,
+                table: Mutex::new(table),
+            },
+            v6: proto6.map(|proto| Net {
+                proto,
+                table: Mutex::new(table6),
+            }),
+            port,
+            implied_port,
+            peers: Mutex::new(HashMap::new()),
+            items: Mutex::new(HashMap::new()),
+            samples: Mutex::new(None),
+            secrets: Mutex::new([rand::random(), rand::random()]),
+            limits: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashMap::new()),
+            votes: Mutex::new(HashMap::new()),
+            state,
+        };
         DhtDispatch {
             msg_port_recv,
             msg_port_send,
-            info_hash,
+            dht: Arc::new(dht),
+            tasks: Mutex::new(Vec::new()),
+            lookups: Mutex::new(HashMap::new()),
         }
     }
 
-    pub fn run(&self, send_peer: Sender<SocketAddr>) {
-        let info_hash = self.info_hash.clone();
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.dht.v4.proto.local_addr()
+    }
+
+    pub fn local_addr6(&self) -> Option<io::Result<SocketAddr>> {
+        self.dht.v6.as_ref().map(|net| net.proto.local_addr())
+    }
+
+    // nodes in the routing tables
+    pub fn nodes(&self) -> usize {
+        self.dht.nets().map(|net| net.table.lock().len()).sum()
+    }
+
+    // writes the routing tables to the state file, done now and then and
+    // on drop too
+    pub fn save(&self) -> io::Result<()> {
+        self.dht.save()
+    }
+
+    pub fn run(&self) {
         let msg_port_recv = self.msg_port_recv.clone();
-        thread::spawn(move || Self::worker(info_hash, send_peer, msg_port_recv));
+        self.tasks.lock().extend([
+            tokio::spawn(self.dht.clone().serve(false)),
+            tokio::spawn(self.dht.clone().serve(true)),
+            tokio::spawn(self.dht.clone().maintain()),
+            tokio::spawn(Self::worker(self.dht.clone(), msg_port_recv)),
+        ]);
     }
 
-    fn worker(
-        info_hash: [u8; 20],
-        send_peer: Sender<SocketAddr>,
-        msg_port_recv: Receiver<(SocketAddr, message::Port)>,
-        ) {
-        if let Ok(peers) = dht_get_peers::get_peers(info_hash) {
-            for peer in peers {
-                send_peer.send(peer);
+    // looks up peers now and then, with announce we also tell the closest
+    // nodes after every lookup
+    pub fn add_torrent(&self, info_hash: [u8; 20], send_peer: Sender<SocketAddr>, announce: bool) {
+        let dht = self.dht.clone();
+        let lookup = tokio::spawn(async move {
+            let query = Query::GetPeers {
+                info_hash,
+                want: Want::default(),
+            };
+            let found_peers = |r: &Response| {
+                for peer in &r.values {
+                    #[allow(unused_must_use)]
+                    {
+                        send_peer.send(*peer);
+                    }
+                }
+            };
+            loop {
+                let (found, found6) = tokio::join!(
+                    dht.lookup(false, info_hash, query.clone(), found_peers),
+                    dht.lookup(true, info_hash, query.clone(), found_peers),
+                );
+                let empty = found.is_empty() && found6.is_empty();
+                if announce {
+                    dht.announce(info_hash, [found, found6].concat()).await;
+                }
+                let wait = if empty {
+                    RETRY_INTERVAL
+                } else {
+                    LOOKUP_INTERVAL
+                };
+                sleep(wait).await;
             }
+        });
+        if let Some(old) = self.lookups.lock().insert(info_hash, lookup) {
+            old.abort();
         }
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        if let Some(lookup) = self.lookups.lock().remove(info_hash) {
+            lookup.abort();
+        }
+    }
+
+    pub async fn get_immutable(&self, target: NodeId) -> Option<Value> {
+        let (items, _) = self.dht.get(target, &[]).await;
+        items.into_iter().next().map(|item| item.v)
+    }
+
+    // the item with the highest seq
+    pub async fn get_mutable(&self, k: [u8; 32], salt: &[u8]) -> Option<Item> {
+        let target = dht_item::mutable_target(&k, salt);
+        let (items, _) = self.dht.get(target, salt).await;
+        items.into_iter().max_by_key(|item| item.seq())
+    }
 
-        let mut port_msgs = Vec::new();
-        while let Ok(port_msg) = msg_port_recv.recv() {
-            port_msgs.push(port_msg);
-            if port_msgs.len() > 5 {
-                let port_msgs = port_msgs.iter().map(|pm| 
-                    SocketAddr::new(pm.0.ip(), pm.1.listen_port)
-                ).collect::<Vec<_>>();
-                if let Ok(peers) = dht_get_peers::get_peers_bs(info_hash, port_msgs.as_slice()) {
-                    for peer in peers {
-                        send_peer.send(peer);
-                    }                    
-                }                
+    // infohashes found by walking the keyspace with sample_infohashes, the
+    // crawl ends when the receiver is dropped
+    pub fn crawl(&self) -> Receiver<[u8; 20]> {
+        let (send, recv) = flume::bounded(MAX_SAMPLES);
+        let crawl = tokio::spawn(self.dht.clone().crawl(send));
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(crawl);
+        recv
+    }
+
+    // stores the item at the closest nodes, returns how many took it;
+    // with cas a mutable item only replaces the one of that seq
+    pub async fn put(&self, item: Item, cas: Option<i64>) -> usize {
+        self.dht.put(item, cas).await
+    }
+
+    // dht nodes of peers sending port messages, pinged so the answering
+    // ones land in the routing table
+    async fn worker(dht: Arc<Dht>, msg_port_recv: Receiver<(SocketAddr, message::Port)>) {
+        let mut pinged = HashMap::<SocketAddr, Instant>::new();
+        while let Ok(port_msg) = msg_port_recv.recv_async().await {
+            pinged.retain(|_, t| t.elapsed() < PORT_RETRY);
+            let mut addrs = Vec::new();
+            for (peer, port) in [port_msg].into_iter().chain(msg_port_recv.drain()) {
+                let addr = SocketAddr::new(peer.ip(), port.listen_port);
+                let Some(net) = dht.net(addr.is_ipv6()) else {
+                    continue;
+                };
+                let known = net.table.lock().entries().any(|e| e.node.addr == addr);
+                if port.listen_port == 0 || known || pinged.contains_key(&addr) {
+                    continue;
+                }
+                pinged.insert(addr, Instant::now());
+                addrs.push(addr);
             }
-            port_msgs.clear();
+            dht.ping(addrs).await;
         }
+    }
+}
 
-        //}
+impl Drop for DhtDispatch {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().iter() {
+            task.abort();
+        }
+        for lookup in self.lookups.lock().values() {
+            lookup.abort();
+        }
+        #[allow(unused_must_use)]
+        {
+            self.save();
+        }
     }
 }
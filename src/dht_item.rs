@@ -0,0 +1,145 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    bencode::Value,
+    dht_proto::{NodeId, Response},
+};
+
+// limits of BEP 44, bencoded value and salt
+pub const MAX_VALUE_LEN: usize = 1000;
+pub const MAX_SALT_LEN: usize = 64;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Value bigger than {MAX_VALUE_LEN} bytes")]
+    TooBig,
+    #[error("Salt bigger than {MAX_SALT_LEN} bytes")]
+    SaltTooBig,
+    #[error("Invalid signature")]
+    Signature,
+}
+
+impl Error {
+    // krpc error code, see BEP 44
+    pub fn code(&self) -> i64 {
+        match self {
+            Error::TooBig => 205,
+            Error::Signature => 206,
+            Error::SaltTooBig => 207,
+        }
+    }
+}
+
+// key, salt and sequence number of a mutable item, signed by the key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mutable {
+    pub k: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub sig: [u8; 64],
+}
+
+// data stored in the dht, immutable items are found by the hash of v and
+// mutable ones by the hash of key and salt, see BEP 44
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub v: Value,
+    pub mutable: Option<Mutable>,
+}
+
+impl Item {
+    pub fn immutable(v: Value) -> Result<Item, Error> {
+        let item = Item { v, mutable: None };
+        item.verify()?;
+        Ok(item)
+    }
+
+    pub fn mutable(key: &SigningKey, salt: Vec<u8>, seq: i64, v: Value) -> Result<Item, Error> {
+        let sig = key.sign(&signed_bytes(&salt, seq, &v)).to_bytes();
+        let mutable = Mutable {
+            k: key.verifying_key().to_bytes(),
+            salt,
+            seq,
+            sig,
+        };
+        let item = Item {
+            v,
+            mutable: Some(mutable),
+        };
+        item.verify()?;
+        Ok(item)
+    }
+
+    // v, k, sig and seq of a get answer, salt is not sent back
+    pub fn from_response(r: &Response, salt: &[u8]) -> Option<Item> {
+        let mutable = match (r.k, r.sig, r.seq) {
+            (Some(k), Some(sig), Some(seq)) => Some(Mutable {
+                k,
+                salt: salt.to_vec(),
+                seq,
+                sig,
+            }),
+            _ => None,
+        };
+        Some(Item {
+            v: r.v.clone()?,
+            mutable,
+        })
+    }
+
+    pub fn target(&self) -> NodeId {
+        match &self.mutable {
+            Some(m) => mutable_target(&m.k, &m.salt),
+            None => immutable_target(&self.v),
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.mutable.as_ref().map(|m| m.seq)
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        if self.v.encode().len() > MAX_VALUE_LEN {
+            return Err(Error::TooBig);
+        }
+        let Some(m) = &self.mutable else {
+            return Ok(());
+        };
+        if m.salt.len() > MAX_SALT_LEN {
+            return Err(Error::SaltTooBig);
+        }
+        let key = VerifyingKey::from_bytes(&m.k).map_err(|_| Error::Signature)?;
+        key.verify(
+            &signed_bytes(&m.salt, m.seq, &self.v),
+            &Signature::from_bytes(&m.sig),
+        )
+        .map_err(|_| Error::Signature)
+    }
+}
+
+pub fn immutable_target(v: &Value) -> NodeId {
+    Sha1::digest(v.encode()).into()
+}
+
+pub fn mutable_target(k: &[u8; 32], salt: &[u8]) -> NodeId {
+    Sha1::new()
+        .chain_update(k)
+        .chain_update(salt)
+        .finalize()
+        .into()
+}
+
+// salt, seq and v as they appear in a bencoded dictionary, without the
+// surrounding d and e
+fn signed_bytes(salt: &[u8], seq: i64, v: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    v.encode_to(&mut buf);
+    buf
+}
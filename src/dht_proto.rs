@@ -0,0 +1,644 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use flume::{Receiver, Sender};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Type};
+use thiserror::Error;
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    bencode::{self, Value},
+    dht_item::{Item, Mutable},
+    peer_proto::message::{compact_v4, compact_v6, to_compact_v4, to_compact_v6},
+    utp::{Outgoing, UtpSocket},
+};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// largest datagram we expect, bigger ones are truncated and fail to decode
+const MAX_DATAGRAM: usize = 1 << 12;
+
+pub type NodeId = [u8; 20];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Error while io")]
+    Io(#[from] io::Error),
+    #[error("Malformed krpc message")]
+    Bencode(#[from] bencode::Error),
+    #[error("Malformed krpc message")]
+    Malformed,
+    #[error("Query timed out")]
+    Timeout,
+    #[error("Node answered with error {0}: {1}")]
+    Remote(i64, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+// address families of the nodes asked for, none means the family of
+// the query, see BEP 32
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Want {
+    pub n4: bool,
+    pub n6: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+        want: Want,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+        want: Want,
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+        // take the source port of the packet instead, it is what a nat
+        // maps to our shared utp socket
+        implied_port: bool,
+    },
+    // newer mutable items than seq only, see BEP 44
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        // mutable item is only replaced if its seq still is cas
+        cas: Option<i64>,
+    },
+    // some of the infohashes the node has peers of, see BEP 51
+    SampleInfohashes {
+        target: NodeId,
+        want: Want,
+    },
+    // answered with error 204
+    Unknown(String),
+}
+
+// every response type shares one dictionary, absent keys stay empty
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<Node>,
+    pub nodes6: Vec<Node>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // stored item of a get, k and sig come with mutable items only
+    pub v: Option<Value>,
+    pub k: Option<[u8; 32]>,
+    pub sig: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+    // sample_infohashes answer, seconds until the samples change and how
+    // many infohashes the node has
+    pub interval: Option<i64>,
+    pub num: Option<i64>,
+    pub samples: Vec<[u8; 20]>,
+    // address of the querying node as the responder sees it, sits next
+    // to r in the message, see BEP 42
+    pub ip: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+// krpc message, see BEP 5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    // transaction id, echoed in the answer
+    pub t: Vec<u8>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        d.insert(b"t".to_vec(), Value::Bytes(self.t.clone()));
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut a = BTreeMap::new();
+                a.insert(b"id".to_vec(), Value::Bytes(id.to_vec()));
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target, want } => {
+                        a.insert(b"target".to_vec(), Value::Bytes(target.to_vec()));
+                        want.encode_to(&mut a);
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash, want } => {
+                        a.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                        want.encode_to(&mut a);
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        a.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                        a.insert(b"port".to_vec(), Value::Int(*port as i64));
+                        a.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                        if *implied_port {
+                            a.insert(b"implied_port".to_vec(), Value::Int(1));
+                        }
+                        "announce_peer"
+                    }
+                    Query::Get { target, seq } => {
+                        a.insert(b"target".to_vec(), Value::Bytes(target.to_vec()));
+                        if let Some(seq) = seq {
+                            a.insert(b"seq".to_vec(), Value::Int(*seq));
+                        }
+                        "get"
+                    }
+                    Query::Put { token, item, cas } => {
+                        a.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                        a.insert(b"v".to_vec(), item.v.clone());
+                        if let Some(m) = &item.mutable {
+                            a.insert(b"k".to_vec(), Value::Bytes(m.k.to_vec()));
+                            a.insert(b"sig".to_vec(), Value::Bytes(m.sig.to_vec()));
+                            a.insert(b"seq".to_vec(), Value::Int(m.seq));
+                            if !m.salt.is_empty() {
+                                a.insert(b"salt".to_vec(), Value::Bytes(m.salt.clone()));
+                            }
+                        }
+                        if let Some(cas) = cas {
+                            a.insert(b"cas".to_vec(), Value::Int(*cas));
+                        }
+                        "put"
+                    }
+                    Query::SampleInfohashes { target, want } => {
+                        a.insert(b"target".to_vec(), Value::Bytes(target.to_vec()));
+                        want.encode_to(&mut a);
+                        "sample_infohashes"
+                    }
+                    Query::Unknown(name) => name,
+                };
+                d.insert(b"y".to_vec(), Value::from("q"));
+                d.insert(b"q".to_vec(), Value::from(name));
+                d.insert(b"a".to_vec(), Value::Dict(a));
+            }
+            Body::Response(response) => {
+                let mut r = BTreeMap::new();
+                r.insert(b"id".to_vec(), Value::Bytes(response.id.to_vec()));
+                if !response.nodes.is_empty() {
+                    let nodes = to_compact_nodes(&response.nodes);
+                    r.insert(b"nodes".to_vec(), Value::Bytes(nodes));
+                }
+                if !response.nodes6.is_empty() {
+                    let nodes = to_compact_nodes6(&response.nodes6);
+                    r.insert(b"nodes6".to_vec(), Value::Bytes(nodes));
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|v| Value::Bytes(compact_addr(*v)))
+                        .collect();
+                    r.insert(b"values".to_vec(), Value::List(values));
+                }
+                if let Some(token) = &response.token {
+                    r.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                }
+                if let Some(v) = &response.v {
+                    r.insert(b"v".to_vec(), v.clone());
+                }
+                if let Some(k) = &response.k {
+                    r.insert(b"k".to_vec(), Value::Bytes(k.to_vec()));
+                }
+                if let Some(sig) = &response.sig {
+                    r.insert(b"sig".to_vec(), Value::Bytes(sig.to_vec()));
+                }
+                if let Some(seq) = response.seq {
+                    r.insert(b"seq".to_vec(), Value::Int(seq));
+                }
+                if let Some(interval) = response.interval {
+                    r.insert(b"interval".to_vec(), Value::Int(interval));
+                }
+                if let Some(num) = response.num {
+                    r.insert(b"num".to_vec(), Value::Int(num));
+                }
+                if response.num.is_some() {
+                    let samples = response.samples.concat();
+                    r.insert(b"samples".to_vec(), Value::Bytes(samples));
+                }
+                if let Some(ip) = response.ip {
+                    d.insert(b"ip".to_vec(), Value::Bytes(compact_addr(ip)));
+                }
+                d.insert(b"y".to_vec(), Value::from("r"));
+                d.insert(b"r".to_vec(), Value::Dict(r));
+            }
+            Body::Error { code, message } => {
+                let e = vec![Value::Int(*code), Value::from(message.as_str())];
+                d.insert(b"y".to_vec(), Value::from("e"));
+                d.insert(b"e".to_vec(), Value::List(e));
+            }
+        }
+        Value::Dict(d).encode()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Message, Error> {
+        let v = Value::decode(buf)?;
+        let t = v
+            .get("t")
+            .and_then(Value::as_bytes)
+            .ok_or(Error::Malformed)?;
+        let body = match v.get("y").and_then(Value::as_str) {
+            Some("q") => {
+                let a = v.get("a").ok_or(Error::Malformed)?;
+                let id = node_id(a.get("id"))?;
+                let query = match v.get("q").and_then(Value::as_str) {
+                    Some("ping") => Query::Ping,
+                    Some("find_node") => Query::FindNode {
+                        target: node_id(a.get("target"))?,
+                        want: Want::decode(a),
+                    },
+                    Some("get_peers") => Query::GetPeers {
+                        info_hash: node_id(a.get("info_hash"))?,
+                        want: Want::decode(a),
+                    },
+                    Some("announce_peer") => Query::AnnouncePeer {
+                        info_hash: node_id(a.get("info_hash"))?,
+                        port: a
+                            .get("port")
+                            .and_then(Value::as_int)
+                            .and_then(|p| u16::try_from(p).ok())
+                            .ok_or(Error::Malformed)?,
+                        token: a
+                            .get("token")
+                            .and_then(Value::as_bytes)
+                            .ok_or(Error::Malformed)?
+                            .to_vec(),
+                        implied_port: a.get("implied_port").and_then(Value::as_int) == Some(1),
+                    },
+                    Some("get") => Query::Get {
+                        target: node_id(a.get("target"))?,
+                        seq: a.get("seq").and_then(Value::as_int),
+                    },
+                    Some("put") => Query::Put {
+                        token: a
+                            .get("token")
+                            .and_then(Value::as_bytes)
+                            .ok_or(Error::Malformed)?
+                            .to_vec(),
+                        item: decode_item(a)?,
+                        cas: a.get("cas").and_then(Value::as_int),
+                    },
+                    Some("sample_infohashes") => Query::SampleInfohashes {
+                        target: node_id(a.get("target"))?,
+                        want: Want::decode(a),
+                    },
+                    Some(name) => Query::Unknown(name.to_string()),
+                    None => return Err(Error::Malformed),
+                };
+                Body::Query { id, query }
+            }
+            Some("r") => {
+                let r = v.get("r").ok_or(Error::Malformed)?;
+                let values = r.get("values").and_then(Value::as_list);
+                Body::Response(Response {
+                    id: node_id(r.get("id"))?,
+                    nodes: r
+                        .get("nodes")
+                        .and_then(Value::as_bytes)
+                        .map(compact_nodes)
+                        .unwrap_or_default(),
+                    nodes6: r
+                        .get("nodes6")
+                        .and_then(Value::as_bytes)
+                        .map(compact_nodes6)
+                        .unwrap_or_default(),
+                    values: values
+                        .iter()
+                        .flat_map(|l| l.iter())
+                        .filter_map(Value::as_bytes)
+                        .flat_map(addr_compact)
+                        .collect(),
+                    token: r.get("token").and_then(Value::as_bytes).map(<[u8]>::to_vec),
+                    v: r.get("v").cloned(),
+                    k: fixed(r.get("k")),
+                    sig: fixed(r.get("sig")),
+                    seq: r.get("seq").and_then(Value::as_int),
+                    interval: r.get("interval").and_then(Value::as_int),
+                    num: r.get("num").and_then(Value::as_int),
+                    samples: r
+                        .get("samples")
+                        .and_then(Value::as_bytes)
+                        .unwrap_or_default()
+                        .chunks_exact(20)
+                        .map(|c| c.try_into().unwrap())
+                        .collect(),
+                    ip: v
+                        .get("ip")
+                        .and_then(Value::as_bytes)
+                        .and_then(|ip| addr_compact(ip).pop()),
+                })
+            }
+            Some("e") => {
+                let e = v
+                    .get("e")
+                    .and_then(Value::as_list)
+                    .ok_or(Error::Malformed)?;
+                Body::Error {
+                    code: e.first().and_then(Value::as_int).unwrap_or(0),
+                    message: e.get(1).and_then(Value::as_str).unwrap_or("").to_string(),
+                }
+            }
+            _ => return Err(Error::Malformed),
+        };
+        Ok(Message {
+            t: t.to_vec(),
+            body,
+        })
+    }
+}
+
+impl Want {
+    fn encode_to(&self, a: &mut BTreeMap<Vec<u8>, Value>) {
+        let want = [(self.n4, "n4"), (self.n6, "n6")]
+            .into_iter()
+            .filter(|(w, _)| *w)
+            .map(|(_, name)| Value::from(name))
+            .collect::<Vec<_>>();
+        if !want.is_empty() {
+            a.insert(b"want".to_vec(), Value::List(want));
+        }
+    }
+
+    fn decode(a: &Value) -> Want {
+        let want = a.get("want").and_then(Value::as_list);
+        let has = |name| {
+            want.iter()
+                .flat_map(|l| l.iter())
+                .any(|w| w.as_str() == Some(name))
+        };
+        Want {
+            n4: has("n4"),
+            n6: has("n6"),
+        }
+    }
+}
+
+fn compact_addr(addr: SocketAddr) -> Vec<u8> {
+    match addr {
+        SocketAddr::V4(_) => to_compact_v4(&[addr]),
+        SocketAddr::V6(_) => to_compact_v6(&[addr]),
+    }
+}
+
+// 6 bytes for ipv4, 18 for ipv6
+fn addr_compact(bytes: &[u8]) -> Vec<SocketAddr> {
+    match bytes.len() {
+        18 => compact_v6(bytes),
+        _ => compact_v4(bytes),
+    }
+}
+
+fn fixed<const N: usize>(v: Option<&Value>) -> Option<[u8; N]> {
+    v.and_then(Value::as_bytes).and_then(|b| b.try_into().ok())
+}
+
+// unverified, the node storing it checks size and signature
+fn decode_item(a: &Value) -> Result<Item, Error> {
+    let v = a.get("v").ok_or(Error::Malformed)?.clone();
+    let mutable = match (fixed(a.get("k")), fixed(a.get("sig"))) {
+        (Some(k), Some(sig)) => Some(Mutable {
+            k,
+            salt: a
+                .get("salt")
+                .and_then(Value::as_bytes)
+                .unwrap_or_default()
+                .to_vec(),
+            seq: a
+                .get("seq")
+                .and_then(Value::as_int)
+                .ok_or(Error::Malformed)?,
+            sig,
+        }),
+        _ => None,
+    };
+    Ok(Item { v, mutable })
+}
+
+fn node_id(v: Option<&Value>) -> Result<NodeId, Error> {
+    v.and_then(Value::as_bytes)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(Error::Malformed)
+}
+
+// 20 bytes id, 4 bytes ip and 2 bytes port per node
+pub fn compact_nodes(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(26)
+        .flat_map(|c| {
+            let id = <NodeId>::try_from(&c[..20]).unwrap();
+            compact_v4(&c[20..])
+                .into_iter()
+                .map(move |addr| Node { id, addr })
+        })
+        .collect()
+}
+
+pub fn to_compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for node in nodes.iter().filter(|n| n.addr.is_ipv4()) {
+        buf.extend_from_slice(&node.id);
+        buf.extend_from_slice(&to_compact_v4(&[node.addr]));
+    }
+    buf
+}
+
+// 20 bytes id, 16 bytes ip and 2 bytes port per node
+pub fn compact_nodes6(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(38)
+        .flat_map(|c| {
+            let id = <NodeId>::try_from(&c[..20]).unwrap();
+            compact_v6(&c[20..])
+                .into_iter()
+                .map(move |addr| Node { id, addr })
+        })
+        .collect()
+}
+
+pub fn to_compact_nodes6(nodes: &[Node]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for node in nodes.iter().filter(|n| n.addr.is_ipv6()) {
+        buf.extend_from_slice(&node.id);
+        buf.extend_from_slice(&to_compact_v6(&[node.addr]));
+    }
+    buf
+}
+
+type Pending = Mutex<HashMap<Vec<u8>, (SocketAddr, Sender<Result<Response, Error>>)>>;
+
+enum Socket {
+    Own(UdpSocket),
+    // rides on the utp socket, see UtpSocket::share
+    Shared {
+        local_addr: SocketAddr,
+        recv: Receiver<(Vec<u8>, SocketAddr)>,
+        send: Outgoing,
+    },
+}
+
+impl Socket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
+        match self {
+            Socket::Own(socket) => socket.send_to(buf, addr).await.map(|_| ()),
+            Socket::Shared { send, .. } => send
+                .send((buf.to_vec(), addr))
+                .map_err(|_| io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Socket::Own(socket) => socket.recv_from(buf).await,
+            Socket::Shared { recv, .. } => {
+                let (packet, addr) = recv
+                    .recv_async()
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+                Ok((len, addr))
+            }
+        }
+    }
+}
+
+// krpc over udp, answers are matched to their queries by transaction id
+pub struct DhtProto {
+    socket: Socket,
+    pending: Pending,
+    next_tid: AtomicU16,
+}
+
+impl DhtProto {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<DhtProto> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self::new(Socket::Own(socket)))
+    }
+
+    // ipv6 only, so it may take the port number of an ipv4 socket
+    pub fn bind_v6(port: u16) -> io::Result<DhtProto> {
+        let socket = socket2::Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok(Self::new(Socket::Own(socket)))
+    }
+
+    // one udp port for utp and dht, so nodes may announce us with
+    // implied_port
+    pub fn shared(utp: &UtpSocket) -> io::Result<DhtProto> {
+        let (recv, send) = utp.share();
+        Ok(Self::new(Socket::Shared {
+            local_addr: utp.local_addr()?,
+            recv,
+            send,
+        }))
+    }
+
+    fn new(socket: Socket) -> DhtProto {
+        DhtProto {
+            socket,
+            pending: Mutex::new(HashMap::new()),
+            next_tid: AtomicU16::new(rand::random()),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.socket {
+            Socket::Own(socket) => socket.local_addr(),
+            Socket::Shared { local_addr, .. } => Ok(*local_addr),
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        matches!(self.socket, Socket::Shared { .. })
+    }
+
+    pub async fn query(
+        &self,
+        addr: SocketAddr,
+        id: NodeId,
+        query: Query,
+    ) -> Result<Response, Error> {
+        let t = self
+            .next_tid
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (send_res, get_res) = flume::bounded(1);
+        self.pending.lock().insert(t.clone(), (addr, send_res));
+        let msg = Message {
+            t: t.clone(),
+            body: Body::Query { id, query },
+        };
+        let res = async {
+            self.socket.send_to(&msg.encode(), addr).await?;
+            match timeout(QUERY_TIMEOUT, get_res.recv_async()).await {
+                Ok(Ok(res)) => res,
+                _ => Err(Error::Timeout),
+            }
+        }
+        .await;
+        self.pending.lock().remove(&t);
+        res
+    }
+
+    pub async fn send(&self, addr: SocketAddr, msg: &Message) -> Result<(), Error> {
+        self.socket.send_to(&msg.encode(), addr).await?;
+        Ok(())
+    }
+
+    // next incoming query, answers are handed to the query waiting for them
+    pub async fn recv(&self) -> Result<(Message, SocketAddr), Error> {
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).await?;
+            let Ok(msg) = Message::decode(&buf[..len]) else {
+                continue;
+            };
+            let res = match msg.body {
+                Body::Query { .. } => return Ok((msg, addr)),
+                Body::Response(r) => Ok(r),
+                Body::Error { code, message } => Err(Error::Remote(code, message)),
+            };
+            // answers from other addresses may be forged
+            let pending = self.pending.lock();
+            if let Some((_, send_res)) = pending.get(&msg.t).filter(|(a, _)| *a == addr) {
+                #[allow(unused_must_use)]
+                {
+                    send_res.try_send(res);
+                }
+            }
+        }
+    }
+}
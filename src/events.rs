@@ -0,0 +1,81 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use tokio::sync::broadcast;
+
+use crate::peer_dispatch::DisconnectReason;
+
+// subscriber lagging behind more than that loses the oldest events
+const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    PieceVerified {
+        info_hash: [u8; 20],
+        index: usize,
+    },
+    HashFailed {
+        info_hash: [u8; 20],
+        index: usize,
+        addr: SocketAddr,
+    },
+    FileCompleted {
+        info_hash: [u8; 20],
+        path: PathBuf,
+    },
+    PeerConnected {
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+    },
+    PeerDisconnected {
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+    // number of received peers or error description
+    TrackerAnnounce {
+        info_hash: [u8; 20],
+        url: String,
+        result: Result<usize, String>,
+    },
+    // a web seed failed to deliver a piece and is backed off
+    WebSeedFailed {
+        info_hash: [u8; 20],
+        url: String,
+        message: String,
+    },
+    // info dict of a magnet link is downloaded and verified
+    MetadataReceived {
+        info_hash: [u8; 20],
+    },
+    TorrentFinished {
+        info_hash: [u8; 20],
+    },
+    Error {
+        info_hash: Option<[u8; 20]>,
+        message: String,
+    },
+}
+
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Events {
+    pub fn new() -> Events {
+        Events(broadcast::channel(EVENT_CAPACITY).0)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+
+    pub fn emit(&self, event: Event) {
+        // nobody listens, that's fine
+        let _ = self.0.send(event);
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
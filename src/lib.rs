@@ -0,0 +1,42 @@
+const BLOCK_SIZE: u32 = 2u32.pow(14);
+const NAME: &str = "get-torrent";
+const UT_PEX_EXTENDED_MSG_ID: u8 = 1;
+const UT_METADATA_EXTENDED_MSG_ID: u8 = 2;
+const PARALLEL_REQUEST_PER_PEER: usize = 4;
+const LISTEN_PORT: u16 = 6888;
+const DHT_PORT: u16 = 6881;
+
+pub mod bencode;
+pub mod create;
+pub mod dht_dispatch;
+pub mod dht_item;
+pub mod dht_proto;
+pub mod events;
+pub mod lsd_dispatch;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata_dispatch;
+pub mod metainfo;
+pub mod mse;
+pub mod peer_dispatch;
+pub mod peer_proto;
+pub mod piece;
+pub mod piece_dispatch;
+pub mod rate_limit;
+pub mod routing_table;
+pub mod session;
+pub mod storage;
+#[cfg(test)]
+mod tests;
+pub mod tracker_dispatch;
+pub mod utp;
+pub mod web_seed_dispatch;
+
+pub use create::CreateOptions;
+pub use events::Event;
+pub use magnet::Magnet;
+pub use metainfo::MetaInfo;
+pub use mse::Encryption;
+pub use peer_dispatch::RunState;
+pub use peer_proto::Transport;
+pub use session::{Session, Settings, TorrentHandle, TorrentOptions, TorrentStatus};
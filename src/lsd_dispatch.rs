@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
+};
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use flume::{Receiver, Sender};
+use parking_lot::Mutex;
+use rand::distributions::{Alphanumeric, DistString};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, task::JoinHandle, time::interval};
+
+// multicast groups of local service discovery, see BEP 14
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+pub const LSD_GROUP6: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+);
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// infohashes per announce, keeps it within one 1400 byte datagram
+const MAX_INFO_HASHES: usize = 20;
+const MAX_MSG_SIZE: usize = 1400;
+
+// BT-SEARCH message, an http request over udp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub host: String,
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            self.host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            msg += &format!("Infohash: {}\r\n", HEXLOWER.encode(info_hash));
+        }
+        if let Some(cookie) = &self.cookie {
+            msg += &format!("cookie: {}\r\n", cookie);
+        }
+        msg += "\r\n\r\n";
+        msg.into_bytes()
+    }
+
+    // header names are case insensitive, unknown ones are skipped
+    pub fn decode(buf: &[u8]) -> Option<Announce> {
+        let mut lines = std::str::from_utf8(buf).ok()?.lines();
+        if lines.next()?.trim_end() != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut announce = Announce {
+            host: String::new(),
+            port: 0,
+            info_hashes: Vec::new(),
+            cookie: None,
+        };
+        for line in lines.take_while(|l| !l.trim().is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "host" => announce.host = value.to_string(),
+                "port" => announce.port = value.parse().ok()?,
+                "infohash" => {
+                    let info_hash = HEXLOWER_PERMISSIVE.decode(value.as_bytes()).ok()?;
+                    announce.info_hashes.push(info_hash.try_into().ok()?);
+                }
+                "cookie" => announce.cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        (announce.port != 0 && !announce.info_hashes.is_empty()).then_some(announce)
+    }
+}
+
+// udp socket joined to a multicast group, announces go to the group
+pub struct LsdSocket {
+    socket: UdpSocket,
+    group: SocketAddr,
+}
+
+impl LsdSocket {
+    // interface is the address of the network to search, unspecified lets
+    // the os pick
+    pub fn bind_v4(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<LsdSocket> {
+        let socket = Self::bind(group.into())?;
+        socket.join_multicast_v4(group.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        Self::new(socket, group.into())
+    }
+
+    // interface is an interface index, 0 lets the os pick
+    pub fn bind_v6(group: SocketAddrV6, interface: u32) -> io::Result<LsdSocket> {
+        let socket = Self::bind(group.into())?;
+        socket.join_multicast_v6(group.ip(), interface)?;
+        socket.set_multicast_if_v6(interface)?;
+        socket.set_multicast_loop_v6(true)?;
+        Self::new(socket, group.into())
+    }
+
+    // other clients on the host listen on the same port
+    fn bind(group: SocketAddr) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        let any = match group {
+            SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => {
+                socket.set_only_v6(true)?;
+                IpAddr::from(Ipv6Addr::UNSPECIFIED)
+            }
+        };
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(any, group.port()).into())?;
+        Ok(socket)
+    }
+
+    fn new(socket: Socket, group: SocketAddr) -> io::Result<LsdSocket> {
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok(LsdSocket { socket, group })
+    }
+
+    pub async fn announce(
+        &self,
+        port: u16,
+        info_hashes: &[[u8; 20]],
+        cookie: &str,
+    ) -> io::Result<()> {
+        for chunk in info_hashes.chunks(MAX_INFO_HASHES) {
+            let announce = Announce {
+                host: self.group.to_string(),
+                port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(cookie.to_string()),
+            };
+            self.socket.send_to(&announce.encode(), self.group).await?;
+        }
+        Ok(())
+    }
+
+    // next well formed announce with its sender
+    pub async fn recv(&self) -> io::Result<(Announce, SocketAddr)> {
+        let mut buf = [0; MAX_MSG_SIZE];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).await?;
+            if let Some(announce) = Announce::decode(&buf[..len]) {
+                return Ok((announce, addr));
+            }
+        }
+    }
+}
+
+type Torrents = Arc<Mutex<HashMap<[u8; 20], Sender<SocketAddr>>>>;
+
+pub struct LsdDispatch {
+    sockets: Vec<Arc<LsdSocket>>,
+    // peer listen port we announce
+    port: u16,
+    // tells our own announces apart, they come back over the loop
+    cookie: String,
+    torrents: Torrents,
+    added_send: Sender<[u8; 20]>,
+    added_recv: Receiver<[u8; 20]>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl LsdDispatch {
+    pub fn new(sockets: Vec<LsdSocket>, port: u16) -> LsdDispatch {
+        let (added_send, added_recv) = flume::unbounded();
+        LsdDispatch {
+            sockets: sockets.into_iter().map(Arc::new).collect(),
+            port,
+            cookie: Alphanumeric.sample_string(&mut rand::thread_rng(), 8),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            added_send,
+            added_recv,
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn run(&self) {
+        let mut tasks = self.tasks.lock();
+        for socket in &self.sockets {
+            tasks.push(tokio::spawn(Self::listener(
+                socket.clone(),
+                self.cookie.clone(),
+                self.torrents.clone(),
+            )));
+        }
+        tasks.push(tokio::spawn(Self::announcer(
+            self.sockets.clone(),
+            self.port,
+            self.cookie.clone(),
+            self.torrents.clone(),
+            self.added_recv.clone(),
+        )));
+    }
+
+    // announces the torrent on the lan now and then, peers announcing it
+    // go to send_peer
+    pub fn add_torrent(&self, info_hash: [u8; 20], send_peer: Sender<SocketAddr>) {
+        self.torrents.lock().insert(info_hash, send_peer);
+        #[allow(unused_must_use)]
+        {
+            self.added_send.send(info_hash);
+        }
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().remove(info_hash);
+    }
+
+    async fn listener(socket: Arc<LsdSocket>, cookie: String, torrents: Torrents) {
+        while let Ok((announce, mut addr)) = socket.recv().await {
+            if announce.cookie.as_ref() == Some(&cookie) {
+                continue;
+            }
+            addr.set_port(announce.port);
+            let torrents = torrents.lock();
+            for info_hash in &announce.info_hashes {
+                if let Some(send_peer) = torrents.get(info_hash) {
+                    #[allow(unused_must_use)]
+                    {
+                        send_peer.send(addr);
+                    }
+                }
+            }
+        }
+    }
+
+    // new torrents are announced right away, all of them every interval
+    async fn announcer(
+        sockets: Vec<Arc<LsdSocket>>,
+        port: u16,
+        cookie: String,
+        torrents: Torrents,
+        added: Receiver<[u8; 20]>,
+    ) {
+        let mut interval = interval(ANNOUNCE_INTERVAL);
+        loop {
+            let info_hashes = tokio::select! {
+                info_hash = added.recv_async() => {
+                    let Ok(info_hash) = info_hash else {
+                        return;
+                    };
+                    let mut info_hashes = vec![info_hash];
+                    info_hashes.extend(added.drain());
+                    info_hashes
+                }
+                _ = interval.tick() => torrents.lock().keys().copied().collect(),
+            };
+            for socket in &sockets {
+                #[allow(unused_must_use)]
+                {
+                    socket.announce(port, &info_hashes, &cookie).await;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for LsdDispatch {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().iter() {
+            task.abort();
+        }
+    }
+}
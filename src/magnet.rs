@@ -0,0 +1,89 @@
+use data_encoding::{BASE32_NOPAD, HEXLOWER_PERMISSIVE};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Not a magnet link")]
+    Scheme,
+    #[error("Magnet link has no btih or btmh exact topic")]
+    MissingInfoHash,
+    #[error("Malformed info hash {0}")]
+    InfoHash(String),
+}
+
+// magnet:?xt=urn:btih:<hash>&dn=<name>&tr=<tracker>&x.pe=<host:port>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    // the btih one, v2 only links use the btmh one truncated
+    pub info_hash: [u8; 20],
+    // sha256 multihash of v2 and hybrid torrents, see BEP 52
+    pub info_hash_v2: Option<[u8; 32]>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // host:port, may need dns resolution
+    pub peers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Magnet, Error> {
+        let query = uri.strip_prefix("magnet:?").ok_or(Error::Scheme)?;
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = match urlencoding::decode(value) {
+                Ok(v) => v.into_owned(),
+                Err(_) => continue,
+            };
+            match key {
+                "xt" => {
+                    // other topics are skipped
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    } else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+                        info_hash_v2 = Some(parse_multihash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                _ => (),
+            }
+        }
+        let info_hash = info_hash
+            .or_else(|| Some(info_hash_v2?[..20].try_into().unwrap()))
+            .ok_or(Error::MissingInfoHash)?;
+        Ok(Magnet {
+            info_hash,
+            info_hash_v2,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+// 40 hex chars or 32 base32 chars
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], Error> {
+    let bytes = match hash.len() {
+        40 => HEXLOWER_PERMISSIVE.decode(hash.as_bytes()).ok(),
+        32 => BASE32_NOPAD
+            .decode(hash.to_ascii_uppercase().as_bytes())
+            .ok(),
+        _ => None,
+    };
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| Error::InfoHash(hash.to_string()))
+}
+
+// hex of a sha2-256 multihash, code 0x12 and length 0x20 come first
+fn parse_multihash(hash: &str) -> Result<[u8; 32], Error> {
+    hash.strip_prefix("1220")
+        .and_then(|h| HEXLOWER_PERMISSIVE.decode(h.as_bytes()).ok())
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| Error::InfoHash(hash.to_string()))
+}
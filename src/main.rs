@@ -1,109 +1,577 @@
-const BLOCK_SIZE: u32 = 2u32.pow(14);
-const NAME: &str = "get-torrent";
-const UT_PEX_EXTENDED_MSG_ID: u8 = 1;
-const PARALLEL_REQUEST_PER_PEER: usize = 4;
-
-mod dht_dispatch;
-mod peer_dispatch;
-mod piece;
-mod piece_dispatch;
-#[cfg(test)]
-mod tests;
-
-use parking_lot::Mutex;
-use rand::distributions::{Alphanumeric, DistString};
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::time::Duration;
-use std::{env, fs, thread};
-
-use std::fs::File;
-use std::io::prelude::*;
-
-use lava_torrent::torrent::v1::Torrent;
-use lava_torrent::tracker::{Peer, TrackerResponse};
-
-use crate::dht_dispatch::DhtDispatch;
-use crate::peer_dispatch::PeerDispatch;
-use crate::piece_dispatch::PieceDispatch;
-
-trait Test {}
-
-impl Test for Peer {}
-
-fn main() {
-    let torrent = Torrent::read_from_file(
-        env::args()
-            .nth(1)
-            .unwrap_or("torrent/debian.iso.torrent".to_string()),
-    )
-    .unwrap();
-    let peer_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 20);
-
-    /*println!(
-        "torrent files total len {}",
-        torrent2
-            .files
-            .as_ref()
-            .unwrap()
-            .iter()
-            .map(|i| i.length)
-            .sum::<i64>()
-    );*/
-    println!("torrent size: {}", torrent.length);
-    println!("pieces count {}", torrent.pieces.len());
-    println!("one piece length {}", &torrent.piece_length);
-
-    if true {
-        let info_hash = urlencoding::encode_binary(&torrent.info_hash_bytes()).into_owned();
-        let params = [
-            ("peer_id", peer_id.as_str()),
-            ("port", "6888"),
-            ("downloaded", "0"),
-            ("uploaded", "0"),
-            ("left", "0"),
-            ("event", "started"),
-        ];
-
-        let announce = torrent.announce.as_ref().unwrap();
-        let url = reqwest::Url::parse_with_params(announce, &params).unwrap();
-        let url = reqwest::Url::parse(&format!("{}&info_hash={}", url, info_hash)).unwrap();
-
-        let client = reqwest::blocking::Client::builder()
-            .user_agent(NAME)
-            .build()
-            .unwrap();
-        let resp = client.get(url).send().unwrap();
-        let mut f = File::create("peers.torrent").unwrap();
-        f.write_all(&resp.bytes().unwrap()).unwrap();
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use ed25519_dalek::SigningKey;
+use get_torrent::{
+    bencode::Value, create, dht_item::Item, piece_dispatch::PieceDispatch, storage::Storage,
+    CreateOptions, Encryption, Event, Magnet, MetaInfo, Session, Settings, TorrentHandle,
+    TorrentOptions, Transport,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "get-torrent",
+    version,
+    about = "Download a torrent",
+    subcommand_negates_reqs = true
+)]
+struct Args {
+    /// Path to the .torrent file or magnet link
+    #[arg(value_name = "TORRENT", required = true)]
+    torrent: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Save .torrent file of a magnet link once metadata is fetched
+    #[arg(long, value_name = "PATH")]
+    save_torrent: Option<PathBuf>,
+
+    /// Directory the torrent data is stored in
+    #[arg(short, long, value_name = "DIR", default_value = ".")]
+    output: PathBuf,
+
+    /// Port to listen on for incoming peers, 0 picks a free one
+    #[arg(short, long, default_value_t = 6888)]
+    port: u16,
+
+    /// Maximum connected peers
+    #[arg(long, value_name = "N", default_value_t = 50)]
+    max_peers: usize,
+
+    /// Download limit in KiB/s, 0 is unlimited
+    #[arg(long, value_name = "KIB", default_value_t = 0, value_parser = kib_parser())]
+    download_limit: u64,
+
+    /// Upload limit in KiB/s, 0 is unlimited
+    #[arg(long, value_name = "KIB", default_value_t = 0, value_parser = kib_parser())]
+    upload_limit: u64,
+
+    /// Comma separated file indices to download, all files by default
+    #[arg(long, value_name = "INDICES", value_delimiter = ',')]
+    files: Option<Vec<usize>>,
+
+    /// Keep seeding until uploaded bytes reach this ratio of the selected size
+    #[arg(long, value_name = "RATIO")]
+    seed_ratio: Option<f64>,
+
+    /// Keep seeding for this many seconds
+    #[arg(long, value_name = "SECS")]
+    seed_time: Option<u64>,
+
+    /// Do not look for peers in DHT
+    #[arg(long)]
+    no_dht: bool,
+
+    /// UDP port of the DHT node when uTP is disabled, 0 picks a free one
+    #[arg(long, value_name = "PORT", default_value_t = 6881)]
+    dht_port: u16,
+
+    /// Let DHT nodes take our port from the UDP packets, for NATed setups
+    #[arg(long)]
+    dht_implied_port: bool,
+
+    /// File keeping the DHT routing table between runs
+    #[arg(long, value_name = "PATH", default_value = ".get-torrent-dht")]
+    dht_state: PathBuf,
+
+    /// Do not keep the DHT routing table between runs
+    #[arg(long)]
+    no_dht_state: bool,
+
+    /// Do not look for peers on the local network
+    #[arg(long)]
+    no_lsd: bool,
+
+    /// Download only from peers, not from web seeds of the torrent
+    #[arg(long)]
+    no_web_seeds: bool,
+
+    /// Ignore peers from peer exchange
+    #[arg(long)]
+    no_pex: bool,
+
+    /// Stay on IPv4 for peers and DHT
+    #[arg(long)]
+    no_ipv6: bool,
+
+    /// Do not announce to trackers
+    #[arg(long)]
+    no_trackers: bool,
+
+    /// Connection encryption policy
+    #[arg(long, value_enum, default_value_t = EncryptionArg::Prefer)]
+    encryption: EncryptionArg,
+
+    /// Comma separated peer transports in order of preference
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "utp,tcp")]
+    transports: Vec<TransportArg>,
+
+    /// More output, repeat for every event
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Verify data already in the output directory and exit
+    #[arg(long)]
+    check_only: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a .torrent file of a file or directory
+    Create(CreateArgs),
+    /// Fetch an item from the DHT, immutable by target or mutable by key
+    DhtGet {
+        /// Hex target of an immutable item
+        #[arg(value_name = "TARGET", required_unless_present = "key")]
+        target: Option<String>,
+
+        /// Hex public key of a mutable item
+        #[arg(long, value_name = "KEY", conflicts_with = "target")]
+        key: Option<String>,
+
+        /// Salt of the mutable item
+        #[arg(long, default_value = "", requires = "key")]
+        salt: String,
+    },
+    /// Store a string in the DHT, mutable when signed with a key file
+    DhtPut {
+        #[arg(value_name = "VALUE")]
+        value: String,
+
+        /// File with the hex ed25519 secret key, created when missing
+        #[arg(long, value_name = "PATH")]
+        key_file: Option<PathBuf>,
+
+        /// Salt of the mutable item
+        #[arg(long, default_value = "", requires = "key_file")]
+        salt: String,
+
+        /// Sequence number, one above the stored item by default
+        #[arg(long, requires = "key_file")]
+        seq: Option<i64>,
+    },
+    /// Print infohashes sampled from DHT nodes across the keyspace
+    DhtCrawl {
+        /// Stop after this many infohashes
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct CreateArgs {
+    /// File or directory to share
+    #[arg(value_name = "PATH")]
+    path: PathBuf,
+
+    /// Where to write the .torrent file, NAME.torrent by default
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Tracker url, repeat for more tiers, comma separated urls share a tier
+    #[arg(short = 'a', long = "tracker", value_name = "URL")]
+    trackers: Vec<String>,
+
+    /// Web seed url, may be repeated
+    #[arg(short, long = "web-seed", value_name = "URL")]
+    web_seeds: Vec<String>,
+
+    #[arg(short, long)]
+    comment: Option<String>,
+
+    /// Piece length in KiB, a power of two; picked from the size by default
+    #[arg(short = 'l', long, value_name = "KIB", value_parser = kib_parser())]
+    piece_length: Option<u64>,
+
+    /// Only the trackers hand out peers
+    #[arg(long)]
+    private: bool,
+
+    /// Source tag, gives the same files another info hash
+    #[arg(short, long)]
+    source: Option<String>,
+
+    /// Leave out the creation date
+    #[arg(long)]
+    no_date: bool,
+
+    /// Hashing threads, one per cpu by default
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum EncryptionArg {
+    /// Plaintext only
+    Disabled,
+    /// Try encryption first, fall back to plaintext
+    Prefer,
+    /// Encrypted connections only
+    Require,
+}
+
+impl From<EncryptionArg> for Encryption {
+    fn from(e: EncryptionArg) -> Self {
+        match e {
+            EncryptionArg::Disabled => Encryption::Disabled,
+            EncryptionArg::Prefer => Encryption::Prefer,
+            EncryptionArg::Require => Encryption::Require,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TransportArg {
+    Tcp,
+    /// Micro transport protocol over udp
+    Utp,
+}
+
+impl From<TransportArg> for Transport {
+    fn from(t: TransportArg) -> Self {
+        match t {
+            TransportArg::Tcp => Transport::Tcp,
+            TransportArg::Utp => Transport::Utp,
+        }
     }
+}
 
-    let s = fs::read("peers.torrent").unwrap();
-    let resp = TrackerResponse::from_bytes(s).unwrap();
-    let info_hash = <[u8; 20]>::try_from(torrent.info_hash_bytes()).unwrap();
-
-    let piece_dispatch = PieceDispatch::new(&torrent);
-    let dht_dispatch = DhtDispatch::new(info_hash);
-    let peer_dispatch = PeerDispatch::run(
-        &resp,
-        info_hash,
-        peer_id.into_bytes().try_into().unwrap(),
-        piece_dispatch.rx,
-        piece_dispatch.tx,
-        piece_dispatch.complete_piece.clone(),
-        dht_dispatch.msg_port_send.clone(),
-    )
-    .unwrap();
-
-    dht_dispatch.run(peer_dispatch.send_peer);
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    // ctrl-c drops the session, which saves the dht state
+    let result = tokio::select! {
+        result = run(args) => result,
+        Ok(()) = tokio::signal::ctrl_c() => Ok(ExitCode::from(130)),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn run(args: Args) -> Result<ExitCode, String> {
+    // needs no session
+    if let Some(Command::Create(create)) = args.command {
+        return tokio::task::spawn_blocking(move || create_command(&create))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    // clap makes sure it is there without a subcommand
+    let path = args.torrent.as_deref().unwrap_or_default();
+    let magnet = if path.starts_with("magnet:") {
+        Some(Magnet::parse(path).map_err(|e| e.to_string())?)
+    } else {
+        None
+    };
+
+    // local torrents are checked before any socket is bound
+    let local = match (&args.command, &magnet) {
+        (None, None) => Some(
+            MetaInfo::read_from_file(path).map_err(|e| format!("cannot read {}: {}", path, e))?,
+        ),
+        _ => None,
+    };
+    if let Some(torrent) = local.as_ref().filter(|_| args.check_only) {
+        print_info(&args, torrent);
+        return Ok(check(&args, torrent));
+    }
+
+    let settings = Settings {
+        listen_port: args.port,
+        ipv6: !args.no_ipv6,
+        dht: !args.no_dht,
+        dht_port: args.dht_port,
+        dht_implied_port: args.dht_implied_port,
+        dht_state: (!args.no_dht_state).then(|| args.dht_state.clone()),
+        trackers: !args.no_trackers,
+        lsd: !args.no_lsd,
+        web_seeds: !args.no_web_seeds,
+        pex: !args.no_pex,
+        encryption: args.encryption.into(),
+        transports: args.transports.iter().map(|&t| t.into()).collect(),
+        output_dir: args.output.clone(),
+        max_peers: args.max_peers,
+        download_limit: args.download_limit * 1024,
+        upload_limit: args.upload_limit * 1024,
+    };
+    let session = Session::new(settings).await.map_err(|e| e.to_string())?;
+    if let Some(command) = &args.command {
+        return dht_command(&session, command).await;
+    }
+    let mut events = session.subscribe();
+
+    let torrent = match local {
+        Some(torrent) => torrent,
+        None => {
+            let magnet = magnet.as_ref().ok_or("no torrent given")?;
+            if !args.quiet {
+                let name = magnet.name.as_deref().unwrap_or("magnet link");
+                println!("fetching metadata of {}", name);
+            }
+            let bytes = session
+                .fetch_metadata(magnet)
+                .await
+                .map_err(|e| e.to_string())?;
+            if let Some(path) = &args.save_torrent {
+                std::fs::write(path, &bytes)
+                    .map_err(|e| format!("cannot save {}: {}", path.display(), e))?;
+            }
+            MetaInfo::from_bytes(&bytes).map_err(|e| format!("bad metadata: {}", e))?
+        }
+    };
+
+    print_info(&args, &torrent);
+    if args.check_only {
+        return Ok(check(&args, &torrent));
+    }
+
+    let options = TorrentOptions {
+        files: args.files.clone(),
+    };
+    let handle = session
+        .add_torrent_with(torrent, options)
+        .map_err(|e| e.to_string())?;
+
+    let mut finished_at = None;
+    let mut status = tokio::time::interval(Duration::from_secs(1));
     loop {
-        thread::sleep(Duration::from_secs(1));
-        println!(
-            "active peers: {:?}, complete pieces: {}/{}",
-            peer_dispatch.active_peers.lock().len(),
-            piece_dispatch.complete_piece.lock().len(),
-            torrent.pieces.len()
-        );
+        tokio::select! {
+            _ = status.tick() => {
+                if !args.quiet {
+                    print_status(&handle);
+                }
+                if let Some(finished_at) = finished_at {
+                    if seeding_done(&args, &handle, finished_at) {
+                        break;
+                    }
+                }
+            }
+            event = events.recv() => match event {
+                Ok(Event::TorrentFinished { .. }) => {
+                    if !args.quiet {
+                        println!("torrent finished");
+                    }
+                    let now = Instant::now();
+                    if seeding_done(&args, &handle, now) {
+                        break;
+                    }
+                    finished_at = Some(now);
+                }
+                Ok(e @ Event::Error { .. }) => eprintln!("{:?}", e),
+                Ok(e @ Event::TrackerAnnounce { .. })
+                | Ok(e @ Event::WebSeedFailed { .. })
+                | Ok(e @ Event::HashFailed { .. })
+                | Ok(e @ Event::FileCompleted { .. }) if args.verbose >= 1 => println!("{:?}", e),
+                Ok(e) if args.verbose >= 2 => println!("{:?}", e),
+                _ => (),
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// KiB counts which still fit in u64 as bytes
+fn kib_parser() -> clap::builder::RangedU64ValueParser {
+    clap::value_parser!(u64).range(..=u64::MAX / 1024)
+}
+
+fn create_command(args: &CreateArgs) -> Result<ExitCode, String> {
+    let options = CreateOptions {
+        piece_length: args.piece_length.map(|kib| kib * 1024),
+        trackers: args
+            .trackers
+            .iter()
+            .map(|tier| tier.split(',').map(str::to_string).collect())
+            .collect(),
+        web_seeds: args.web_seeds.clone(),
+        comment: args.comment.clone(),
+        created_by: Some(format!("get-torrent {}", env!("CARGO_PKG_VERSION"))),
+        creation_date: (!args.no_date).then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64)
+        }),
+        private: args.private,
+        source: args.source.clone(),
+        threads: args.threads,
+    };
+    let torrent = create::create(&args.path, &options)
+        .map_err(|e| format!("cannot create torrent of {}: {}", args.path.display(), e))?;
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info.name)));
+    std::fs::write(&output, torrent.to_bytes())
+        .map_err(|e| format!("cannot write {}: {}", output.display(), e))?;
+    println!("info hash {}", HEXLOWER.encode(&torrent.info_hash));
+    println!(
+        "{} pieces of {} bytes written to {}",
+        torrent.info.piece_count(),
+        torrent.info.piece_length,
+        output.display()
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn dht_command(session: &Session, command: &Command) -> Result<ExitCode, String> {
+    let dht = session.dht().ok_or("dht is disabled")?;
+    match command {
+        Command::Create(_) => unreachable!("created before the session starts"),
+        Command::DhtGet { target, key, salt } => {
+            let v = match (target, key) {
+                (_, Some(key)) => dht
+                    .get_mutable(parse_hex(key)?, salt.as_bytes())
+                    .await
+                    .map(|item| item.v),
+                (Some(target), None) => dht.get_immutable(parse_hex(target)?).await,
+                (None, None) => return Err("missing target or key".to_string()),
+            };
+            match v {
+                Some(Value::Bytes(b)) if std::str::from_utf8(&b).is_ok() => {
+                    println!("{}", String::from_utf8_lossy(&b))
+                }
+                Some(v) => println!("{:?}", v),
+                None => {
+                    eprintln!("item not found");
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+        }
+        Command::DhtPut {
+            value,
+            key_file,
+            salt,
+            seq,
+        } => {
+            let v = Value::from(value.as_str());
+            let item = match key_file {
+                None => Item::immutable(v),
+                Some(path) => {
+                    let key = load_key(path)?;
+                    let k = key.verifying_key().to_bytes();
+                    let seq = match seq {
+                        Some(seq) => *seq,
+                        None => dht
+                            .get_mutable(k, salt.as_bytes())
+                            .await
+                            .and_then(|item| item.seq())
+                            .map_or(1, |seq| seq + 1),
+                    };
+                    println!("key {}", HEXLOWER.encode(&k));
+                    println!("seq {}", seq);
+                    Item::mutable(&key, salt.as_bytes().to_vec(), seq, v)
+                }
+            }
+            .map_err(|e| e.to_string())?;
+            println!("target {}", HEXLOWER.encode(&item.target()));
+            if dht.put(item, None).await == 0 {
+                eprintln!("no node stored the item");
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::DhtCrawl { limit } => {
+            let found = dht.crawl();
+            let mut n = 0;
+            while limit.is_none_or(|limit| n < limit) {
+                let Ok(info_hash) = found.recv_async().await else {
+                    break;
+                };
+                println!("{}", HEXLOWER.encode(&info_hash));
+                n += 1;
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], String> {
+    HEXLOWER_PERMISSIVE
+        .decode(s.as_bytes())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| format!("expected {} hex encoded bytes: {}", N, s))
+}
+
+// hex secret key, a new one is written when the file does not exist
+fn load_key(path: &Path) -> Result<SigningKey, String> {
+    let secret = match std::fs::read_to_string(path) {
+        Ok(hex) => parse_hex(hex.trim())?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let secret = rand::random::<[u8; 32]>();
+            std::fs::write(path, HEXLOWER.encode(&secret))
+                .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+            secret
+        }
+        Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+    };
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+fn print_status(handle: &TorrentHandle) {
+    let s = handle.status();
+    println!(
+        "active peers: {:?}, complete pieces: {}/{}, down: {} up: {}",
+        s.peers, s.complete_pieces, s.wanted_pieces, s.downloaded, s.uploaded
+    );
+}
+
+// without seeding options we stop as soon as the download is finished
+fn seeding_done(args: &Args, handle: &TorrentHandle, finished_at: Instant) -> bool {
+    if args.seed_ratio.is_none() && args.seed_time.is_none() {
+        return true;
+    }
+    let s = handle.status();
+    let ratio_done = args.seed_ratio.is_some_and(|ratio| {
+        let size =
+            (s.wanted_pieces as u64 * handle.storage().piece_length).min(handle.storage().length);
+        s.uploaded as f64 >= ratio * size as f64
+    });
+    let time_done = args
+        .seed_time
+        .is_some_and(|secs| finished_at.elapsed() >= Duration::from_secs(secs));
+    ratio_done || time_done
+}
+
+fn print_info(args: &Args, torrent: &MetaInfo) {
+    if !args.quiet {
+        println!("torrent size: {}", torrent.info.length);
+        println!("pieces count {}", torrent.info.piece_count());
+        println!("one piece length {}", &torrent.info.piece_length);
+    }
+}
+
+fn check(args: &Args, torrent: &MetaInfo) -> ExitCode {
+    let storage = Arc::new(Storage::new(args.output.clone(), torrent));
+    let pieces = PieceDispatch::new(torrent, storage.clone(), None);
+    let count = storage.piece_count();
+    let mut bad = 0;
+    for index in 0..count {
+        if !storage.verify(&pieces.piece(index)) {
+            bad += 1;
+            if args.verbose >= 1 {
+                println!("piece {} missing or corrupt", index);
+            }
+        }
+    }
+    if !args.quiet {
+        println!("{}/{} pieces ok", count - bad, count);
+    }
+    if bad == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
 }
@@ -0,0 +1,121 @@
+use sha2::{Digest, Sha256};
+
+use crate::BLOCK_SIZE;
+
+// most hashes a hash request may ask for, see BEP 52
+pub const MAX_HASHES: usize = 512;
+
+// v2 files are hashed in a binary tree of sha256, the leaves are the hashes
+// of the 16 KiB blocks and leaves past the end of the file are zero, see
+// BEP 52
+pub fn hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// root of a subtree with 2^height zero leaves, it pads the layers above
+// the leaves
+pub fn pad_hash(height: u32) -> [u8; 32] {
+    (0..height).fold([0; 32], |pad, _| parent(&pad, &pad))
+}
+
+// next layer up, an odd last node is paired with pad
+pub fn layer_up(layer: &[[u8; 32]], pad: &[u8; 32]) -> Vec<[u8; 32]> {
+    layer
+        .chunks(2)
+        .map(|pair| parent(&pair[0], pair.get(1).unwrap_or(pad)))
+        .collect()
+}
+
+// root over width nodes, width is a power of two and nodes past the given
+// ones are pad
+pub fn root(layer: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = layer.to_vec();
+    let mut pad = pad;
+    let mut width = width.max(1);
+    while width > 1 {
+        layer = layer_up(&layer, &pad);
+        pad = parent(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+// root of the blocks of data over width leaves
+pub fn data_root(data: &[u8], width: usize) -> [u8; 32] {
+    let leaves = data
+        .chunks(BLOCK_SIZE as usize)
+        .map(hash)
+        .collect::<Vec<_>>();
+    root(&leaves, width, [0; 32])
+}
+
+// leaves of a file of len bytes, the tree is that wide
+pub fn width(len: u64) -> usize {
+    (len.div_ceil(BLOCK_SIZE as u64) as usize).next_power_of_two()
+}
+
+// height of the piece layer above the leaves
+pub fn piece_height(piece_length: u64) -> u32 {
+    (piece_length / BLOCK_SIZE as u64).max(1).ilog2()
+}
+
+// the root the piece layer of a file hashes up to
+pub fn layer_root(layer: &[[u8; 32]], piece_length: u64) -> [u8; 32] {
+    let pad = pad_hash(piece_height(piece_length));
+    root(layer, layer.len().next_power_of_two(), pad)
+}
+
+// answer to a hash request from the piece layer of a file: length nodes
+// of the base layer from index on, followed by the uncles of their subtree
+// from the bottom up, at most proof_layers of them; None if the piece
+// layer cannot serve the request
+pub fn hashes(
+    layer: &[[u8; 32]],
+    piece_length: u64,
+    base: u32,
+    index: usize,
+    length: usize,
+    proof_layers: u32,
+) -> Option<Vec<[u8; 32]>> {
+    let height = piece_height(piece_length);
+    // the root of the file is the highest layer there is
+    let top = height + layer.len().next_power_of_two().ilog2();
+    if base < height
+        || base > top
+        || layer.is_empty()
+        || !length.is_power_of_two()
+        || length > MAX_HASHES
+        || !index.is_multiple_of(length)
+    {
+        return None;
+    }
+    let mut pad = pad_hash(height);
+    let mut nodes = layer.to_vec();
+    nodes.resize(layer.len().next_power_of_two(), pad);
+    for _ in height..base {
+        nodes = layer_up(&nodes, &pad);
+        pad = parent(&pad, &pad);
+    }
+    let mut hashes = nodes.get(index..index + length)?.to_vec();
+    // subtree of the requested nodes is already proven by themselves
+    for _ in 0..length.ilog2() {
+        nodes = layer_up(&nodes, &pad);
+    }
+    let mut pos = index / length;
+    for _ in 0..proof_layers {
+        if nodes.len() < 2 {
+            break;
+        }
+        hashes.push(nodes[pos ^ 1]);
+        nodes = layer_up(&nodes, &pad);
+        pos /= 2;
+    }
+    Some(hashes)
+}
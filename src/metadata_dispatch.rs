@@ -0,0 +1,240 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use flume::{Receiver, Sender};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::{
+    task::JoinSet,
+    time::{error::Elapsed, sleep, timeout},
+};
+
+use crate::{
+    merkle,
+    metainfo::{self, Info, PieceLayers},
+    mse::Encryption,
+    peer_proto::{
+        self,
+        message::{ExtHandshake, Extended, HashRequest, UtMetadata, UT_METADATA},
+        Message, PeerProto, Transports,
+    },
+    NAME, UT_METADATA_EXTENDED_MSG_ID,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// whole exchange with one peer
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+// whole search, peer sources never run dry on their own
+const METADATA_TIMEOUT: Duration = Duration::from_secs(600);
+pub const METADATA_PIECE_LEN: usize = 16384;
+// biggest info dict we agree to download
+const MAX_METADATA_SIZE: usize = 1 << 24;
+const PARALLEL_PEERS: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum Err {
+    #[error("Peer proto error")]
+    PeerProto(#[from] peer_proto::Error),
+    #[error("Timed out")]
+    Timeout(#[from] Elapsed),
+    #[error("Peer does not support metadata exchange")]
+    Unsupported,
+    #[error("Peer rejected metadata request")]
+    Rejected,
+    #[error("Metadata does not match info hash")]
+    InfoHash,
+    #[error("Malformed metadata")]
+    MetaInfo(#[from] metainfo::Error),
+    #[error("Peer rejected hash request")]
+    HashRejected,
+    #[error("Piece layer does not match file root")]
+    PieceLayer,
+}
+
+// downloads the info dictionary of a magnet link from peers, see BEP 9
+pub struct MetadataDispatch {
+    pub info_hash: [u8; 20],
+    pub local_peer_id: [u8; 20],
+    pub encryption: Encryption,
+    pub transports: Transports,
+    pub send_peer: Sender<SocketAddr>,
+    pub get_peer: Receiver<SocketAddr>,
+}
+
+impl MetadataDispatch {
+    pub fn new(
+        info_hash: [u8; 20],
+        local_peer_id: [u8; 20],
+        encryption: Encryption,
+        transports: Transports,
+    ) -> MetadataDispatch {
+        let (send_peer, get_peer) = flume::unbounded();
+        MetadataDispatch {
+            info_hash,
+            local_peer_id,
+            encryption,
+            transports,
+            send_peer,
+            get_peer,
+        }
+    }
+
+    // asks several peers at once and returns the first info dict which
+    // hashes to info_hash with the piece layers of v2 only torrents, None if
+    // no peer delivered it within METADATA_TIMEOUT
+    pub async fn run(&self) -> Option<(Vec<u8>, PieceLayers)> {
+        let mut peers = JoinSet::new();
+        // trackers announce the same peers again and again
+        let mut tried = HashSet::new();
+        let deadline = sleep(METADATA_TIMEOUT);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                addr = self.get_peer.recv_async(), if peers.len() < PARALLEL_PEERS => {
+                    let addr = addr.ok()?;
+                    if !tried.insert(addr) {
+                        continue;
+                    }
+                    let info_hash = self.info_hash;
+                    let local_peer_id = self.local_peer_id;
+                    let encryption = self.encryption;
+                    let transports = self.transports.clone();
+                    peers.spawn(async move {
+                        let fetch =
+                            Self::fetch(addr, info_hash, local_peer_id, encryption, &transports);
+                        timeout(PEER_TIMEOUT, fetch).await?
+                    });
+                }
+                Some(res) = peers.join_next() => {
+                    if let Ok(Ok(info)) = res {
+                        return Some(info);
+                    }
+                }
+                _ = &mut deadline => return None,
+            }
+        }
+    }
+
+    async fn fetch(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        local_peer_id: [u8; 20],
+        encryption: Encryption,
+        transports: &Transports,
+    ) -> Result<(Vec<u8>, PieceLayers), Err> {
+        let connect = PeerProto::connect(addr, info_hash, local_peer_id, encryption, transports);
+        let p = timeout(CONNECT_TIMEOUT, connect).await??;
+        if !p.peer_handshake.extended_support() {
+            return Err(Err::Unsupported);
+        }
+        let handshake = ExtHandshake {
+            m: BTreeMap::from([(UT_METADATA.to_string(), UT_METADATA_EXTENDED_MSG_ID)]),
+            v: Some(NAME.to_string()),
+            ..Default::default()
+        };
+        p.send(Message::Extended(Extended::Handshake(handshake)))
+            .await?;
+
+        // peer may send bitfield and haves before its extended handshake
+        let size = loop {
+            if let Message::Extended(Extended::Handshake(_)) = p.recv().await? {
+                match p.peer_extensions().and_then(|h| h.metadata_size) {
+                    Some(size) if p.supports(UT_METADATA) && size <= MAX_METADATA_SIZE => {
+                        break size
+                    }
+                    _ => return Err(Err::Unsupported),
+                }
+            }
+        };
+
+        let pieces = size.div_ceil(METADATA_PIECE_LEN);
+        for piece in 0..pieces {
+            let request = UtMetadata::Request { piece };
+            p.send(Message::Extended(Extended::UtMetadata(request)))
+                .await?;
+        }
+
+        let mut info = vec![0; size];
+        let mut received = vec![false; pieces];
+        while received.contains(&false) {
+            match p.recv().await? {
+                Message::Extended(Extended::UtMetadata(UtMetadata::Data {
+                    piece, data, ..
+                })) => {
+                    if piece >= pieces {
+                        return Err(Err::PeerProto(peer_proto::Error::Malformed(20)));
+                    }
+                    let begin = piece * METADATA_PIECE_LEN;
+                    let len = METADATA_PIECE_LEN.min(size - begin);
+                    if data.len() != len {
+                        return Err(Err::PeerProto(peer_proto::Error::Malformed(20)));
+                    }
+                    info[begin..begin + len].copy_from_slice(&data);
+                    received[piece] = true;
+                }
+                Message::Extended(Extended::UtMetadata(UtMetadata::Reject { .. })) => {
+                    return Err(Err::Rejected)
+                }
+                _ => (),
+            }
+        }
+
+        // v2 only torrents go by the truncated sha256
+        if Sha1::digest(&info).as_slice() != info_hash && merkle::hash(&info)[..20] != info_hash {
+            return Err(Err::InfoHash);
+        }
+        let layers = Self::fetch_layers(&p, &Info::from_bytes(&info)?).await?;
+        Ok((info, layers))
+    }
+
+    // nothing else verifies pieces of v2 only torrents, hybrid ones fall
+    // back to sha1 and are spared the requests, see BEP 52
+    async fn fetch_layers(p: &PeerProto, info: &Info) -> Result<PieceLayers, Err> {
+        let mut layers = PieceLayers::new();
+        if !info.pieces.is_empty() {
+            return Ok(layers);
+        }
+        for (_, length, root) in info.file_roots() {
+            let count = length.div_ceil(info.piece_length) as usize;
+            if count <= 1 {
+                continue;
+            }
+            if !p.peer_handshake.v2_support() {
+                return Err(Err::Unsupported);
+            }
+            let mut layer = Vec::with_capacity(count);
+            while layer.len() < count {
+                let length = (count - layer.len())
+                    .next_power_of_two()
+                    .clamp(2, merkle::MAX_HASHES);
+                let request = HashRequest {
+                    pieces_root: root,
+                    base_layer: merkle::piece_height(info.piece_length),
+                    index: layer.len() as u32,
+                    length: length as u32,
+                    proof_layers: 0,
+                };
+                p.send(Message::HashRequest(request)).await?;
+                loop {
+                    match p.recv().await? {
+                        Message::Hashes(h) if h.request == request && h.hashes.len() >= length => {
+                            layer.extend_from_slice(&h.hashes[..length]);
+                            break;
+                        }
+                        Message::HashReject(r) if r == request => return Err(Err::HashRejected),
+                        _ => (),
+                    }
+                }
+            }
+            layer.truncate(count);
+            if merkle::layer_root(&layer, info.piece_length) != root {
+                return Err(Err::PieceLayer);
+            }
+            layers.insert(root, layer);
+        }
+        Ok(layers)
+    }
+}
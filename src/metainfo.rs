@@ -0,0 +1,539 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    bencode::{self, Value},
+    merkle, BLOCK_SIZE,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot read torrent file")]
+    Io(#[from] io::Error),
+    #[error("Malformed bencode: {0}")]
+    Bencode(#[from] bencode::Error),
+    #[error("Missing {0}")]
+    Missing(&'static str),
+    #[error("Invalid {0}")]
+    Invalid(&'static str),
+}
+
+// merkle roots of v2 files to their piece layer, see BEP 52
+pub type PieceLayers = BTreeMap<[u8; 32], Vec<[u8; 32]>>;
+
+// contents of a .torrent file, see BEP 3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaInfo {
+    pub announce: Option<String>,
+    // tiers of trackers, see BEP 12
+    pub announce_list: Vec<Vec<String>>,
+    // web seeds, see BEP 19 and BEP 17
+    pub url_list: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub info: Info,
+    // sha1 of the info dict, v2 only torrents use info_hash_v2 truncated
+    pub info_hash: [u8; 20],
+    // sha256 of the info dict of v2 and hybrid torrents, see BEP 52
+    pub info_hash_v2: Option<[u8; 32]>,
+    // info dict exactly as in the file, its hash is the info hash
+    pub info_bytes: Vec<u8>,
+    // files of one piece have none, torrents made from magnet links may
+    // lack them
+    pub piece_layers: PieceLayers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u64,
+    // empty for v2 only torrents
+    pub pieces: Vec<[u8; 20]>,
+    // of all files together, pad files included
+    pub length: u64,
+    // None for single file torrents, files of the v2 file tree are padded
+    // to piece boundaries like those of hybrid torrents
+    pub files: Option<Vec<File>>,
+    // 2 for v2 and hybrid torrents, see BEP 52
+    pub meta_version: i64,
+    // merkle root of a single file v2 torrent
+    pub pieces_root: Option<[u8; 32]>,
+    // peers only come from the trackers, see BEP 27
+    pub private: bool,
+    pub source: Option<String>,
+    pub md5sum: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    pub length: u64,
+    // relative to the torrent directory
+    pub path: PathBuf,
+    pub md5sum: Option<String>,
+    // only aligns the next file to a piece, never stored, see BEP 47
+    pub pad: bool,
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+impl MetaInfo {
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<MetaInfo, Error> {
+        MetaInfo::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<MetaInfo, Error> {
+        let root = Value::decode(buf)?;
+        let root = root.as_dict().ok_or(Error::Invalid("torrent"))?;
+        let info_bytes = raw_value(buf, b"info")?
+            .ok_or(Error::Missing("info"))?
+            .to_vec();
+        let info = Info::from_value(root.get(&b"info"[..]).ok_or(Error::Missing("info"))?)?;
+        let (info_hash, info_hash_v2) = info_hashes(&info, &info_bytes);
+        let piece_layers = match root.get(&b"piece layers"[..]) {
+            Some(layers) => piece_layers(layers, &info)?,
+            None => PieceLayers::new(),
+        };
+
+        let mut announce_list = match root.get(&b"announce-list"[..]) {
+            Some(tiers) => tiers
+                .as_list()
+                .ok_or(Error::Invalid("announce-list"))?
+                .iter()
+                .map(|tier| strings(tier, "announce-list"))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        announce_list.retain(|tier| !tier.is_empty());
+        // a single url is allowed in place of the list
+        let mut url_list = match root.get(&b"url-list"[..]) {
+            Some(Value::Bytes(_)) => string(root, "url-list", "url-list")?.into_iter().collect(),
+            Some(urls) => strings(urls, "url-list")?,
+            None => Vec::new(),
+        };
+        url_list.retain(|url| !url.is_empty());
+        let http_seeds = match root.get(&b"httpseeds"[..]) {
+            Some(urls) => strings(urls, "httpseeds")?,
+            None => Vec::new(),
+        };
+        Ok(MetaInfo {
+            announce: string(root, "announce", "announce")?,
+            announce_list,
+            url_list,
+            http_seeds,
+            comment: string(root, "comment", "comment")?,
+            created_by: string(root, "created by", "created by")?,
+            creation_date: int(root, "creation date", "creation date")?,
+            info,
+            info_hash,
+            info_hash_v2,
+            info_bytes,
+            piece_layers,
+        })
+    }
+
+    // torrent of a bare info dict as fetched from peers or just created,
+    // nothing outside of it is set
+    pub fn from_info(info_bytes: Vec<u8>, piece_layers: PieceLayers) -> Result<MetaInfo, Error> {
+        let info = Info::from_bytes(&info_bytes)?;
+        let (info_hash, info_hash_v2) = info_hashes(&info, &info_bytes);
+        Ok(MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info,
+            info_hash,
+            info_hash_v2,
+            info_bytes,
+            piece_layers,
+        })
+    }
+
+    // .torrent file, the info dict goes in as is so the info hash stays
+    // the same
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut torrent = BTreeMap::new();
+        if let Some(announce) = &self.announce {
+            torrent.insert("announce", Value::from(announce.as_str()));
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|tier| Value::List(tier.iter().map(|t| Value::from(t.as_str())).collect()))
+                .collect();
+            torrent.insert("announce-list", Value::List(tiers));
+        }
+        let list =
+            |urls: &[String]| Value::List(urls.iter().map(|u| Value::from(u.as_str())).collect());
+        if !self.url_list.is_empty() {
+            torrent.insert("url-list", list(&self.url_list));
+        }
+        if !self.http_seeds.is_empty() {
+            torrent.insert("httpseeds", list(&self.http_seeds));
+        }
+        if let Some(comment) = &self.comment {
+            torrent.insert("comment", Value::from(comment.as_str()));
+        }
+        if let Some(created_by) = &self.created_by {
+            torrent.insert("created by", Value::from(created_by.as_str()));
+        }
+        if let Some(date) = self.creation_date {
+            torrent.insert("creation date", Value::Int(date));
+        }
+        if !self.piece_layers.is_empty() {
+            let layers = self
+                .piece_layers
+                .iter()
+                .map(|(root, layer)| (root.to_vec(), Value::Bytes(layer.concat())))
+                .collect();
+            torrent.insert("piece layers", Value::Dict(layers));
+        }
+        // placeholder keeps the key order
+        torrent.insert("info", Value::Int(0));
+
+        let mut buf = b"d".to_vec();
+        for (key, value) in torrent {
+            Value::from(key).encode_to(&mut buf);
+            match key {
+                "info" => buf.extend_from_slice(&self.info_bytes),
+                _ => value.encode_to(&mut buf),
+            }
+        }
+        buf.push(b'e');
+        buf
+    }
+}
+
+// v2 only torrents go by the truncated sha256
+fn info_hashes(info: &Info, info_bytes: &[u8]) -> ([u8; 20], Option<[u8; 32]>) {
+    let info_hash_v2 = (info.meta_version == 2).then(|| merkle::hash(info_bytes));
+    let info_hash = match info_hash_v2 {
+        Some(hash) if info.pieces.is_empty() => hash[..20].try_into().unwrap(),
+        _ => Sha1::digest(info_bytes).into(),
+    };
+    (info_hash, info_hash_v2)
+}
+
+impl Info {
+    // the bare info dict, as fetched from peers
+    pub fn from_bytes(buf: &[u8]) -> Result<Info, Error> {
+        Info::from_value(&Value::decode(buf)?)
+    }
+
+    fn from_value(info: &Value) -> Result<Info, Error> {
+        let info = info.as_dict().ok_or(Error::Invalid("info"))?;
+        // utf-8 variants of some clients win when they are well formed
+        let name = match string(info, "name.utf-8", "info.name.utf-8") {
+            Ok(Some(name)) => name,
+            _ => string(info, "name", "info.name")?.ok_or(Error::Missing("info.name"))?,
+        };
+        let piece_length = int(info, "piece length", "info.piece length")?
+            .ok_or(Error::Missing("info.piece length"))?;
+        if piece_length <= 0 {
+            return Err(Error::Invalid("info.piece length"));
+        }
+        let piece_length = piece_length as u64;
+        let meta_version = int(info, "meta version", "info.meta version")?.unwrap_or(1);
+        let tree = match meta_version {
+            1 => None,
+            2 => {
+                let mut tree = Vec::new();
+                file_tree(
+                    info.get(&b"file tree"[..])
+                        .ok_or(Error::Missing("info.file tree"))?,
+                    &mut Vec::new(),
+                    &mut tree,
+                )?;
+                Some(tree)
+            }
+            _ => return Err(Error::Invalid("info.meta version")),
+        };
+        if tree.is_some() && (!piece_length.is_power_of_two() || piece_length < BLOCK_SIZE as u64) {
+            return Err(Error::Invalid("info.piece length"));
+        }
+        let pieces = match info.get(&b"pieces"[..]) {
+            Some(pieces) => pieces.as_bytes().ok_or(Error::Invalid("info.pieces"))?,
+            None if tree.is_some() => &[],
+            None => return Err(Error::Missing("info.pieces")),
+        };
+        if pieces.len() % 20 != 0 {
+            return Err(Error::Invalid("info.pieces"));
+        }
+        let pieces = pieces
+            .chunks(20)
+            .map(|hash| hash.try_into().unwrap())
+            .collect::<Vec<[u8; 20]>>();
+
+        let files = match info.get(&b"files"[..]) {
+            Some(files) => Some(
+                files
+                    .as_list()
+                    .filter(|files| !files.is_empty())
+                    .ok_or(Error::Invalid("info.files"))?
+                    .iter()
+                    .map(File::from_value)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        let single_length = match files {
+            Some(_) => None,
+            None => int(info, "length", "info.length")?
+                .map(|length| u64::try_from(length).map_err(|_| Error::Invalid("info.length")))
+                .transpose()?,
+        };
+        let (files, single_length, pieces_root) = match (tree, files, single_length) {
+            (None, files, length) => (files, length, None),
+            (Some(tree), Some(files), _) => (Some(hybrid_files(tree, files)?), None, None),
+            // one file named like the torrent is a single file torrent
+            (Some(tree), None, length)
+                if tree.len() == 1
+                    && tree[0].path == Path::new(&name)
+                    && length.is_none_or(|l| l == tree[0].length) =>
+            {
+                (None, Some(tree[0].length), tree[0].pieces_root)
+            }
+            (Some(tree), None, None) => (Some(padded(tree, piece_length)), None, None),
+            (Some(_), None, Some(_)) => return Err(Error::Invalid("info.file tree")),
+        };
+        let length = match (&files, single_length) {
+            (Some(files), _) => files.iter().map(|f| f.length).sum(),
+            (None, Some(length)) => length,
+            (None, None) => return Err(Error::Missing("info.length")),
+        };
+        if (meta_version == 1 || !pieces.is_empty())
+            && length.div_ceil(piece_length) != pieces.len() as u64
+        {
+            return Err(Error::Invalid("info.pieces"));
+        }
+        Ok(Info {
+            name,
+            piece_length,
+            pieces,
+            length,
+            files,
+            meta_version,
+            pieces_root,
+            private: int(info, "private", "info.private")? == Some(1),
+            source: string(info, "source", "info.source")?,
+            md5sum: string(info, "md5sum", "info.md5sum")?,
+        })
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.length.div_ceil(self.piece_length) as usize
+    }
+
+    // offset in the torrent, length and merkle root of every v2 file
+    // which is not empty
+    pub fn file_roots(&self) -> Vec<(u64, u64, [u8; 32])> {
+        match &self.files {
+            Some(files) => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .filter_map(|f| {
+                        let start = offset;
+                        offset += f.length;
+                        Some((start, f.length, f.pieces_root?))
+                    })
+                    .collect()
+            }
+            None => self
+                .pieces_root
+                .map(|root| (0, self.length, root))
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+impl File {
+    fn from_value(file: &Value) -> Result<File, Error> {
+        let file = file.as_dict().ok_or(Error::Invalid("info.files"))?;
+        let length =
+            int(file, "length", "info.files.length")?.ok_or(Error::Missing("info.files.length"))?;
+        let path = match file
+            .get(&b"path.utf-8"[..])
+            .map(|p| strings(p, "info.files.path.utf-8"))
+        {
+            Some(Ok(path)) => path,
+            _ => strings(
+                file.get(&b"path"[..])
+                    .ok_or(Error::Missing("info.files.path"))?,
+                "info.files.path",
+            )?,
+        };
+        if path.is_empty() {
+            return Err(Error::Invalid("info.files.path"));
+        }
+        Ok(File {
+            length: u64::try_from(length).map_err(|_| Error::Invalid("info.files.length"))?,
+            path: path.iter().collect(),
+            md5sum: string(file, "md5sum", "info.files.md5sum")?,
+            pad: string(file, "attr", "info.files.attr")?.is_some_and(|attr| attr.contains('p')),
+            pieces_root: None,
+        })
+    }
+}
+
+// files of a v2 file tree in tree order, a dict with the empty key holds
+// the file at the path of the keys leading to it
+fn file_tree(tree: &Value, path: &mut Vec<String>, files: &mut Vec<File>) -> Result<(), Error> {
+    let tree = tree.as_dict().ok_or(Error::Invalid("info.file tree"))?;
+    for (name, node) in tree {
+        if !name.is_empty() {
+            let name = std::str::from_utf8(name).map_err(|_| Error::Invalid("info.file tree"))?;
+            path.push(name.to_string());
+            file_tree(node, path, files)?;
+            path.pop();
+            continue;
+        }
+        let file = node.as_dict().ok_or(Error::Invalid("info.file tree"))?;
+        let length = int(file, "length", "info.file tree.length")?
+            .ok_or(Error::Missing("info.file tree.length"))?;
+        let length = u64::try_from(length).map_err(|_| Error::Invalid("info.file tree.length"))?;
+        let pieces_root = match file.get(&b"pieces root"[..]) {
+            Some(root) => Some(
+                root.as_bytes()
+                    .and_then(|r| r.try_into().ok())
+                    .ok_or(Error::Invalid("info.file tree.pieces root"))?,
+            ),
+            None if length == 0 => None,
+            None => return Err(Error::Missing("info.file tree.pieces root")),
+        };
+        if path.is_empty() {
+            return Err(Error::Invalid("info.file tree"));
+        }
+        files.push(File {
+            length,
+            path: path.iter().collect(),
+            md5sum: None,
+            pad: false,
+            pieces_root,
+        });
+    }
+    Ok(())
+}
+
+// v1 file list of a hybrid torrent with the roots of the file tree, both
+// must list the same files
+fn hybrid_files(tree: Vec<File>, mut files: Vec<File>) -> Result<Vec<File>, Error> {
+    let mut tree = tree.into_iter();
+    for file in files.iter_mut().filter(|f| !f.pad) {
+        match tree.next() {
+            Some(t) if t.path == file.path && t.length == file.length => {
+                file.pieces_root = t.pieces_root
+            }
+            _ => return Err(Error::Invalid("info.file tree")),
+        }
+    }
+    match tree.next() {
+        Some(_) => Err(Error::Invalid("info.file tree")),
+        None => Ok(files),
+    }
+}
+
+// every file of a v2 torrent starts at a piece, pad files fill the gaps
+// like in hybrid torrents
+fn padded(tree: Vec<File>, piece_length: u64) -> Vec<File> {
+    let count = tree.len();
+    let mut files = Vec::with_capacity(count * 2);
+    for (i, file) in tree.into_iter().enumerate() {
+        let pad = (piece_length - file.length % piece_length) % piece_length;
+        files.push(file);
+        if pad > 0 && i + 1 < count {
+            files.push(File {
+                length: pad,
+                path: PathBuf::from(".pad").join(pad.to_string()),
+                md5sum: None,
+                pad: true,
+                pieces_root: None,
+            });
+        }
+    }
+    files
+}
+
+// layers of files of the torrent which hash up to the root of their file
+fn piece_layers(layers: &Value, info: &Info) -> Result<PieceLayers, Error> {
+    let layers = layers.as_dict().ok_or(Error::Invalid("piece layers"))?;
+    let mut valid = PieceLayers::new();
+    for (_, length, root) in info.file_roots() {
+        let Some(layer) = layers.get(&root[..]) else {
+            continue;
+        };
+        let layer = layer.as_bytes().ok_or(Error::Invalid("piece layers"))?;
+        if layer.len() % 32 != 0 {
+            return Err(Error::Invalid("piece layers"));
+        }
+        let layer = layer
+            .chunks(32)
+            .map(|hash| hash.try_into().unwrap())
+            .collect::<Vec<[u8; 32]>>();
+        if layer.len() as u64 != length.div_ceil(info.piece_length)
+            || merkle::layer_root(&layer, info.piece_length) != root
+        {
+            return Err(Error::Invalid("piece layers"));
+        }
+        valid.insert(root, layer);
+    }
+    Ok(valid)
+}
+
+// raw bytes of a value of the top level dict, the decoder keeps none
+fn raw_value<'a>(buf: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, bencode::Error> {
+    let mut pos = 1;
+    while buf.get(pos).is_some_and(|b| *b != b'e') {
+        let (k, len) = Value::decode_prefix(&buf[pos..])?;
+        pos += len;
+        let (_, len) = Value::decode_prefix(&buf[pos..])?;
+        if k.as_bytes() == Some(key) {
+            return Ok(Some(&buf[pos..pos + len]));
+        }
+        pos += len;
+    }
+    Ok(None)
+}
+
+// optional keys, field names the key in errors
+fn string(
+    dict: &BTreeMap<Vec<u8>, Value>,
+    key: &str,
+    field: &'static str,
+) -> Result<Option<String>, Error> {
+    match dict.get(key.as_bytes()) {
+        Some(v) => Ok(Some(v.as_str().ok_or(Error::Invalid(field))?.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn int(
+    dict: &BTreeMap<Vec<u8>, Value>,
+    key: &str,
+    field: &'static str,
+) -> Result<Option<i64>, Error> {
+    match dict.get(key.as_bytes()) {
+        Some(v) => Ok(Some(v.as_int().ok_or(Error::Invalid(field))?)),
+        None => Ok(None),
+    }
+}
+
+fn strings(list: &Value, field: &'static str) -> Result<Vec<String>, Error> {
+    list.as_list()
+        .ok_or(Error::Invalid(field))?
+        .iter()
+        .map(|s| s.as_str().map(str::to_string).ok_or(Error::Invalid(field)))
+        .collect()
+}
@@ -0,0 +1,368 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::peer_proto::Stream;
+
+// 768 bit prime from the MSE spec, generator is 2
+const P: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                 020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                 4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A3620FFFFFFFFFFFFFFFF";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAIN: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Error while io")]
+    Io(#[from] io::Error),
+    #[error("Encryption handshake out of sync")]
+    Sync,
+    #[error("Peer asks for unknown info hash")]
+    InfoHash,
+    #[error("No common crypto method")]
+    Crypto,
+    #[error("Padding too long")]
+    Pad,
+}
+
+// connection encryption policy, plaintext peers are refused on Require
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encryption {
+    Disabled,
+    #[default]
+    Prefer,
+    Require,
+}
+
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    // first 1024 bytes of keystream are dropped as the spec says
+    fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        let mut rc4 = Rc4 { s, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+// stream after the encryption handshake, bytes which were read during the
+// handshake but belong to the payload are served first
+pub struct MseStream<S> {
+    inner: S,
+    prefix: Vec<u8>,
+    read_key: Option<Rc4>,
+    write_key: Option<Rc4>,
+    // encrypted bytes not yet accepted by inner
+    pending: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    pub fn plain(inner: S, prefix: Vec<u8>) -> MseStream<S> {
+        MseStream {
+            inner,
+            prefix,
+            read_key: None,
+            write_key: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.write_key.is_some()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if let Some(key) = &mut this.read_key {
+                    key.apply(&mut buf.filled_mut()[filled..]);
+                }
+                Poll::Ready(Ok(()))
+            }
+            r => r,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.pending.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// encrypted data is buffered, so it is written out on the next write or flush
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.write_key.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if !this.pending.is_empty() {
+            return Poll::Pending;
+        }
+        let mut data = buf.to_vec();
+        if let Some(key) = &mut this.write_key {
+            key.apply(&mut data);
+        }
+        this.pending = data;
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            r => r,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            r => r,
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut h = Sha1::new();
+    for p in parts {
+        h.update(p);
+    }
+    h.finalize().into()
+}
+
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+// private exponent and public key Y = 2^x mod P
+fn keypair() -> (BigUint, [u8; KEY_LEN]) {
+    let p = BigUint::parse_bytes(P.as_bytes(), 16).unwrap();
+    let mut x = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut x);
+    let x = BigUint::from_bytes_be(&x);
+    let y = BigUint::from(2u8).modpow(&x, &p);
+    (x, to_key(&y))
+}
+
+fn shared_secret(y: &[u8; KEY_LEN], x: &BigUint) -> [u8; KEY_LEN] {
+    let p = BigUint::parse_bytes(P.as_bytes(), 16).unwrap();
+    to_key(&BigUint::from_bytes_be(y).modpow(x, &p))
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0; rng.gen_range(0..=MAX_PAD)];
+    rng.fill_bytes(&mut pad);
+    pad
+}
+
+// reads until the stream ends with pattern, the peer pad is before it
+async fn sync<S: Stream>(stream: &mut S, pattern: &[u8], limit: usize) -> Result<(), Error> {
+    let mut window = Vec::with_capacity(limit);
+    while window.len() < limit {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(Error::Sync)
+}
+
+// 2 byte length and data, used for pads and the initial payload
+async fn read_block<S: Stream>(stream: &mut S, key: &mut Rc4) -> Result<Vec<u8>, Error> {
+    let len = stream.read_u16().await?;
+    let mut len = len.to_be_bytes();
+    key.apply(&mut len);
+    let len = u16::from_be_bytes(len) as usize;
+    if len > MAX_PAD {
+        return Err(Error::Pad);
+    }
+    let mut pad = vec![0; len];
+    stream.read_exact(&mut pad).await?;
+    key.apply(&mut pad);
+    Ok(pad)
+}
+
+// outgoing side, rc4 is chosen whenever the peer agrees
+pub async fn initiate<S: Stream>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    policy: Encryption,
+) -> Result<MseStream<S>, Error> {
+    let (x, ya) = keypair();
+    stream.write_all(&[&ya[..], &random_pad()].concat()).await?;
+    let mut yb = [0; KEY_LEN];
+    stream.read_exact(&mut yb).await?;
+    let s = shared_secret(&yb, &x);
+
+    let mut enc = Rc4::new(&hash(&[b"keyA", &s, &info_hash]));
+    let mut dec = Rc4::new(&hash(&[b"keyB", &s, &info_hash]));
+    let provide = match policy {
+        Encryption::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAIN,
+    };
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &s]);
+    let mut msg = hash(&[b"req1", &s]).to_vec();
+    msg.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    // empty pad and initial payload, handshake follows on the stream
+    let mut payload = [&VC[..], &provide.to_be_bytes(), &[0, 0], &[0, 0]].concat();
+    enc.apply(&mut payload);
+    msg.extend(payload);
+    stream.write_all(&msg).await?;
+
+    let mut vc = VC;
+    dec.apply(&mut vc);
+    sync(&mut stream, &vc, MAX_PAD + VC.len()).await?;
+    let mut select = [0; 4];
+    stream.read_exact(&mut select).await?;
+    dec.apply(&mut select);
+    read_block(&mut stream, &mut dec).await?;
+
+    match u32::from_be_bytes(select) {
+        CRYPTO_RC4 => Ok(MseStream {
+            read_key: Some(dec),
+            write_key: Some(enc),
+            ..MseStream::plain(stream, Vec::new())
+        }),
+        CRYPTO_PLAIN if policy != Encryption::Require => Ok(MseStream::plain(stream, Vec::new())),
+        _ => Err(Error::Crypto),
+    }
+}
+
+// incoming side, prefix holds the first bytes of the peer public key
+// already read to tell it from a plaintext handshake
+pub async fn accept<S: Stream>(
+    mut stream: S,
+    prefix: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: Encryption,
+) -> Result<(MseStream<S>, [u8; 20]), Error> {
+    let mut ya = [0; KEY_LEN];
+    ya[..prefix.len()].copy_from_slice(prefix);
+    stream.read_exact(&mut ya[prefix.len()..]).await?;
+    let (x, yb) = keypair();
+    stream.write_all(&[&yb[..], &random_pad()].concat()).await?;
+    let s = shared_secret(&ya, &x);
+
+    sync(&mut stream, &hash(&[b"req1", &s]), MAX_PAD + 20).await?;
+    let mut req = [0; 20];
+    stream.read_exact(&mut req).await?;
+    let req3 = hash(&[b"req3", &s]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|ih| {
+            let req2 = hash(&[b"req2", &ih[..]]);
+            req2.iter().zip(req3).map(|(a, b)| a ^ b).eq(req)
+        })
+        .ok_or(Error::InfoHash)?;
+
+    let mut dec = Rc4::new(&hash(&[b"keyA", &s, &info_hash]));
+    let mut enc = Rc4::new(&hash(&[b"keyB", &s, &info_hash]));
+    let mut head = [0; 12];
+    stream.read_exact(&mut head).await?;
+    dec.apply(&mut head);
+    if head[..8] != VC {
+        return Err(Error::Sync);
+    }
+    let provide = u32::from_be_bytes(head[8..12].try_into().unwrap());
+    read_block(&mut stream, &mut dec).await?;
+    // initial payload is encrypted whatever method is selected
+    let ia = read_block(&mut stream, &mut dec).await?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAIN != 0 && policy != Encryption::Require {
+        CRYPTO_PLAIN
+    } else {
+        return Err(Error::Crypto);
+    };
+    let mut reply = [&VC[..], &select.to_be_bytes(), &[0, 0]].concat();
+    enc.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let stream = if select == CRYPTO_RC4 {
+        MseStream {
+            read_key: Some(dec),
+            write_key: Some(enc),
+            ..MseStream::plain(stream, ia)
+        }
+    } else {
+        MseStream::plain(stream, ia)
+    };
+    Ok((stream, info_hash))
+}
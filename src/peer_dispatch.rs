@@ -1,29 +1,56 @@
-use crossbeam_channel::{Receiver, SendError, Sender};
-use lava_torrent::tracker::TrackerResponse;
-use parking_lot::{Condvar, Mutex};
+use flume::{Receiver, Sender};
+use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
-    fmt::Debug,
-    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
-    sync::Arc,
-    thread::{self},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
+use tokio::{
+    sync::{broadcast, mpsc, watch, Semaphore},
+    task::JoinHandle,
+    time::{error::Elapsed, timeout},
+};
 
 use thiserror::Error;
 
 use crate::{
-    //peer_proto::message,
-    //peer_proto::{self, message::Extended, Message, PeerProto},
-    piece::Piece,
-    piece_dispatch::CompletePiece,
-    PARALLEL_REQUEST_PER_PEER,
+    events::{Event, Events},
+    merkle,
+    metadata_dispatch::METADATA_PIECE_LEN,
+    metainfo::PieceLayers,
+    mse::Encryption,
+    peer_proto::{
+        self, local_ext_id, message,
+        message::{
+            Bitfield, ExtHandshake, Extended, PexPeer, UtMetadata, UtPex, PEX_ENCRYPTION,
+            PEX_MAX_PEERS, PEX_REACHABLE, PEX_SEED, PEX_UTP, UT_METADATA, UT_PEX,
+        },
+        Transport, Transports,
+    },
+    piece::{AddError, Piece},
+    piece_dispatch::{CompletePiece, PieceDispatch},
+    rate_limit::RateLimiter,
+    storage::Storage,
+    NAME, PARALLEL_REQUEST_PER_PEER,
 };
 
-use bittorrent_peer_proto::{
-    message::{self, Extended},
-    peer_proto,
-};
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const PIECE_TIMEOUT: Duration = Duration::from_secs(10);
+// requests bigger than that are dropped, see BEP 3
+const MAX_REQUEST_LEN: u32 = 1 << 17;
+// outstanding requests we let a peer queue, told in the extended handshake
+const REQQ: usize = 250;
+// BEP 11 forbids sending ut_pex more often
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// dropped pex peers we remember, and for how long
+const PEX_DROPPED_MAX: usize = 1000;
+const PEX_DROPPED_TTL: Duration = Duration::from_secs(600);
+// size of the allowed fast set we give to fast peers, see BEP 6
+const ALLOWED_FAST: usize = 10;
 
 /*#[derive(Debug)]
 pub struct Error {
@@ -37,8 +64,8 @@ pub enum Err {
     Io(#[from] std::io::Error),
     #[error("Peer proto error")]
     PeerProto(#[from] peer_proto::Error),
-    #[error("Receive message error")]
-    RecvMsg(#[from] peer_proto::RecvMsgError),
+    #[error("Connect timed out")]
+    ConnectTimeout(#[from] Elapsed),
     //#[error("Get peer from peers hashmap error")]
     //GetPeersHashMap,
     //#[error("Error while piece channel sending")]
@@ -47,185 +74,641 @@ pub enum Err {
     BitfieldNotRecv,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 pub enum State {
     Choke,
     Unchoke,
+    Closed,
 }
 
-#[derive(Error, Debug)]
-pub enum RunError {
-    #[error("cannot extract peers from response")]
-    TrackerResponse,
-    #[error("cannot fill sockaddr channel")]
-    SendError(#[from] SendError<SocketAddr>),
+// state of the whole torrent, peers stop at the next safe point
+// and return their piece to the queue when it leaves Running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // piece queue was closed, nothing left to download
+    Finished,
+    // peer did not answer block requests in time
+    PieceTimeout,
+    // peer closed connection or sent garbage
+    PeerClosed,
+    // torrent was paused or removed
+    Paused,
+    ConnectTimeout,
+    Io,
+    PeerProto,
+    BitfieldNotRecv,
+}
+
+impl From<&Err> for DisconnectReason {
+    fn from(e: &Err) -> Self {
+        match e {
+            Err::Io(_) => DisconnectReason::Io,
+            Err::PeerProto(peer_proto::Error::Io(_)) => DisconnectReason::PeerClosed,
+            Err::PeerProto(_) => DisconnectReason::PeerProto,
+            Err::ConnectTimeout(_) => DisconnectReason::ConnectTimeout,
+            Err::BitfieldNotRecv => DisconnectReason::BitfieldNotRecv,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    // address other peers can connect to, advertised over pex
+    pub pex_addr: Option<SocketAddr>,
+    // PEX_* flags
+    pub flags: u8,
+}
+
+type ActivePeers = Arc<Mutex<HashMap<SocketAddr, PeerInfo>>>;
+
+// what we advertised to one peer over pex
+#[derive(Debug, Default)]
+pub struct PexState {
+    pub sent: HashSet<SocketAddr>,
+    pub last: Option<Instant>,
+}
+
+impl PexState {
+    // added and dropped peers since the last message, at most PEX_MAX_PEERS
+    // of each; None if there is nothing new or the last message is less
+    // than PEX_INTERVAL ago, see BEP 11
+    pub fn delta(&mut self, current: &HashMap<SocketAddr, u8>, now: Instant) -> Option<UtPex> {
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added = current
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains(*addr))
+            .take(PEX_MAX_PEERS)
+            .map(|(addr, flags)| PexPeer {
+                addr: *addr,
+                flags: *flags,
+            })
+            .collect::<Vec<_>>();
+        let dropped = self
+            .sent
+            .iter()
+            .filter(|addr| !current.contains_key(*addr))
+            .take(PEX_MAX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for peer in &added {
+            self.sent.insert(peer.addr);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.last = Some(now);
+        Some(UtPex { added, dropped })
+    }
+}
+
+// one connection attempt to a peer, the address is removed from
+// ActivePeers and helper tasks are stopped when the session is dropped
+// whatever the exit path was
+pub struct PeerSession {
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    active_peers: ActivePeers,
+    events: Events,
+    registered: bool,
+    reason: DisconnectReason,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PeerSession {
+    fn new(
+        active_peers: ActivePeers,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        events: Events,
+    ) -> PeerSession {
+        PeerSession {
+            addr,
+            info_hash,
+            active_peers,
+            events,
+            registered: false,
+            reason: DisconnectReason::Finished,
+            tasks: Vec::new(),
+        }
+    }
+
+    fn register(&mut self, info: PeerInfo) {
+        self.active_peers.lock().insert(self.addr, info);
+        self.registered = true;
+        self.events.emit(Event::PeerConnected {
+            info_hash: self.info_hash,
+            addr: self.addr,
+        });
+    }
+
+    fn close(&mut self, reason: DisconnectReason) {
+        self.reason = reason;
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn reason(&self) -> DisconnectReason {
+        self.reason
+    }
+}
+
+impl Drop for PeerSession {
+    fn drop(&mut self) {
+        for t in &self.tasks {
+            t.abort();
+        }
+        if self.registered {
+            self.active_peers.lock().remove(&self.addr);
+            self.events.emit(Event::PeerDisconnected {
+                info_hash: self.info_hash,
+                addr: self.addr,
+                reason: self.reason,
+            });
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PeerSettings {
+    // connections per torrent
+    pub max_peers: usize,
+    pub pex: bool,
+    pub encryption: Encryption,
+    pub transports: Transports,
+    pub listen_port: u16,
+    // raw info dict served over ut_metadata, None disables it
+    pub metadata: Option<Arc<Vec<u8>>>,
+    // answers hash requests of v2 peers
+    pub piece_layers: Arc<PieceLayers>,
+    // shared by all torrents of the session
+    pub download_limit: Arc<RateLimiter>,
+    pub upload_limit: Arc<RateLimiter>,
+}
+
+// everything a peer task shares with the rest of its torrent
+#[derive(Clone)]
+struct PeerContext {
+    active_peers: ActivePeers,
+    info_hash: [u8; 20],
+    local_peer_id: [u8; 20],
+    get_piece: Receiver<Piece>,
+    return_piece: Sender<Piece>,
+    verified_piece: Sender<Piece>,
+    complete_piece: CompletePiece,
+    have_tx: broadcast::Sender<usize>,
+    storage: Arc<Storage>,
+    send_peer: Sender<SocketAddr>,
+    // peers reported gone by pex by the peer which advertised them,
+    // skipped once when dequeued
+    pex_dropped: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+    msg_port_send: Sender<(SocketAddr, message::Port)>,
+    control: watch::Receiver<RunState>,
+    events: Events,
+    settings: PeerSettings,
+    downloaded: Arc<AtomicU64>,
+    uploaded: Arc<AtomicU64>,
+}
+
+// what the peer has and what it lets us request while choked
+struct PeerPieces {
+    have: Bitfield,
+    allowed_fast: HashSet<u32>,
+}
+
+// block answers forwarded from the reader task to the piece loop
+enum Block {
+    Piece(message::Piece),
+    // fast peers reject instead of silently dropping requests
+    Rejected(message::Request),
+}
+
+// per connection state shared by the reader task and the piece loop
+struct PeerLink {
+    choke_tx: watch::Sender<State>,
+    peer_pieces: Arc<Mutex<PeerPieces>>,
+    // pieces we let the peer request while it is choked
+    allowed_fast: HashSet<u32>,
+    fast: bool,
+    // message read in place of the bitfield, handled first
+    first: Option<peer_proto::Message>,
+}
+
+enum Connection {
+    Outgoing(SocketAddr),
+    // already handshaked by the session listener
+    Incoming(Box<peer_proto::PeerProto>),
 }
 
-type ActivePeers = Arc<Mutex<HashMap<SocketAddr, ()>>>;
-type ChokeLock = Arc<(Mutex<State>, Condvar)>;
 pub struct PeerDispatch {
     pub send_peer: Sender<SocketAddr>,
     pub get_peer: Receiver<SocketAddr>,
+    pub send_conn: Sender<peer_proto::PeerProto>,
     pub active_peers: ActivePeers,
+    pub downloaded: Arc<AtomicU64>,
+    pub uploaded: Arc<AtomicU64>,
 }
 
 impl PeerDispatch {
     pub fn run(
-        resp: &TrackerResponse,
         info_hash: [u8; 20],
         local_peer_id: [u8; 20],
-        get_piece: Receiver<Piece>,
-        return_piece: Sender<Piece>,
-        complete_piece: CompletePiece,
+        piece_dispatch: &PieceDispatch,
         msg_port_send: Sender<(SocketAddr, message::Port)>,
-    ) -> Result<PeerDispatch, RunError> {
-        let (send_peer, get_peer) = crossbeam_channel::unbounded();
-
-        // TODO: start periodically poll tracker
-        match resp {
-            TrackerResponse::Success { peers, .. } => {
-                for p in peers {
-                    send_peer.send(p.addr)?;
-                }
-            }
-            TrackerResponse::Failure { .. } => return Err(RunError::TrackerResponse),
-        }
+        control: watch::Receiver<RunState>,
+        events: Events,
+        settings: PeerSettings,
+    ) -> PeerDispatch {
+        let (send_peer, get_peer) = flume::unbounded();
+        let (send_conn, get_conn) = flume::unbounded();
 
         let active_peers = Arc::new(Mutex::new(HashMap::new()));
+        let downloaded = Arc::new(AtomicU64::new(0));
+        let uploaded = Arc::new(AtomicU64::new(0));
 
-        let ap = active_peers.clone();
-        let gp = get_peer.clone();
-        let sp = send_peer.clone();
-        thread::spawn(move || {
-            Self::peer_receiver(
-                ap,
-                gp,
-                sp,
-                info_hash,
-                local_peer_id,
-                get_piece,
-                return_piece,
-                complete_piece,
-                msg_port_send,
-            )
-        });
+        let ctx = PeerContext {
+            active_peers: active_peers.clone(),
+            info_hash,
+            local_peer_id,
+            get_piece: piece_dispatch.rx.clone(),
+            return_piece: piece_dispatch.tx.clone(),
+            verified_piece: piece_dispatch.verified_tx.clone(),
+            complete_piece: piece_dispatch.complete_piece.clone(),
+            have_tx: piece_dispatch.have_tx.clone(),
+            storage: piece_dispatch.storage.clone(),
+            send_peer: send_peer.clone(),
+            pex_dropped: Arc::new(Mutex::new(HashMap::new())),
+            msg_port_send,
+            control,
+            events,
+            settings,
+            downloaded: downloaded.clone(),
+            uploaded: uploaded.clone(),
+        };
+        tokio::spawn(Self::peer_receiver(ctx, get_peer.clone(), get_conn));
 
-        Ok(PeerDispatch {
+        PeerDispatch {
             send_peer,
             get_peer,
+            send_conn,
             active_peers,
-        })
+            downloaded,
+            uploaded,
+        }
     }
 
-    fn peer_receiver(
-        active_peers: ActivePeers,
+    async fn peer_receiver(
+        mut ctx: PeerContext,
         get_peer: Receiver<SocketAddr>,
-        send_peer: Sender<SocketAddr>,
-        info_hash: [u8; 20],
-        local_peer_id: [u8; 20],
-        get_piece: Receiver<Piece>,
-        return_piece: Sender<Piece>,
-        complete_piece: CompletePiece,
-        msg_port_send: Sender<(SocketAddr, message::Port)>,
+        get_conn: Receiver<peer_proto::PeerProto>,
     ) {
-        while let Ok(addr) = get_peer.recv() {
-            if active_peers.lock().contains_key(&addr) {
+        let slots = Arc::new(Semaphore::new(ctx.settings.max_peers.max(1)));
+        loop {
+            // addresses are kept in the queue while paused
+            let state = *ctx.control.borrow_and_update();
+            match state {
+                RunState::Stopped => return,
+                RunState::Paused => {
+                    if ctx.control.changed().await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                RunState::Running => (),
+            }
+            let permit = tokio::select! {
+                permit = slots.clone().acquire_owned() => match permit {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                },
+                _ = ctx.control.changed() => continue,
+            };
+            let conn = tokio::select! {
+                addr = get_peer.recv_async() => match addr {
+                    Ok(addr) => Connection::Outgoing(addr),
+                    Err(_) => return,
+                },
+                conn = get_conn.recv_async() => match conn {
+                    Ok(conn) => Connection::Incoming(Box::new(conn)),
+                    Err(_) => return,
+                },
+                _ = ctx.control.changed() => continue,
+            };
+            let addr = match &conn {
+                Connection::Outgoing(addr) => *addr,
+                Connection::Incoming(p) => p.addr,
+            };
+            if ctx.active_peers.lock().contains_key(&addr) {
                 continue;
             }
-            let ap = active_peers.clone();
-            let ih = info_hash.clone();
-            let pi = local_peer_id.clone();
-            let gp = get_piece.clone();
-            let rp = return_piece.clone();
-            let cp = complete_piece.clone();
-            let sp = send_peer.clone();
-            let mp = msg_port_send.clone();
-            thread::spawn(move || Self::peer_run(ap, addr, ih, pi, gp, rp, cp, sp, mp));
+            if let Connection::Outgoing(addr) = &conn {
+                if ctx.pex_dropped.lock().remove(addr).is_some() {
+                    continue;
+                }
+            }
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                Self::peer_run(ctx, conn).await;
+                drop(permit);
+            });
         }
     }
 
-    fn peer_run(
-        active_peers: ActivePeers,
-        addr: SocketAddr,
-        info_hash: [u8; 20],
-        local_peer_id: [u8; 20],
-        get_piece: Receiver<Piece>,
-        return_piece: Sender<Piece>,
-        complete_piece: CompletePiece,
-        send_peer: Sender<SocketAddr>,
-        msg_port_send: Sender<(SocketAddr, message::Port)>,
+    async fn peer_run(ctx: PeerContext, conn: Connection) -> DisconnectReason {
+        let addr = match &conn {
+            Connection::Outgoing(addr) => *addr,
+            Connection::Incoming(p) => p.addr,
+        };
+        let mut session = PeerSession::new(
+            ctx.active_peers.clone(),
+            addr,
+            ctx.info_hash,
+            ctx.events.clone(),
+        );
+        if let Err(e) = Self::peer_session(&mut session, ctx.clone(), conn).await {
+            session.close(DisconnectReason::from(&e));
+        }
+        // reconnect to this peer once the torrent is resumed
+        if session.reason() == DisconnectReason::Paused {
+            #[allow(unused_must_use)]
+            {
+                ctx.send_peer.send(addr);
+            }
+        }
+        session.reason()
+    }
+
+    async fn peer_session(
+        session: &mut PeerSession,
+        mut ctx: PeerContext,
+        conn: Connection,
     ) -> Result<(), Err> {
-        let s = TcpStream::connect(addr)?;
-        let t = peer_proto::PeerProto::handshake(s, info_hash, local_peer_id);
-        if t.is_err() {
-            println!(
-                "peer {} connected but handshake failed due to {:?}",
-                addr, t
-            );
+        let addr = session.addr();
+        let outgoing = matches!(conn, Connection::Outgoing(_));
+        let p = match conn {
+            Connection::Outgoing(_) => {
+                let t = timeout(
+                    CONNECT_TIMEOUT,
+                    peer_proto::PeerProto::connect(
+                        addr,
+                        ctx.info_hash,
+                        ctx.local_peer_id,
+                        ctx.settings.encryption,
+                        &ctx.settings.transports,
+                    ),
+                )
+                .await?;
+                match t {
+                    Ok(p) => p,
+                    Err(peer_proto::Error::Io(e)) => return Err(e.into()),
+                    Err(e) => {
+                        ctx.events.emit(Event::Error {
+                            info_hash: Some(ctx.info_hash),
+                            message: format!("peer {} connected but handshake failed: {}", addr, e),
+                        });
+                        return Err(e.into());
+                    }
+                }
+            }
+            Connection::Incoming(p) => *p,
+        };
+        let p = Arc::new(p);
+        let fast = p.peer_handshake.fast_support();
+        let piece_count = ctx.storage.piece_count();
+
+        // bitfield goes first, see BEP 3
+        let bitfield = ctx.complete_piece.lock().clone();
+        let have = match bitfield.count() {
+            0 if fast => Some(peer_proto::Message::HaveNone),
+            0 => None,
+            n if fast && n == piece_count => Some(peer_proto::Message::HaveAll),
+            _ => Some(peer_proto::Message::Bitfield(bitfield)),
+        };
+        if let Some(have) = have {
+            p.send(have).await?;
+        }
+        if p.peer_handshake.extended_support() {
+            let handshake = Self::ext_handshake(&ctx.settings, addr);
+            p.send(peer_proto::Message::Extended(Extended::Handshake(
+                handshake,
+            )))
+            .await?;
+        }
+        // pieces the peer may take from us while choked
+        let mut allowed_fast = HashSet::new();
+        if let (true, IpAddr::V4(ip)) = (fast, addr.ip()) {
+            for index in peer_proto::allowed_fast_set(ip, ctx.info_hash, piece_count, ALLOWED_FAST)
+            {
+                allowed_fast.insert(index);
+                p.send(peer_proto::Message::AllowedFast(index)).await?;
+            }
         }
-        let p = Arc::new(t?);
 
-        let msg = p.recv()?;
-        let bitfield = match msg {
+        // fast peers must start with one of the have messages, others may
+        // skip the bitfield when they have nothing
+        let mut first = None;
+        let have = match p.recv().await? {
             peer_proto::Message::Bitfield(bf) => bf,
-            _ => return Err(Err::BitfieldNotRecv),
+            peer_proto::Message::HaveAll if fast => Bitfield::full(piece_count),
+            peer_proto::Message::HaveNone if fast => Bitfield::new(piece_count),
+            _ if fast => return Err(Err::BitfieldNotRecv),
+            msg => {
+                first = Some(msg);
+                Bitfield::new(piece_count)
+            }
         };
 
-        active_peers.lock().insert(addr, ());
+        let mut flags = 0;
+        if have.count() >= piece_count {
+            flags |= PEX_SEED;
+        }
+        // incoming peers get their address once the extended handshake tells the port
+        if outgoing {
+            flags |= PEX_REACHABLE;
+        }
+        if p.encrypted {
+            flags |= PEX_ENCRYPTION;
+        }
+        if p.transport == Transport::Utp {
+            flags |= PEX_UTP;
+        }
+        session.register(PeerInfo {
+            pex_addr: outgoing.then_some(addr),
+            flags,
+        });
         //println!("{:?}", p.peer_handshake.extended_support());
 
-        p.send(peer_proto::Message::Interested)?;
+        p.send(peer_proto::Message::Interested).await?;
 
-        let choke_lock = Arc::new((Mutex::new(State::Choke), Condvar::new()));
+        let (choke_tx, mut choke_rx) = watch::channel(State::Choke);
+        let peer_pieces = Arc::new(Mutex::new(PeerPieces {
+            have,
+            allowed_fast: HashSet::new(),
+        }));
 
-        let (msg_piece_tx, msg_piece_rx) = crossbeam_channel::unbounded();
-        let pp = p.clone();
-        let cl = choke_lock.clone();
-        thread::spawn(move || {
-            Self::preprocess_received_msg(cl, pp, msg_piece_tx, send_peer, msg_port_send)
-        });
-
-        // waiting while choked
-        let (lock, cvar) = &*choke_lock;
-        let mut choke = lock.lock();
-        if *choke == State::Choke {
-            cvar.wait(&mut choke);
+        let (msg_piece_tx, mut msg_piece_rx) = mpsc::unbounded_channel();
+        session
+            .tasks
+            .push(tokio::spawn(Self::preprocess_received_msg(
+                ctx.clone(),
+                PeerLink {
+                    choke_tx,
+                    peer_pieces: peer_pieces.clone(),
+                    allowed_fast,
+                    fast,
+                    first,
+                },
+                p.clone(),
+                msg_piece_tx,
+            )));
+        session.tasks.push(tokio::spawn(Self::forward_have(
+            ctx.have_tx.subscribe(),
+            p.clone(),
+        )));
+        if ctx.settings.pex {
+            session
+                .tasks
+                .push(tokio::spawn(Self::pex_sender(ctx.clone(), p.clone())));
         }
 
-        //thread::sleep(Duration::from_secs(300));
+        // pieces taken from the queue in a row which this peer cannot serve
+        let mut misses = 0;
+        loop {
+            // while choked only allowed fast pieces can be requested, wait
+            // for unchoke when none of them is left
+            let choked = match *choke_rx.borrow() {
+                State::Closed => {
+                    session.close(DisconnectReason::PeerClosed);
+                    return Ok(());
+                }
+                state => state == State::Choke,
+            };
+            let can_fetch = !choked
+                || peer_pieces
+                    .lock()
+                    .allowed_fast
+                    .iter()
+                    .any(|i| ctx.complete_piece.lock().get(*i as usize) == Some(false));
+            if !can_fetch || misses > ctx.get_piece.len() {
+                misses = 0;
+                tokio::select! {
+                    r = timeout(PIECE_TIMEOUT, choke_rx.changed()) => if let Ok(Err(_)) = r {
+                        session.close(DisconnectReason::PeerClosed);
+                        return Ok(());
+                    },
+                    _ = interrupted(&mut ctx.control) => {
+                        session.close(DisconnectReason::Paused);
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
 
-        while let Ok(mut piece) = get_piece.recv() {
-            if bitfield.get(piece.index) != Some(true) {
+            let mut piece = tokio::select! {
+                piece = ctx.get_piece.recv_async() => match piece {
+                    Ok(piece) => piece,
+                    Err(_) => break,
+                },
+                _ = interrupted(&mut ctx.control) => {
+                    session.close(DisconnectReason::Paused);
+                    return Ok(());
+                }
+            };
+            let available = {
+                let peer_pieces = peer_pieces.lock();
+                peer_pieces.have.get(piece.index) == Some(true)
+                    && (!choked || peer_pieces.allowed_fast.contains(&(piece.index as u32)))
+            };
+            if !available {
                 #[allow(unused_must_use)]
                 {
-                    return_piece.send(piece);
+                    ctx.return_piece.send(piece);
                 }
+                misses += 1;
+                // let other peers take the piece before we get it back again
+                tokio::task::yield_now().await;
                 continue;
             }
-            for u in piece.unfinished_blocks().chunks(PARALLEL_REQUEST_PER_PEER) {
+            misses = 0;
+            let reqq = p
+                .peer_extensions()
+                .and_then(|h| h.reqq)
+                .unwrap_or(PARALLEL_REQUEST_PER_PEER)
+                .clamp(1, PARALLEL_REQUEST_PER_PEER);
+            let mut rejected = false;
+            'blocks: for u in piece.unfinished_blocks().chunks(reqq) {
                 for uc in u {
+                    ctx.settings.download_limit.acquire(uc.len as u64).await;
                     #[allow(unused_must_use)]
                     {
                         p.send(peer_proto::Message::Request(message::Request::new(
                             piece.index as u32,
                             uc.begin,
                             uc.len,
-                        )));
+                        )))
+                        .await;
                     }
                 }
-                for _ in 0..PARALLEL_REQUEST_PER_PEER {
-                    match msg_piece_rx.recv_timeout(Duration::from_secs(10)) {
-                        Ok(msg_piece) => {
+                let mut waiting = u.len();
+                while waiting > 0 {
+                    let r = tokio::select! {
+                        r = timeout(PIECE_TIMEOUT, msg_piece_rx.recv()) => r,
+                        _ = interrupted(&mut ctx.control) => {
+                            session.close(DisconnectReason::Paused);
                             #[allow(unused_must_use)]
                             {
-                                piece.add(msg_piece.begin, msg_piece.block);
+                                ctx.return_piece.send(piece);
+                            }
+                            return Ok(());
+                        }
+                    };
+                    match r {
+                        // late blocks of a piece we gave up on
+                        Ok(Some(Block::Piece(msg_piece)))
+                            if msg_piece.index as usize != piece.index => {}
+                        Ok(Some(Block::Piece(msg_piece))) => {
+                            waiting -= 1;
+                            ctx.downloaded
+                                .fetch_add(msg_piece.block.len() as u64, Ordering::Relaxed);
+                            if let Err(AddError::HashMismatch) =
+                                piece.add(msg_piece.begin, msg_piece.block)
+                            {
+                                ctx.events.emit(Event::HashFailed {
+                                    info_hash: ctx.info_hash,
+                                    index: piece.index,
+                                    addr,
+                                });
                             }
                         }
-                        Err(_) => {
-                            active_peers.lock().remove(&addr);
+                        Ok(Some(Block::Rejected(r))) if r.index as usize == piece.index => {
+                            rejected = true;
+                            break 'blocks;
+                        }
+                        Ok(Some(Block::Rejected(_))) => {}
+                        r => {
+                            session.close(match r {
+                                Err(_) => DisconnectReason::PieceTimeout,
+                                _ => DisconnectReason::PeerClosed,
+                            });
                             #[allow(unused_must_use)]
                             {
-                                return_piece.send(piece);
+                                ctx.return_piece.send(piece);
                             }
                             return Ok(());
                         }
@@ -233,67 +716,328 @@ impl PeerDispatch {
                 }
             }
             if piece.complete {
-                complete_piece.lock().push(piece);
+                #[allow(unused_must_use)]
+                {
+                    ctx.verified_piece.send(piece);
+                }
             } else {
                 #[allow(unused_must_use)]
                 {
-                    return_piece.send(piece);
+                    ctx.return_piece.send(piece);
+                }
+                if rejected {
+                    // give the piece to someone else before trying again
+                    tokio::task::yield_now().await;
                 }
             }
         }
+        session.close(DisconnectReason::Finished);
         Ok(())
     }
 
-    fn preprocess_received_msg(
-        choke_lock: ChokeLock,
+    fn ext_handshake(settings: &PeerSettings, addr: SocketAddr) -> ExtHandshake {
+        let mut m = std::collections::BTreeMap::new();
+        let mut enable = |name| {
+            if let Some(id) = local_ext_id(name) {
+                m.insert(name.to_string(), id);
+            }
+        };
+        if settings.pex {
+            enable(UT_PEX);
+        }
+        if settings.metadata.is_some() {
+            enable(UT_METADATA);
+        }
+        ExtHandshake {
+            m,
+            v: Some(NAME.to_string()),
+            p: Some(settings.listen_port),
+            reqq: Some(REQQ),
+            yourip: Some(addr.ip()),
+            metadata_size: settings.metadata.as_ref().map(|m| m.len()),
+        }
+    }
+
+    // advertises our connected peers as added/dropped deltas, see BEP 11
+    async fn pex_sender(ctx: PeerContext, p: Arc<peer_proto::PeerProto>) {
+        let mut state = PexState::default();
+        let mut interval = tokio::time::interval(PEX_INTERVAL);
+        // first tick is immediate, the peer hardly sent its handshake yet
+        interval.tick().await;
+        loop {
+            let now = interval.tick().await.into_std();
+            if !p.supports(UT_PEX) {
+                continue;
+            }
+            let current = ctx
+                .active_peers
+                .lock()
+                .iter()
+                .filter(|(addr, _)| **addr != p.addr)
+                .filter_map(|(_, info)| Some((info.pex_addr?, info.flags)))
+                .collect::<HashMap<_, _>>();
+            let Some(pex) = state.delta(&current, now) else {
+                continue;
+            };
+            if p.send(peer_proto::Message::Extended(Extended::UtPex(pex)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    // tell the peer about pieces we got since the handshake
+    async fn forward_have(mut have_rx: broadcast::Receiver<usize>, p: Arc<peer_proto::PeerProto>) {
+        loop {
+            let index = match have_rx.recv().await {
+                Ok(index) => index,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let have = message::Have {
+                piece_index: index as u32,
+            };
+            if p.send(peer_proto::Message::Have(have)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn preprocess_received_msg(
+        ctx: PeerContext,
+        mut link: PeerLink,
         peer_proto: Arc<peer_proto::PeerProto>,
-        msg_piece_tx: Sender<message::Piece>,
-        send_peer: Sender<SocketAddr>,
-        msg_port_send: Sender<(SocketAddr, message::Port)>,
+        msg_piece_tx: mpsc::UnboundedSender<Block>,
     ) {
-        let s = UdpSocket::bind("0.0.0.0:0").unwrap();
-        while let Ok(msg) = peer_proto.recv() {
+        let choke_tx = link.choke_tx;
+        let piece_count = ctx.storage.piece_count();
+        // we unchoke every interested peer, max_peers bounds upload slots
+        let mut peer_unchoked = false;
+        // peers this one advertised over pex, only those it may drop
+        let mut pex_known = HashSet::new();
+        loop {
+            let msg = match link.first.take() {
+                Some(msg) => msg,
+                None => match peer_proto.recv().await {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
             //println!("{:?} [{:?}] {:?}", Instant::now(), addr, msg);
             match msg {
-                peer_proto::Message::Choke => *choke_lock.0.lock() = State::Choke,
+                peer_proto::Message::Choke => {
+                    choke_tx.send_replace(State::Choke);
+                }
                 peer_proto::Message::Unchoke => {
-                    let (lock, cvar) = &*choke_lock;
-                    let mut choke = lock.lock();
-                    *choke = State::Unchoke;
-                    cvar.notify_one();
+                    choke_tx.send_replace(State::Unchoke);
+                }
+                peer_proto::Message::Have(h) => {
+                    link.peer_pieces
+                        .lock()
+                        .have
+                        .set(h.piece_index as usize, true);
+                }
+                peer_proto::Message::Bitfield(bf) => link.peer_pieces.lock().have = bf,
+                peer_proto::Message::HaveAll if link.fast => {
+                    link.peer_pieces.lock().have = Bitfield::full(piece_count);
+                }
+                peer_proto::Message::HaveNone if link.fast => {
+                    link.peer_pieces.lock().have = Bitfield::new(piece_count);
                 }
-                //Message::Have(h) => cfg.lock().bitfield.set(h.piece_index as usize, true),
-                //Message::Bitfield(bf) => cfg.lock().bitfield = bf,
+                peer_proto::Message::AllowedFast(index) if link.fast => {
+                    link.peer_pieces.lock().allowed_fast.insert(index);
+                    // wake up the piece loop waiting for unchoke
+                    choke_tx.send_modify(|_| ());
+                }
+                peer_proto::Message::RejectRequest(r) if link.fast => {
+                    // the piece loop requeues the piece at once
+                    let rejected = msg_piece_tx.send(Block::Rejected(r));
+                    if rejected.is_err() {
+                        break;
+                    }
+                }
+                // suggestions are only hints, piece order is ours
+                peer_proto::Message::SuggestPiece(_) => (),
                 peer_proto::Message::Piece(p) => {
-                    if msg_piece_tx.send(p).is_err() {
+                    let sent = msg_piece_tx.send(Block::Piece(p));
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+                peer_proto::Message::Interested if !peer_unchoked => {
+                    peer_unchoked = true;
+                    if peer_proto.send(peer_proto::Message::Unchoke).await.is_err() {
                         break;
                     }
-                } //chan_tx.send((addr, p))?,
+                }
+                peer_proto::Message::Request(r) => {
+                    if !(peer_unchoked || link.allowed_fast.contains(&r.index))
+                        || r.len > MAX_REQUEST_LEN
+                        || r.begin as u64 + r.len as u64 > ctx.storage.piece_len(r.index as usize)
+                        || ctx.complete_piece.lock().get(r.index as usize) != Some(true)
+                    {
+                        // fast peers expect an answer for every request
+                        if link.fast
+                            && peer_proto
+                                .send(peer_proto::Message::RejectRequest(r))
+                                .await
+                                .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    ctx.settings.upload_limit.acquire(r.len as u64).await;
+                    let storage = ctx.storage.clone();
+                    let block = tokio::task::spawn_blocking(move || {
+                        storage.read(r.index as usize, r.begin as u64, r.len as u64)
+                    })
+                    .await;
+                    if let Ok(Ok(block)) = block {
+                        let piece = message::Piece {
+                            index: r.index,
+                            begin: r.begin,
+                            block,
+                        };
+                        if peer_proto
+                            .send(peer_proto::Message::Piece(piece))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        ctx.uploaded.fetch_add(r.len as u64, Ordering::Relaxed);
+                    }
+                }
+                peer_proto::Message::HashRequest(r) => {
+                    let hashes = ctx
+                        .settings
+                        .piece_layers
+                        .get(&r.pieces_root)
+                        .and_then(|layer| {
+                            merkle::hashes(
+                                layer,
+                                ctx.storage.piece_length,
+                                r.base_layer,
+                                r.index as usize,
+                                r.length as usize,
+                                r.proof_layers,
+                            )
+                        });
+                    let reply = match hashes {
+                        Some(hashes) => {
+                            peer_proto::Message::Hashes(message::Hashes { request: r, hashes })
+                        }
+                        None => peer_proto::Message::HashReject(r),
+                    };
+                    if peer_proto.send(reply).await.is_err() {
+                        break;
+                    }
+                }
                 peer_proto::Message::Port(port) => {
-                    if let Ok(addr) = peer_proto.stream.peer_addr() {
-                        msg_port_send.send((addr, port));
+                    #[allow(unused_must_use)]
+                    {
+                        ctx.msg_port_send.send((peer_proto.addr, port));
+                    }
+                }
+                peer_proto::Message::Extended(Extended::UtMetadata(UtMetadata::Request {
+                    piece,
+                })) => {
+                    // piece comes from the peer, it may be anything
+                    let begin = piece.checked_mul(METADATA_PIECE_LEN);
+                    let reply = match (&ctx.settings.metadata, begin) {
+                        (Some(metadata), Some(begin)) if begin < metadata.len() => {
+                            UtMetadata::Data {
+                                piece,
+                                total_size: metadata.len(),
+                                data: metadata
+                                    [begin..metadata.len().min(begin + METADATA_PIECE_LEN)]
+                                    .to_vec(),
+                            }
+                        }
+                        _ => UtMetadata::Reject { piece },
+                    };
+                    #[allow(unused_must_use)]
+                    {
+                        peer_proto
+                            .send(peer_proto::Message::Extended(Extended::UtMetadata(reply)))
+                            .await;
                     }
-                    /*
-                    println!(
-                        "port received: {:?} {:?}",
-                        peer_proto.stream.peer_addr(),
-                        port
-                    );*/
-                    if let IpAddr::V4(addr) = peer_proto.stream.peer_addr().unwrap().ip() {
-                        let mut buf = Vec::new();
-                        buf.extend_from_slice(&addr.octets());
-                        buf.extend_from_slice(&port.bytes());
-                        s.send_to(&buf, "127.0.0.1:56565");
+                }
+                peer_proto::Message::Extended(Extended::Handshake(h)) => {
+                    if let (Some(port), Some(info)) =
+                        (h.p, ctx.active_peers.lock().get_mut(&peer_proto.addr))
+                    {
+                        if info.pex_addr.is_none() && port != 0 {
+                            info.pex_addr = Some(SocketAddr::new(peer_proto.addr.ip(), port));
+                        }
                     }
                 }
                 peer_proto::Message::Extended(Extended::UtPex(pex)) => {
-                    for addr in pex.added {
-                        send_peer.send(addr);
+                    if !ctx.settings.pex {
+                        continue;
+                    }
+                    {
+                        let now = Instant::now();
+                        let mut pex_dropped = ctx.pex_dropped.lock();
+                        pex_dropped.retain(|_, at| now.duration_since(*at) < PEX_DROPPED_TTL);
+                        for addr in pex.dropped {
+                            if pex_known.remove(&addr) && pex_dropped.len() < PEX_DROPPED_MAX {
+                                pex_dropped.insert(addr, now);
+                            }
+                        }
+                        for peer in &pex.added {
+                            pex_dropped.remove(&peer.addr);
+                            if pex_known.len() < PEX_DROPPED_MAX {
+                                pex_known.insert(peer.addr);
+                            }
+                        }
+                    }
+                    // seeds are useless once we are seeding ourselves
+                    let seeding = ctx.complete_piece.lock().count() >= ctx.storage.piece_count();
+                    let mut added = pex
+                        .added
+                        .into_iter()
+                        .filter(|peer| !(seeding && peer.flags & PEX_SEED != 0))
+                        .collect::<Vec<_>>();
+                    added.sort_by_key(|peer| std::cmp::Reverse(pex_rank(peer.flags)));
+                    for peer in added {
+                        #[allow(unused_must_use)]
+                        {
+                            ctx.send_peer.send(peer.addr);
+                        }
                     }
                 }
-                peer_proto::Message::Unknown(r) => println!("Received unknown msg: {:?}", r),
                 _ => (),
             }
         }
+        // wake up peer_run if it still waits for unchoke
+        choke_tx.send_replace(State::Closed);
+    }
+}
+
+// resolves as soon as the torrent is not running anymore
+async fn interrupted(control: &mut watch::Receiver<RunState>) {
+    while *control.borrow_and_update() == RunState::Running {
+        if control.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// candidates from pex are tried best first
+fn pex_rank(flags: u8) -> u8 {
+    let mut rank = 0;
+    if flags & PEX_SEED != 0 {
+        rank += 4;
+    }
+    if flags & PEX_REACHABLE != 0 {
+        rank += 2;
+    }
+    if flags & (PEX_ENCRYPTION | PEX_UTP) != 0 {
+        rank += 1;
     }
+    rank
 }
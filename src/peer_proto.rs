@@ -0,0 +1,587 @@
+pub mod message;
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use sha1::{Digest, Sha1};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::{
+    bencode,
+    mse::{self, Encryption},
+    utp::UtpSocket,
+    UT_METADATA_EXTENDED_MSG_ID, UT_PEX_EXTENDED_MSG_ID,
+};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+const HANDSHAKE_LEN: usize = 68;
+// largest block is 16 KiB, bitfield of huge torrent is still far less than that
+const MAX_MSG_LEN: u32 = 1 << 18;
+// peers without utp never answer, leave time for the tcp attempt
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// extensions we understand and the ids peers must use to send them to us
+pub const LOCAL_EXTENSIONS: [(&str, u8); 2] = [
+    (message::UT_PEX, UT_PEX_EXTENDED_MSG_ID),
+    (message::UT_METADATA, UT_METADATA_EXTENDED_MSG_ID),
+];
+
+pub fn local_ext_id(name: &str) -> Option<u8> {
+    LOCAL_EXTENSIONS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, id)| *id)
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Error while io")]
+    Io(#[from] std::io::Error),
+    #[error("Peer speaks unknown protocol")]
+    Protocol,
+    #[error("Peer serves another info hash")]
+    InfoHash,
+    #[error("Message length {0} exceeds limit")]
+    MsgTooLong(u32),
+    #[error("Malformed message with id {0}")]
+    Malformed(u8),
+    #[error("Malformed extended message")]
+    Bencode(#[from] bencode::Error),
+    #[error("Peer does not support extension {0}")]
+    ExtensionUnsupported(&'static str),
+    #[error("Encryption handshake failed")]
+    Mse(#[from] mse::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+        let mut reserved = [0; 8];
+        // BEP 10 extension protocol
+        reserved[5] |= 0x10;
+        // BEP 6 fast extension
+        reserved[7] |= 0x04;
+        // BEP 52 v2 protocol
+        reserved[7] |= 0x10;
+        Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn extended_support(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn dht_support(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    pub fn fast_support(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    pub fn v2_support(&self) -> bool {
+        self.reserved[7] & 0x10 != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0; HANDSHAKE_LEN];
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..68].copy_from_slice(&self.peer_id);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HANDSHAKE_LEN]) -> Result<Handshake, Error> {
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(Error::Protocol);
+        }
+        Ok(Handshake {
+            reserved: buf[20..28].try_into().unwrap(),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..68].try_into().unwrap(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(message::Have),
+    Bitfield(message::Bitfield),
+    Request(message::Request),
+    Piece(message::Piece),
+    Cancel(message::Request),
+    Port(message::Port),
+    // BEP 6 fast extension
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(message::Request),
+    AllowedFast(u32),
+    Extended(message::Extended),
+    // BEP 52 merkle tree nodes
+    HashRequest(message::HashRequest),
+    Hashes(message::Hashes),
+    HashReject(message::HashRequest),
+    Unknown(Vec<u8>),
+}
+
+impl Message {
+    // extended messages get the ids we assigned, see encode_ext
+    pub fn encode(&self) -> Vec<u8> {
+        let ext_id = match self {
+            Message::Extended(e) => e.name().and_then(local_ext_id).unwrap_or(0),
+            _ => 0,
+        };
+        self.encode_ext(ext_id)
+    }
+
+    // ext_id is the id the receiver assigned to the extension of the message
+    pub fn encode_ext(&self, ext_id: u8) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        match self {
+            Message::KeepAlive => (),
+            Message::Choke => buf.push(0),
+            Message::Unchoke => buf.push(1),
+            Message::Interested => buf.push(2),
+            Message::NotInterested => buf.push(3),
+            Message::Have(h) => {
+                buf.push(4);
+                buf.extend_from_slice(&h.piece_index.to_be_bytes());
+            }
+            Message::Bitfield(bf) => {
+                buf.push(5);
+                buf.extend_from_slice(bf.as_bytes());
+            }
+            Message::Request(r) | Message::Cancel(r) | Message::RejectRequest(r) => {
+                buf.push(match self {
+                    Message::Request(_) => 6,
+                    Message::Cancel(_) => 8,
+                    _ => 0x10,
+                });
+                buf.extend_from_slice(&r.index.to_be_bytes());
+                buf.extend_from_slice(&r.begin.to_be_bytes());
+                buf.extend_from_slice(&r.len.to_be_bytes());
+            }
+            Message::Piece(p) => {
+                buf.push(7);
+                buf.extend_from_slice(&p.index.to_be_bytes());
+                buf.extend_from_slice(&p.begin.to_be_bytes());
+                buf.extend_from_slice(&p.block);
+            }
+            Message::Port(p) => {
+                buf.push(9);
+                buf.extend_from_slice(&p.bytes());
+            }
+            Message::SuggestPiece(index) => {
+                buf.push(0x0d);
+                buf.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::HaveAll => buf.push(0x0e),
+            Message::HaveNone => buf.push(0x0f),
+            Message::AllowedFast(index) => {
+                buf.push(0x11);
+                buf.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::HashRequest(r) | Message::HashReject(r) => {
+                buf.push(match self {
+                    Message::HashRequest(_) => 21,
+                    _ => 23,
+                });
+                buf.extend_from_slice(&r.to_bytes());
+            }
+            Message::Hashes(h) => {
+                buf.push(22);
+                buf.extend_from_slice(&h.request.to_bytes());
+                for hash in &h.hashes {
+                    buf.extend_from_slice(hash);
+                }
+            }
+            Message::Extended(message::Extended::Handshake(h)) => {
+                buf.push(20);
+                buf.push(0);
+                buf.extend_from_slice(&h.to_bytes());
+            }
+            Message::Extended(message::Extended::UtMetadata(m)) => {
+                buf.push(20);
+                buf.push(ext_id);
+                buf.extend_from_slice(&m.to_bytes());
+            }
+            Message::Extended(message::Extended::UtPex(pex)) => {
+                buf.push(20);
+                buf.push(ext_id);
+                buf.extend_from_slice(&pex.to_bytes());
+            }
+            Message::Extended(message::Extended::Unknown(id, payload)) => {
+                buf.push(20);
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+            Message::Unknown(raw) => buf.extend_from_slice(raw),
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    // payload is message body with leading id byte
+    pub fn decode(payload: Vec<u8>) -> Result<Message, Error> {
+        let id = match payload.first() {
+            Some(id) => *id,
+            None => return Ok(Message::KeepAlive),
+        };
+        let body = &payload[1..];
+        let u32_at = |i: usize| -> Result<u32, Error> {
+            body.get(i..i + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or(Error::Malformed(id))
+        };
+        let msg = match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(message::Have {
+                piece_index: u32_at(0)?,
+            }),
+            5 => Message::Bitfield(message::Bitfield::from_bytes(body.to_vec())),
+            6 | 8 | 0x10 => {
+                if body.len() != 12 {
+                    return Err(Error::Malformed(id));
+                }
+                let r = message::Request::new(u32_at(0)?, u32_at(4)?, u32_at(8)?);
+                match id {
+                    6 => Message::Request(r),
+                    8 => Message::Cancel(r),
+                    _ => Message::RejectRequest(r),
+                }
+            }
+            7 => Message::Piece(message::Piece {
+                index: u32_at(0)?,
+                begin: u32_at(4)?,
+                block: body[8..].to_vec(),
+            }),
+            9 => {
+                let port = body.get(0..2).ok_or(Error::Malformed(id))?;
+                Message::Port(message::Port {
+                    listen_port: u16::from_be_bytes([port[0], port[1]]),
+                })
+            }
+            0x0d => Message::SuggestPiece(u32_at(0)?),
+            0x0e => Message::HaveAll,
+            0x0f => Message::HaveNone,
+            0x11 => Message::AllowedFast(u32_at(0)?),
+            21 | 23 => {
+                if body.len() != message::HashRequest::LEN {
+                    return Err(Error::Malformed(id));
+                }
+                let r = message::HashRequest::from_bytes(body).ok_or(Error::Malformed(id))?;
+                match id {
+                    21 => Message::HashRequest(r),
+                    _ => Message::HashReject(r),
+                }
+            }
+            22 => {
+                let hashes = body
+                    .get(message::HashRequest::LEN..)
+                    .filter(|h| h.len() % 32 == 0)
+                    .ok_or(Error::Malformed(id))?;
+                Message::Hashes(message::Hashes {
+                    request: message::HashRequest::from_bytes(body).ok_or(Error::Malformed(id))?,
+                    hashes: hashes
+                        .chunks(32)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                })
+            }
+            20 => {
+                let ext_id = *body.first().ok_or(Error::Malformed(id))?;
+                let name = LOCAL_EXTENSIONS
+                    .iter()
+                    .find(|(_, i)| *i == ext_id)
+                    .map(|(n, _)| *n);
+                let ext = match (ext_id, name) {
+                    (0, _) => {
+                        message::Extended::Handshake(message::ExtHandshake::from_bytes(&body[1..])?)
+                    }
+                    (_, Some(message::UT_METADATA)) => {
+                        message::Extended::UtMetadata(message::UtMetadata::from_bytes(&body[1..])?)
+                    }
+                    (_, Some(message::UT_PEX)) => {
+                        message::Extended::UtPex(message::UtPex::from_bytes(&body[1..])?)
+                    }
+                    _ => message::Extended::Unknown(ext_id, body[1..].to_vec()),
+                };
+                Message::Extended(ext)
+            }
+            _ => Message::Unknown(payload),
+        };
+        Ok(msg)
+    }
+}
+
+// pieces a peer with this ip may request while choked, see BEP 6
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: [u8; 20], pieces: usize, k: usize) -> Vec<u32> {
+    let k = k.min(pieces);
+    let mut set = Vec::with_capacity(k);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffffff00).to_be_bytes());
+    x.extend_from_slice(&info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            let index = y % pieces as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+type Reader = BufReader<ReadHalf<Box<dyn Stream>>>;
+type Writer = WriteHalf<Box<dyn Stream>>;
+
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    // BEP 29
+    Utp,
+}
+
+// outgoing connections try transports in order, utp goes over the
+// session socket
+#[derive(Clone)]
+pub struct Transports {
+    pub order: Vec<Transport>,
+    pub utp: Option<Arc<UtpSocket>>,
+}
+
+impl Transports {
+    async fn open(&self, transport: Transport, addr: SocketAddr) -> io::Result<Box<dyn Stream>> {
+        match transport {
+            Transport::Tcp => Ok(Box::new(TcpStream::connect(addr).await?)),
+            Transport::Utp => {
+                let socket = self.utp.as_ref().ok_or(io::ErrorKind::Unsupported)?;
+                let stream = timeout(UTP_CONNECT_TIMEOUT, socket.connect(addr))
+                    .await
+                    .map_err(|_| io::ErrorKind::TimedOut)??;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+// read and write halves are locked separately, so one task may wait
+// for incoming messages while another one sends
+pub struct PeerProto {
+    pub addr: SocketAddr,
+    pub peer_handshake: Handshake,
+    pub transport: Transport,
+    // stream is rc4 encrypted, see mse
+    pub encrypted: bool,
+    // latest extended handshake of the peer, BEP 10
+    peer_ext: parking_lot::Mutex<Option<message::ExtHandshake>>,
+    reader: Mutex<Reader>,
+    writer: Mutex<Writer>,
+}
+
+impl PeerProto {
+    // outgoing connection over the first transport which works
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        local_peer_id: [u8; 20],
+        encryption: Encryption,
+        transports: &Transports,
+    ) -> Result<PeerProto, Error> {
+        let mut res = Err(Error::Io(io::ErrorKind::Unsupported.into()));
+        for &transport in &transports.order {
+            res = Self::connect_via(
+                transports,
+                transport,
+                addr,
+                info_hash,
+                local_peer_id,
+                encryption,
+            )
+            .await;
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+
+    // with Prefer a failed encryption handshake is retried in plaintext
+    async fn connect_via(
+        transports: &Transports,
+        transport: Transport,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        local_peer_id: [u8; 20],
+        encryption: Encryption,
+    ) -> Result<PeerProto, Error> {
+        if encryption != Encryption::Disabled {
+            let stream = transports.open(transport, addr).await?;
+            match mse::initiate(stream, info_hash, encryption).await {
+                Ok(stream) => {
+                    let encrypted = stream.encrypted();
+                    let mut p = Self::handshake(stream, addr, info_hash, local_peer_id).await?;
+                    p.encrypted = encrypted;
+                    p.transport = transport;
+                    return Ok(p);
+                }
+                Err(e) if encryption == Encryption::Require => return Err(e.into()),
+                Err(_) => (),
+            }
+        }
+        let stream = transports.open(transport, addr).await?;
+        let mut p = Self::handshake(stream, addr, info_hash, local_peer_id).await?;
+        p.transport = transport;
+        Ok(p)
+    }
+
+    // outgoing connection, we speak first
+    pub async fn handshake<S: Stream + 'static>(
+        mut stream: S,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        local_peer_id: [u8; 20],
+    ) -> Result<PeerProto, Error> {
+        stream
+            .write_all(&Handshake::new(info_hash, local_peer_id).to_bytes())
+            .await?;
+        let peer_handshake = Self::read_handshake(&mut stream).await?;
+        if peer_handshake.info_hash != info_hash {
+            return Err(Error::InfoHash);
+        }
+        Ok(Self::from_stream(stream, addr, peer_handshake))
+    }
+
+    // incoming connection, peer handshake is already read by the listener
+    // which picked the torrent by its info hash
+    pub async fn accept<S: Stream + 'static>(
+        mut stream: S,
+        addr: SocketAddr,
+        peer_handshake: Handshake,
+        local_peer_id: [u8; 20],
+    ) -> Result<PeerProto, Error> {
+        stream
+            .write_all(&Handshake::new(peer_handshake.info_hash, local_peer_id).to_bytes())
+            .await?;
+        Ok(Self::from_stream(stream, addr, peer_handshake))
+    }
+
+    pub async fn read_handshake<S: Stream>(stream: &mut S) -> Result<Handshake, Error> {
+        let mut buf = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut buf).await?;
+        Handshake::from_bytes(&buf)
+    }
+
+    fn from_stream<S: Stream + 'static>(
+        stream: S,
+        addr: SocketAddr,
+        peer_handshake: Handshake,
+    ) -> PeerProto {
+        let stream: Box<dyn Stream> = Box::new(stream);
+        let (reader, writer) = tokio::io::split(stream);
+        PeerProto {
+            addr,
+            peer_handshake,
+            transport: Transport::Tcp,
+            encrypted: false,
+            peer_ext: parking_lot::Mutex::new(None),
+            reader: Mutex::new(BufReader::new(reader)),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn peer_extensions(&self) -> Option<message::ExtHandshake> {
+        self.peer_ext.lock().clone()
+    }
+
+    // extension is usable once the peer announced it in its handshake
+    pub fn supports(&self, name: &str) -> bool {
+        self.peer_ext
+            .lock()
+            .as_ref()
+            .is_some_and(|h| h.id(name).is_some())
+    }
+
+    pub async fn send(&self, msg: Message) -> Result<(), Error> {
+        let buf = match &msg {
+            Message::Extended(e) => match e.name() {
+                Some(name) => {
+                    let id = self
+                        .peer_ext
+                        .lock()
+                        .as_ref()
+                        .and_then(|h| h.id(name))
+                        .ok_or(Error::ExtensionUnsupported(name))?;
+                    msg.encode_ext(id)
+                }
+                None => msg.encode(),
+            },
+            _ => msg.encode(),
+        };
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&buf).await?;
+        // encrypted streams buffer writes
+        writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn recv(&self) -> Result<Message, Error> {
+        let mut reader = self.reader.lock().await;
+        let len = reader.read_u32().await?;
+        if len > MAX_MSG_LEN {
+            return Err(Error::MsgTooLong(len));
+        }
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload).await?;
+        let msg = Message::decode(payload)?;
+        if let Message::Extended(message::Extended::Handshake(h)) = &msg {
+            let mut peer_ext = self.peer_ext.lock();
+            match peer_ext.as_mut() {
+                Some(ext) => ext.update(h.clone()),
+                None => *peer_ext = Some(h.clone()),
+            }
+        }
+        Ok(msg)
+    }
+}
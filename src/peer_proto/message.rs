@@ -0,0 +1,430 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::bencode::{self, Value};
+
+pub const UT_PEX: &str = "ut_pex";
+pub const UT_METADATA: &str = "ut_metadata";
+
+// ut_pex peer flags, see BEP 11
+pub const PEX_ENCRYPTION: u8 = 0x01;
+pub const PEX_SEED: u8 = 0x02;
+pub const PEX_UTP: u8 = 0x04;
+pub const PEX_HOLEPUNCH: u8 = 0x08;
+pub const PEX_REACHABLE: u8 = 0x10;
+// at most that many added and dropped peers per message
+pub const PEX_MAX_PEERS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Have {
+    pub piece_index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield(vec![0; len.div_ceil(8)])
+    }
+
+    // every piece set, spare bits stay clear
+    pub fn full(len: usize) -> Bitfield {
+        let mut bf = Bitfield::new(len);
+        for i in 0..len {
+            bf.set(i, true);
+        }
+        bf
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Bitfield {
+        Bitfield(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn get(&self, index: usize) -> Option<bool> {
+        self.0
+            .get(index / 8)
+            .map(|b| b & (0x80 >> (index % 8)) != 0)
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if let Some(b) = self.0.get_mut(index / 8) {
+            if value {
+                *b |= 0x80 >> (index % 8);
+            } else {
+                *b &= !(0x80 >> (index % 8));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub index: u32,
+    pub begin: u32,
+    pub len: u32,
+}
+
+impl Request {
+    pub fn new(index: u32, begin: u32, len: u32) -> Request {
+        Request { index, begin, len }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    pub index: u32,
+    pub begin: u32,
+    pub block: Vec<u8>,
+}
+
+// nodes of the merkle tree of a v2 file, see BEP 52
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    // layer of the requested nodes, 0 are the leaves
+    pub base_layer: u32,
+    // first node, a multiple of length
+    pub index: u32,
+    pub length: u32,
+    // uncles to prove the nodes with, from the bottom up
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub const LEN: usize = 48;
+
+    pub fn from_bytes(buf: &[u8]) -> Option<HashRequest> {
+        let u32_at = |i: usize| Some(u32::from_be_bytes(buf.get(i..i + 4)?.try_into().ok()?));
+        Some(HashRequest {
+            pieces_root: buf.get(..32)?.try_into().ok()?,
+            base_layer: u32_at(32)?,
+            index: u32_at(36)?,
+            length: u32_at(40)?,
+            proof_layers: u32_at(44)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.pieces_root.to_vec();
+        buf.extend_from_slice(&self.base_layer.to_be_bytes());
+        buf.extend_from_slice(&self.index.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        buf.extend_from_slice(&self.proof_layers.to_be_bytes());
+        buf
+    }
+}
+
+// answer to a hash request, the nodes followed by the uncles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub request: HashRequest,
+    pub hashes: Vec<[u8; 32]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port {
+    pub listen_port: u16,
+}
+
+impl Port {
+    pub fn bytes(&self) -> [u8; 2] {
+        self.listen_port.to_be_bytes()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extended {
+    Handshake(ExtHandshake),
+    UtPex(UtPex),
+    UtMetadata(UtMetadata),
+    Unknown(u8, Vec<u8>),
+}
+
+impl Extended {
+    // name used in the m dictionary, None for messages with fixed id
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Extended::UtPex(_) => Some(UT_PEX),
+            Extended::UtMetadata(_) => Some(UT_METADATA),
+            Extended::Handshake(_) | Extended::Unknown(..) => None,
+        }
+    }
+}
+
+// BEP 10 handshake, extended message id 0
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtHandshake {
+    // extension name to message id, 0 means disabled
+    pub m: BTreeMap<String, u8>,
+    // client name and version
+    pub v: Option<String>,
+    // tcp listen port of the sender
+    pub p: Option<u16>,
+    // number of outstanding requests the sender accepts
+    pub reqq: Option<usize>,
+    // receiver address as seen by the sender
+    pub yourip: Option<IpAddr>,
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtHandshake {
+    pub fn from_bytes(payload: &[u8]) -> Result<ExtHandshake, bencode::Error> {
+        let v = Value::decode(payload)?;
+        let m = v
+            .get("m")
+            .and_then(Value::as_dict)
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(k, id)| {
+                        let id = u8::try_from(id.as_int()?).ok()?;
+                        Some((String::from_utf8_lossy(k).into_owned(), id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let int = |key| v.get(key).and_then(Value::as_int);
+        let yourip = v
+            .get("yourip")
+            .and_then(Value::as_bytes)
+            .and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+                _ => None,
+            });
+        Ok(ExtHandshake {
+            m,
+            v: v.get("v").and_then(Value::as_str).map(str::to_string),
+            p: int("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: int("reqq").and_then(|r| usize::try_from(r).ok()),
+            yourip,
+            metadata_size: int("metadata_size").and_then(|s| usize::try_from(s).ok()),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let m = self
+            .m
+            .iter()
+            .map(|(k, id)| (k.as_bytes().to_vec(), Value::Int(*id as i64)))
+            .collect();
+        let mut d = BTreeMap::new();
+        d.insert(b"m".to_vec(), Value::Dict(m));
+        if let Some(v) = &self.v {
+            d.insert(b"v".to_vec(), Value::from(v.as_str()));
+        }
+        if let Some(p) = self.p {
+            d.insert(b"p".to_vec(), Value::Int(p as i64));
+        }
+        if let Some(reqq) = self.reqq {
+            d.insert(b"reqq".to_vec(), Value::Int(reqq as i64));
+        }
+        if let Some(ip) = self.yourip {
+            let ip = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            d.insert(b"yourip".to_vec(), Value::Bytes(ip));
+        }
+        if let Some(size) = self.metadata_size {
+            d.insert(b"metadata_size".to_vec(), Value::Int(size as i64));
+        }
+        Value::Dict(d).encode()
+    }
+
+    // later handshakes may enable or disable single extensions
+    pub fn update(&mut self, h: ExtHandshake) {
+        for (name, id) in h.m {
+            if id == 0 {
+                self.m.remove(&name);
+            } else {
+                self.m.insert(name, id);
+            }
+        }
+        self.v = h.v.or(self.v.take());
+        self.p = h.p.or(self.p);
+        self.reqq = h.reqq.or(self.reqq);
+        self.yourip = h.yourip.or(self.yourip);
+        self.metadata_size = h.metadata_size.or(self.metadata_size);
+    }
+
+    // message id the sender of this handshake wants for the extension
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+}
+
+// BEP 9 metadata exchange, data follows the bencoded dict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtMetadata {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl UtMetadata {
+    pub fn from_bytes(payload: &[u8]) -> Result<UtMetadata, super::Error> {
+        let (v, len) = Value::decode_prefix(payload)?;
+        let int = |key| {
+            v.get(key)
+                .and_then(Value::as_int)
+                .and_then(|i| usize::try_from(i).ok())
+                .ok_or(super::Error::Malformed(20))
+        };
+        let piece = int("piece")?;
+        match int("msg_type")? {
+            0 => Ok(UtMetadata::Request { piece }),
+            1 => Ok(UtMetadata::Data {
+                piece,
+                total_size: int("total_size")?,
+                data: payload[len..].to_vec(),
+            }),
+            2 => Ok(UtMetadata::Reject { piece }),
+            _ => Err(super::Error::Malformed(20)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            UtMetadata::Request { piece } => bencode::dict([
+                ("msg_type", Value::Int(0)),
+                ("piece", Value::Int(*piece as i64)),
+            ])
+            .encode(),
+            UtMetadata::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let mut buf = bencode::dict([
+                    ("msg_type", Value::Int(1)),
+                    ("piece", Value::Int(*piece as i64)),
+                    ("total_size", Value::Int(*total_size as i64)),
+                ])
+                .encode();
+                buf.extend_from_slice(data);
+                buf
+            }
+            UtMetadata::Reject { piece } => bencode::dict([
+                ("msg_type", Value::Int(2)),
+                ("piece", Value::Int(*piece as i64)),
+            ])
+            .encode(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+// ipv4 and ipv6 peers share the lists, they are split on the wire
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UtPex {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl UtPex {
+    pub fn from_bytes(payload: &[u8]) -> Result<UtPex, bencode::Error> {
+        let v = Value::decode(payload)?;
+        let bytes = |key| v.get(key).and_then(Value::as_bytes).unwrap_or_default();
+        let added = |key, flags_key, compact: fn(&[u8]) -> Vec<SocketAddr>| {
+            let flags = bytes(flags_key);
+            compact(bytes(key))
+                .into_iter()
+                .enumerate()
+                .map(|(i, addr)| PexPeer {
+                    addr,
+                    flags: flags.get(i).copied().unwrap_or(0),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut pex = UtPex {
+            added: added("added", "added.f", compact_v4),
+            dropped: compact_v4(bytes("dropped")),
+        };
+        pex.added.extend(added("added6", "added6.f", compact_v6));
+        pex.dropped.extend(compact_v6(bytes("dropped6")));
+        Ok(pex)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (added, added6): (Vec<_>, Vec<_>) = self.added.iter().partition(|p| p.addr.is_ipv4());
+        let addrs = |peers: &[&PexPeer]| peers.iter().map(|p| p.addr).collect::<Vec<_>>();
+        let flags = |peers: &[&PexPeer]| peers.iter().map(|p| p.flags).collect::<Vec<_>>();
+        bencode::dict([
+            ("added", Value::Bytes(to_compact_v4(&addrs(&added)))),
+            ("added.f", Value::Bytes(flags(&added))),
+            ("added6", Value::Bytes(to_compact_v6(&addrs(&added6)))),
+            ("added6.f", Value::Bytes(flags(&added6))),
+            ("dropped", Value::Bytes(to_compact_v4(&self.dropped))),
+            ("dropped6", Value::Bytes(to_compact_v6(&self.dropped))),
+        ])
+        .encode()
+    }
+}
+
+// 4 bytes ip and 2 bytes port per peer, trailing garbage is ignored
+pub fn compact_v4(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(6)
+        .map(|c| {
+            let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+            SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([c[4], c[5]]))
+        })
+        .collect()
+}
+
+pub fn to_compact_v4(addrs: &[SocketAddr]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for addr in addrs {
+        if let SocketAddr::V4(addr) = addr {
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+    buf
+}
+
+// 16 bytes ip and 2 bytes port per peer
+pub fn compact_v6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(18)
+        .map(|c| {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&c[..16]).unwrap());
+            SocketAddr::new(IpAddr::V6(ip), u16::from_be_bytes([c[16], c[17]]))
+        })
+        .collect()
+}
+
+pub fn to_compact_v6(addrs: &[SocketAddr]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for addr in addrs {
+        if let SocketAddr::V6(addr) = addr {
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+    buf
+}
@@ -1,7 +1,7 @@
 use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
 
-use crate::BLOCK_SIZE;
+use crate::{merkle, BLOCK_SIZE};
 
 #[derive(Debug)]
 pub enum AddError {
@@ -9,12 +9,28 @@ pub enum AddError {
     BeginNotInRage,
     BlockOversize,
     BlockOverwrite,
+    // all blocks received but piece is corrupted, blocks are dropped
+    HashMismatch,
+}
+
+// merkle root of a piece of a v2 file, see BEP 52
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceRoot {
+    pub root: [u8; 32],
+    // leaves of the tree, the piece length in blocks for files spanning
+    // several pieces
+    pub width: usize,
+    // bytes of the piece in the file, the rest is padding
+    pub len: u32,
 }
 
 #[derive(Debug)]
 pub struct Piece {
     pub index: usize,
-    pub hash: [u8; 20],
+    // None for v2 only torrents
+    pub hash: Option<[u8; 20]>,
+    // None for v1 torrents, hybrid ones must match both
+    pub root: Option<PieceRoot>,
     pub len: u32,
     pub complete: bool,
     pub blocks: BTreeMap<u32, Vec<u8>>,
@@ -29,13 +45,23 @@ pub struct BlockParam {
 
 impl Piece {
     pub fn new(index: usize, hash: [u8; 20], len: u32) -> Piece {
+        Piece::with_hashes(index, Some(hash), None, len)
+    }
+
+    pub fn with_hashes(
+        index: usize,
+        hash: Option<[u8; 20]>,
+        root: Option<PieceRoot>,
+        len: u32,
+    ) -> Piece {
         Piece {
             index,
             hash,
+            root,
             len,
             complete: false,
             blocks: BTreeMap::new(),
-            block_count: (len + BLOCK_SIZE - 1) / BLOCK_SIZE, //divceil
+            block_count: len.div_ceil(BLOCK_SIZE),
         }
     }
 
@@ -59,21 +85,34 @@ impl Piece {
             .collect()
     }
 
-    fn update_complete(&mut self) {
+    // pieces without any hash never verify
+    pub fn verify(&self, data: &[u8]) -> bool {
+        (self.hash.is_some() || self.root.is_some())
+            && self
+                .hash
+                .is_none_or(|hash| Sha1::digest(data).as_slice() == hash)
+            && self.root.is_none_or(|root| {
+                data.get(..root.len as usize)
+                    .is_some_and(|data| merkle::data_root(data, root.width) == root.root)
+            })
+    }
+
+    fn update_complete(&mut self) -> Result<(), AddError> {
         if self.blocks.len() == self.block_count as usize {
-            let mut hasher = Sha1::new();
             let blocks = self
                 .blocks
                 .clone()
                 .into_values()
                 .flatten()
                 .collect::<Vec<_>>();
-            hasher.update(blocks);
-            let result = hasher.finalize().to_vec();
-            if self.hash == result.as_slice() {
+            if self.verify(&blocks) {
                 self.complete = true;
+            } else {
+                self.blocks.clear();
+                return Err(AddError::HashMismatch);
             }
         }
+        Ok(())
     }
 
     pub fn add(&mut self, begin: u32, block: Vec<u8>) -> Result<(), AddError> {
@@ -91,7 +130,7 @@ impl Piece {
         }
 
         let insert_res = self.blocks.insert(block_index, block);
-        self.update_complete();
+        self.update_complete()?;
         if insert_res.is_some() {
             return Err(AddError::BlockOverwrite);
         }
@@ -1,39 +1,245 @@
 use std::sync::Arc;
 
-use crossbeam_channel::{Receiver, Sender};
-use lava_torrent::torrent::v1::Torrent;
+use flume::{Receiver, Sender};
 use parking_lot::Mutex;
+use tokio::{sync::broadcast, task::JoinHandle};
 
-use crate::piece;
+use crate::{
+    events::{Event, Events},
+    merkle,
+    metainfo::MetaInfo,
+    peer_proto::message::Bitfield,
+    piece::{self, PieceRoot},
+    storage::Storage,
+};
+
+pub type CompletePiece = Arc<Mutex<Bitfield>>;
 
-pub type CompletePiece = Arc<Mutex<Vec<piece::Piece>>>;
 pub struct PieceDispatch {
     pub tx: Sender<piece::Piece>,
     pub rx: Receiver<piece::Piece>,
     pub complete_piece: CompletePiece,
+    // peers send hash-verified pieces here
+    pub verified_tx: Sender<piece::Piece>,
+    pub verified_rx: Receiver<piece::Piece>,
+    // index of every piece written to storage, peers forward it as Have
+    pub have_tx: broadcast::Sender<usize>,
+    // sha1 of every piece, empty for v2 only torrents
+    pub hashes: Arc<Vec<[u8; 20]>>,
+    // merkle root of every piece, empty for v1 torrents
+    pub roots: Arc<Vec<Option<PieceRoot>>>,
+    // pieces of selected files
+    pub wanted: Arc<Bitfield>,
+    pub storage: Arc<Storage>,
 }
 
 impl PieceDispatch {
-    pub fn new(torrent: &Torrent) -> PieceDispatch {
-        let (tx, rx) = crossbeam_channel::unbounded();
-        for (index, hash) in torrent.pieces.iter().enumerate() {
-            let mut len = torrent.piece_length as u32;
-            // last piece may be shorter than others
-            if index as i64 * torrent.piece_length + torrent.piece_length > torrent.length {
-                len = (torrent.length % torrent.piece_length) as u32;
+    // files are indices of files to download, None for all of them
+    pub fn new(
+        torrent: &MetaInfo,
+        storage: Arc<Storage>,
+        files: Option<&[usize]>,
+    ) -> PieceDispatch {
+        let (tx, rx) = flume::unbounded();
+        let hashes = torrent.info.pieces.clone();
+        let piece_count = storage.piece_count();
+
+        let mut wanted = Bitfield::new(piece_count);
+        for (i, file) in storage.files.iter().enumerate() {
+            if files.is_none_or(|f| f.contains(&i)) {
+                for index in storage.file_pieces(file) {
+                    wanted.set(index, true);
+                }
             }
-            tx.send(piece::Piece::new(
-                index,
-                hash.clone().try_into().expect("piece hash mismatch length"),
-                len,
-            ))
-            .expect("Piece queue send exception");
         }
-        let complete_piece = Arc::new(Mutex::new(Vec::new()));
+
+        let complete_piece = Arc::new(Mutex::new(Bitfield::new(piece_count)));
+        let (verified_tx, verified_rx) = flume::unbounded();
+        let (have_tx, _) = broadcast::channel(piece_count.max(1));
         PieceDispatch {
             tx,
             rx,
             complete_piece,
+            verified_tx,
+            verified_rx,
+            have_tx,
+            hashes: Arc::new(hashes),
+            roots: Arc::new(roots(torrent, piece_count)),
+            wanted: Arc::new(wanted),
+            storage,
+        }
+    }
+
+    // empty piece of the torrent, as queued for download
+    pub fn piece(&self, index: usize) -> piece::Piece {
+        new_piece(index, &self.hashes, &self.roots, &self.storage)
+    }
+
+    pub fn wanted_count(&self) -> usize {
+        (0..self.storage.piece_count())
+            .filter(|i| self.wanted.get(*i) == Some(true))
+            .count()
+    }
+
+    // checks data already on disk, queues missing pieces, then stores
+    // verified pieces and reports progress
+    pub fn run(&self, info_hash: [u8; 20], events: Events) -> JoinHandle<()> {
+        let tx = self.tx.clone();
+        let verified_rx = self.verified_rx.clone();
+        let complete_piece = self.complete_piece.clone();
+        let have_tx = self.have_tx.clone();
+        let hashes = self.hashes.clone();
+        let roots = self.roots.clone();
+        let wanted = self.wanted.clone();
+        let storage = self.storage.clone();
+        tokio::spawn(async move {
+            let st = storage.clone();
+            let h = hashes.clone();
+            let r = roots.clone();
+            let w = wanted.clone();
+            let existing = tokio::task::spawn_blocking(move || {
+                (0..st.piece_count())
+                    .map(|i| w.get(i) == Some(true) && st.verify(&new_piece(i, &h, &r, &st)))
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap_or_default();
+
+            let mut left = 0;
+            {
+                let mut complete_piece = complete_piece.lock();
+                for index in 0..storage.piece_count() {
+                    if existing.get(index) == Some(&true) {
+                        complete_piece.set(index, true);
+                    } else if wanted.get(index) == Some(true) {
+                        left += 1;
+                        #[allow(unused_must_use)]
+                        {
+                            tx.send(new_piece(index, &hashes, &roots, &storage));
+                        }
+                    }
+                }
+            }
+
+            let files = storage
+                .files
+                .iter()
+                .map(|f| (f.path.clone(), storage.file_pieces(f)))
+                .filter(|(_, p)| p.clone().all(|i| wanted.get(i) == Some(true)))
+                .collect::<Vec<_>>();
+            let mut remaining = files
+                .iter()
+                .map(|(_, p)| {
+                    p.clone()
+                        .filter(|i| existing.get(*i) != Some(&true))
+                        .count()
+                })
+                .collect::<Vec<_>>();
+            for ((path, _), _) in files.iter().zip(&remaining).filter(|(_, r)| **r == 0) {
+                events.emit(Event::FileCompleted {
+                    info_hash,
+                    path: path.clone(),
+                });
+            }
+            if left == 0 {
+                events.emit(Event::TorrentFinished { info_hash });
+            }
+
+            while let Ok(piece) = verified_rx.recv_async().await {
+                let index = piece.index;
+                let st = storage.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    let data = piece.blocks.into_values().flatten().collect::<Vec<_>>();
+                    st.write(piece.index, 0, &data)
+                })
+                .await;
+                if let Ok(Err(e)) = written {
+                    events.emit(Event::Error {
+                        info_hash: Some(info_hash),
+                        message: format!("cannot write piece {}: {}", index, e),
+                    });
+                    #[allow(unused_must_use)]
+                    {
+                        tx.send(new_piece(index, &hashes, &roots, &storage));
+                    }
+                    continue;
+                }
+
+                complete_piece.lock().set(index, true);
+                #[allow(unused_must_use)]
+                {
+                    have_tx.send(index);
+                }
+                events.emit(Event::PieceVerified { info_hash, index });
+                for ((path, pieces), remaining) in files.iter().zip(remaining.iter_mut()) {
+                    if pieces.contains(&index) {
+                        *remaining -= 1;
+                        if *remaining == 0 {
+                            events.emit(Event::FileCompleted {
+                                info_hash,
+                                path: path.clone(),
+                            });
+                        }
+                    }
+                }
+                left -= 1;
+                if left == 0 {
+                    events.emit(Event::TorrentFinished { info_hash });
+                }
+            }
+        })
+    }
+}
+
+// empty piece checked against every hash the torrent has of it
+fn new_piece(
+    index: usize,
+    hashes: &[[u8; 20]],
+    roots: &[Option<PieceRoot>],
+    storage: &Storage,
+) -> piece::Piece {
+    piece::Piece::with_hashes(
+        index,
+        hashes.get(index).copied(),
+        roots.get(index).copied().flatten(),
+        storage.piece_len(index) as u32,
+    )
+}
+
+// v2 files start at a piece and their pieces hash up to their root, the
+// only piece of a small file is hashed like the whole file; pieces of
+// files without piece layer stay None
+fn roots(torrent: &MetaInfo, piece_count: usize) -> Vec<Option<PieceRoot>> {
+    let piece_length = torrent.info.piece_length;
+    let file_roots = torrent.info.file_roots();
+    if file_roots.is_empty() {
+        return Vec::new();
+    }
+    let mut roots = vec![None; piece_count];
+    for (offset, length, root) in file_roots {
+        let first = (offset / piece_length) as usize;
+        if length <= piece_length {
+            if let Some(r) = roots.get_mut(first) {
+                *r = Some(PieceRoot {
+                    root,
+                    width: merkle::width(length),
+                    len: length as u32,
+                });
+            }
+            continue;
+        }
+        let Some(layer) = torrent.piece_layers.get(&root) else {
+            continue;
+        };
+        for (i, hash) in layer.iter().enumerate() {
+            if let Some(r) = roots.get_mut(first + i) {
+                *r = Some(PieceRoot {
+                    root: *hash,
+                    width: merkle::width(piece_length),
+                    len: piece_length.min(length - i as u64 * piece_length) as u32,
+                });
+            }
         }
     }
+    roots
 }
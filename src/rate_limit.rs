@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// token bucket shared by every peer of the session, rate is in bytes per
+// second and 0 means unlimited
+pub struct RateLimiter {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        // tokens may go below zero, caller then waits for the debt
+        let wait = {
+            let mut state = self.state.lock();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            let burst = self.rate as f64;
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * burst).min(burst);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / burst)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
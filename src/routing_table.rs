@@ -0,0 +1,333 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bencode::{self, Value},
+    dht_proto::{Node, NodeId},
+    peer_proto::message::{compact_v4, compact_v6, to_compact_v4, to_compact_v6},
+};
+
+// nodes per bucket
+pub const K: usize = 8;
+const BUCKETS: usize = 160;
+// nodes silent for this long are questionable, see BEP 5
+const QUESTIONABLE: Duration = Duration::from_secs(15 * 60);
+// unanswered queries before a node is dropped
+const MAX_FAILS: u32 = 3;
+
+// castagnoli crc, bitwise since ids are rarely checked in bulk
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// first 21 bits of a node id bound to ip, r is the last byte of the id,
+// see BEP 42
+fn id_prefix(ip: IpAddr, r: u8) -> u32 {
+    let mut ip: Vec<u8> = match ip {
+        IpAddr::V4(ip) => {
+            let mask = [0x03, 0x0f, 0x3f, 0xff];
+            ip.octets().iter().zip(mask).map(|(b, m)| b & m).collect()
+        }
+        IpAddr::V6(ip) => {
+            let mask = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+            ip.octets().iter().zip(mask).map(|(b, m)| b & m).collect()
+        }
+    };
+    ip[0] |= r << 5;
+    crc32c(&ip) & 0xffff_f800
+}
+
+// local networks are exempt from BEP 42
+fn exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let s = ip.segments()[0];
+            ip.is_loopback() || s & 0xfe00 == 0xfc00 || s & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+// random id a node at ip may use
+pub fn secure_id(ip: IpAddr) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let prefix = id_prefix(ip, id[19]).to_be_bytes();
+    id[0] = prefix[0];
+    id[1] = prefix[1];
+    id[2] = prefix[2] | (id[2] & 0x07);
+    id
+}
+
+pub fn is_secure(id: &NodeId, ip: IpAddr) -> bool {
+    if exempt(ip) {
+        return true;
+    }
+    let prefix = id_prefix(ip, id[19]);
+    u32::from_be_bytes([id[0], id[1], id[2], 0]) & 0xffff_f800 == prefix
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0; 20];
+    for (d, (a, b)) in d.iter_mut().zip(a.iter().zip(b)) {
+        *d = a ^ b;
+    }
+    d
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub node: Node,
+    pub last_seen: Instant,
+    pub fails: u32,
+}
+
+impl Entry {
+    pub fn questionable(&self) -> bool {
+        self.fails > 0 || self.last_seen.elapsed() >= QUESTIONABLE
+    }
+}
+
+struct Bucket {
+    entries: Vec<Entry>,
+    last_changed: Instant,
+}
+
+// bucket i holds nodes whose distance to us starts with i zero bits
+pub struct RoutingTable {
+    pub id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> RoutingTable {
+        let buckets = (0..BUCKETS)
+            .map(|_| Bucket {
+                entries: Vec::new(),
+                last_changed: Instant::now(),
+            })
+            .collect();
+        RoutingTable { id, buckets }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.id, id);
+        let i = d.iter().position(|b| *b != 0)?;
+        Some(i * 8 + d[i].leading_zeros() as usize)
+    }
+
+    // adds a node we heard from, known ones are refreshed; a full bucket
+    // makes room by dropping a failing node, or for a BEP 42 node a node
+    // whose id does not match its ip
+    pub fn insert(&mut self, node: Node) -> bool {
+        self.insert_seen(node, Instant::now())
+    }
+
+    fn insert_seen(&mut self, node: Node, last_seen: Instant) -> bool {
+        let Some(i) = self.bucket(&node.id) else {
+            return false;
+        };
+        let now = Instant::now();
+        let bucket = &mut self.buckets[i];
+        if let Some(e) = bucket.entries.iter_mut().find(|e| e.node.id == node.id) {
+            e.node.addr = node.addr;
+            e.last_seen = last_seen;
+            e.fails = 0;
+            bucket.last_changed = now;
+            return true;
+        }
+        if bucket.entries.len() >= K {
+            let worst = bucket
+                .entries
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.fails > 0)
+                .max_by_key(|(_, e)| e.fails)
+                .map(|(i, _)| i);
+            let worst = worst.or_else(|| {
+                if !is_secure(&node.id, node.addr.ip()) {
+                    return None;
+                }
+                bucket
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, e)| !is_secure(&e.node.id, e.node.addr.ip()))
+                    .max_by_key(|(_, e)| e.last_seen.elapsed())
+                    .map(|(i, _)| i)
+            });
+            match worst {
+                Some(worst) => bucket.entries.remove(worst),
+                None => return false,
+            };
+        }
+        bucket.entries.push(Entry {
+            node,
+            last_seen,
+            fails: 0,
+        });
+        bucket.last_changed = now;
+        true
+    }
+
+    // node at addr did not answer
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(i) = bucket.entries.iter().position(|e| e.node.addr == addr) {
+                bucket.entries[i].fails += 1;
+                if bucket.entries[i].fails >= MAX_FAILS {
+                    bucket.entries.remove(i);
+                }
+                return;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            bucket.entries.retain(|e| e.node.addr != addr);
+        }
+    }
+
+    // a new id moves every node to the bucket of its new distance
+    pub fn set_id(&mut self, id: NodeId) {
+        let entries = self.entries().cloned().collect::<Vec<_>>();
+        *self = RoutingTable::new(id);
+        for e in entries {
+            self.insert_seen(e.node, e.last_seen);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut entries = self.entries().collect::<Vec<_>>();
+        entries.sort_by_key(|e| (e.fails, distance(&e.node.id, target)));
+        let mut nodes = entries
+            .into_iter()
+            .take(n)
+            .map(|e| e.node.clone())
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.buckets.iter().flat_map(|b| &b.entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // pinged now and then, so dead nodes make room for new ones
+    pub fn questionable(&self) -> Vec<Node> {
+        self.entries()
+            .filter(|e| e.questionable())
+            .map(|e| e.node.clone())
+            .collect()
+    }
+
+    // random ids in buckets which did not change for a while, looking
+    // them up refreshes the buckets
+    pub fn refresh_targets(&self) -> Vec<NodeId> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.entries.is_empty() && b.last_changed.elapsed() >= QUESTIONABLE)
+            .map(|(i, _)| random_id_in_bucket(&self.id, i))
+            .collect()
+    }
+}
+
+// our id and the nodes of the tables with the unix time they were last
+// seen, tables share the id
+pub fn to_state(tables: &[&RoutingTable]) -> Vec<u8> {
+    let now = SystemTime::now();
+    let nodes = tables
+        .iter()
+        .flat_map(|t| t.entries())
+        .map(|e| {
+            let seen = now
+                .checked_sub(e.last_seen.elapsed())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+            let addr = match e.node.addr {
+                SocketAddr::V4(_) => to_compact_v4(&[e.node.addr]),
+                SocketAddr::V6(_) => to_compact_v6(&[e.node.addr]),
+            };
+            bencode::dict([
+                ("addr", Value::Bytes(addr)),
+                ("id", Value::Bytes(e.node.id.to_vec())),
+                ("seen", Value::Int(seen as i64)),
+            ])
+        })
+        .collect();
+    let id = tables.first().map_or([0; 20], |t| t.id);
+    bencode::dict([
+        ("id", Value::Bytes(id.to_vec())),
+        ("nodes", Value::List(nodes)),
+    ])
+    .encode()
+}
+
+// ipv4 and ipv6 tables, malformed nodes are skipped; nodes keep their
+// age so stale ones get pinged before they are trusted
+pub fn from_state(buf: &[u8]) -> Option<(RoutingTable, RoutingTable)> {
+    let v = Value::decode(buf).ok()?;
+    let id = v.get("id")?.as_bytes()?.try_into().ok()?;
+    let (mut v4, mut v6) = (RoutingTable::new(id), RoutingTable::new(id));
+    let (now, instant) = (SystemTime::now(), Instant::now());
+    for n in v
+        .get("nodes")
+        .and_then(Value::as_list)
+        .into_iter()
+        .flatten()
+    {
+        let id = n.get("id").and_then(Value::as_bytes);
+        let addr = n.get("addr").and_then(Value::as_bytes);
+        let (Some(Ok(id)), Some(addr)) = (id.map(NodeId::try_from), addr) else {
+            continue;
+        };
+        let (table, addr) = match addr.len() {
+            6 => (&mut v4, compact_v4(addr)),
+            18 => (&mut v6, compact_v6(addr)),
+            _ => continue,
+        };
+        let seen = n.get("seen").and_then(Value::as_int).unwrap_or(0);
+        let seen = UNIX_EPOCH + Duration::from_secs(seen.max(0) as u64);
+        let age = now.duration_since(seen).unwrap_or_default();
+        let last_seen = instant
+            .checked_sub(age)
+            .or_else(|| instant.checked_sub(QUESTIONABLE))
+            .unwrap_or(instant);
+        table.insert_seen(Node { id, addr: addr[0] }, last_seen);
+    }
+    Some((v4, v6))
+}
+
+// keeps the first i bits of id and flips the next one
+fn random_id_in_bucket(id: &NodeId, i: usize) -> NodeId {
+    let mut target: NodeId = rand::random();
+    for bit in 0..=i {
+        let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+        let own = id[byte] & mask;
+        let flipped = if bit < i { own } else { own ^ mask };
+        target[byte] = (target[byte] & !mask) | flipped;
+    }
+    target
+}
@@ -0,0 +1,691 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use rand::distributions::{Alphanumeric, DistString};
+use socket2::{Domain, Protocol, Type};
+use thiserror::Error;
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpListener,
+    sync::{broadcast, watch},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    dht_dispatch::DhtDispatch,
+    dht_proto::DhtProto,
+    events::{Event, Events},
+    lsd_dispatch::{LsdDispatch, LsdSocket, LSD_GROUP, LSD_GROUP6},
+    magnet::Magnet,
+    metadata_dispatch::MetadataDispatch,
+    metainfo::MetaInfo,
+    mse::{self, Encryption, MseStream},
+    peer_dispatch::{PeerDispatch, PeerSettings, RunState},
+    peer_proto::{self, PeerProto, Stream, Transport, Transports, PROTOCOL},
+    piece_dispatch::{CompletePiece, PieceDispatch},
+    rate_limit::RateLimiter,
+    storage::Storage,
+    tracker_dispatch::TrackerDispatch,
+    utp::UtpSocket,
+    web_seed_dispatch::{self, Kind, WebSeedDispatch},
+    DHT_PORT, LISTEN_PORT,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot bind listener")]
+    Listen(#[source] std::io::Error),
+    #[error("Torrent already added")]
+    Duplicate,
+    #[error("Torrent not found")]
+    NotFound,
+    #[error("File index {0} out of range")]
+    FileIndex(usize),
+    #[error("Cannot get torrent metadata")]
+    Metadata,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // 0 lets the os pick a free port
+    pub listen_port: u16,
+    // also listen and run the dht on ipv6 when the host has it
+    pub ipv6: bool,
+    pub dht: bool,
+    // udp port of the dht node when utp is disabled, otherwise it shares
+    // the utp socket; 0 lets the os pick
+    pub dht_port: u16,
+    // announce with implied_port, for nats which map the shared udp port
+    pub dht_implied_port: bool,
+    // dht routing table and node id are kept here between runs
+    pub dht_state: Option<PathBuf>,
+    pub trackers: bool,
+    // announce torrents on the local network, see BEP 14
+    pub lsd: bool,
+    // http servers of the url-list and httpseeds, see BEP 19 and BEP 17
+    pub web_seeds: bool,
+    pub pex: bool,
+    pub encryption: Encryption,
+    // tried in order for outgoing peers, utp also accepts incoming ones
+    pub transports: Vec<Transport>,
+    // torrent data is stored under it
+    pub output_dir: PathBuf,
+    // connections per torrent
+    pub max_peers: usize,
+    // bytes per second for the whole session, 0 is unlimited
+    pub download_limit: u64,
+    pub upload_limit: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            listen_port: LISTEN_PORT,
+            ipv6: true,
+            dht: true,
+            dht_port: DHT_PORT,
+            dht_implied_port: false,
+            dht_state: None,
+            trackers: true,
+            lsd: true,
+            web_seeds: true,
+            pex: true,
+            encryption: Encryption::Prefer,
+            transports: vec![Transport::Utp, Transport::Tcp],
+            output_dir: PathBuf::from("."),
+            max_peers: 50,
+            download_limit: 0,
+            upload_limit: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TorrentOptions {
+    // indices into the torrent file list, None downloads everything
+    pub files: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    pub state: RunState,
+    pub peers: usize,
+    pub complete_pieces: usize,
+    pub wanted_pieces: usize,
+    pub total_pieces: usize,
+    // payload bytes since the torrent was added
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+struct TorrentEntry {
+    info_hash: [u8; 20],
+    name: String,
+    total_pieces: usize,
+    wanted_pieces: usize,
+    complete_piece: CompletePiece,
+    storage: Arc<Storage>,
+    peer_dispatch: PeerDispatch,
+    control: watch::Sender<RunState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TorrentEntry {
+    fn stop(&self) {
+        self.control.send_replace(RunState::Stopped);
+        for t in &self.tasks {
+            t.abort();
+        }
+    }
+}
+
+impl Drop for TorrentEntry {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Clone)]
+pub struct TorrentHandle {
+    entry: Arc<TorrentEntry>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.entry.info_hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.entry.name
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.entry.storage
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let peer_dispatch = &self.entry.peer_dispatch;
+        TorrentStatus {
+            state: *self.entry.control.borrow(),
+            peers: peer_dispatch.active_peers.lock().len(),
+            complete_pieces: self.entry.complete_piece.lock().count(),
+            wanted_pieces: self.entry.wanted_pieces,
+            total_pieces: self.entry.total_pieces,
+            downloaded: peer_dispatch.downloaded.load(Ordering::Relaxed),
+            uploaded: peer_dispatch.uploaded.load(Ordering::Relaxed),
+        }
+    }
+
+    // fraction of verified pieces among the selected files, 0.0..=1.0
+    pub fn progress(&self) -> f64 {
+        if self.entry.wanted_pieces == 0 {
+            return 1.0;
+        }
+        let complete = self.entry.complete_piece.lock().count();
+        (complete as f64 / self.entry.wanted_pieces as f64).min(1.0)
+    }
+}
+
+type Torrents = Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>;
+
+pub struct Session {
+    settings: Settings,
+    peer_id: [u8; 20],
+    listen_port: u16,
+    transports: Transports,
+    torrents: Torrents,
+    dht_dispatch: Option<DhtDispatch>,
+    lsd_dispatch: Option<LsdDispatch>,
+    events: Events,
+    download_limit: Arc<RateLimiter>,
+    upload_limit: Arc<RateLimiter>,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    pub async fn new(settings: Settings) -> Result<Session, Error> {
+        let peer_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 20);
+        let peer_id: [u8; 20] = peer_id.into_bytes().try_into().unwrap();
+
+        let listener = TcpListener::bind(("0.0.0.0", settings.listen_port))
+            .await
+            .map_err(Error::Listen)?;
+        let listen_port = listener.local_addr().map_err(Error::Listen)?.port();
+
+        let torrents: Torrents = Arc::new(Mutex::new(HashMap::new()));
+        let mut listeners = vec![tokio::spawn(Self::listener(
+            listener,
+            peer_id,
+            torrents.clone(),
+            settings.encryption,
+        ))];
+        // best effort, hosts without ipv6 stay on ipv4
+        if let Some(listener) = settings
+            .ipv6
+            .then(|| Self::bind_v6(listen_port).ok())
+            .flatten()
+        {
+            listeners.push(tokio::spawn(Self::listener(
+                listener,
+                peer_id,
+                torrents.clone(),
+                settings.encryption,
+            )));
+        }
+
+        // utp shares the port number with tcp
+        let utp = if settings.transports.contains(&Transport::Utp) {
+            let socket = UtpSocket::bind(("0.0.0.0", listen_port))
+                .await
+                .map_err(Error::Listen)?;
+            let socket = Arc::new(socket);
+            listeners.push(tokio::spawn(Self::utp_listener(
+                socket.clone(),
+                peer_id,
+                torrents.clone(),
+                settings.encryption,
+            )));
+            Some(socket)
+        } else {
+            None
+        };
+        let transports = Transports {
+            order: settings.transports.clone(),
+            utp,
+        };
+
+        let dht_dispatch = if settings.dht {
+            let proto = match &transports.utp {
+                Some(utp) => DhtProto::shared(utp),
+                None => DhtProto::bind(("0.0.0.0", settings.dht_port)).await,
+            }
+            .map_err(Error::Listen)?;
+            // same port number as the ipv4 node, which keeps implied_port right
+            let dht_port = proto.local_addr().map_err(Error::Listen)?.port();
+            let proto6 = settings
+                .ipv6
+                .then(|| DhtProto::bind_v6(dht_port).ok())
+                .flatten();
+            let implied_port = settings.dht_implied_port && proto.is_shared();
+            let dht_dispatch = DhtDispatch::new(
+                proto,
+                proto6,
+                listen_port,
+                implied_port,
+                settings.dht_state.clone(),
+            );
+            dht_dispatch.run();
+            Some(dht_dispatch)
+        } else {
+            None
+        };
+
+        // best effort too, hosts without multicast just skip the lan
+        let lsd_dispatch = if settings.lsd {
+            let mut sockets = Vec::new();
+            sockets.extend(LsdSocket::bind_v4(LSD_GROUP, Ipv4Addr::UNSPECIFIED).ok());
+            if settings.ipv6 {
+                sockets.extend(LsdSocket::bind_v6(LSD_GROUP6, 0).ok());
+            }
+            let lsd_dispatch = LsdDispatch::new(sockets, listen_port);
+            lsd_dispatch.run();
+            Some(lsd_dispatch)
+        } else {
+            None
+        };
+
+        let download_limit = Arc::new(RateLimiter::new(settings.download_limit));
+        let upload_limit = Arc::new(RateLimiter::new(settings.upload_limit));
+        Ok(Session {
+            settings,
+            peer_id,
+            listen_port,
+            transports,
+            torrents,
+            dht_dispatch,
+            lsd_dispatch,
+            events: Events::new(),
+            download_limit,
+            upload_limit,
+            listeners,
+        })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    // dht node of the session, for BEP 44 items
+    pub fn dht(&self) -> Option<&DhtDispatch> {
+        self.dht_dispatch.as_ref()
+    }
+
+    // events of every torrent in the session
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn add_torrent(&self, torrent: MetaInfo) -> Result<TorrentHandle, Error> {
+        self.add_torrent_with(torrent, TorrentOptions::default())
+    }
+
+    pub fn add_torrent_with(
+        &self,
+        torrent: MetaInfo,
+        options: TorrentOptions,
+    ) -> Result<TorrentHandle, Error> {
+        let info_hash = torrent.info_hash;
+        // private torrents only get peers from their trackers, see BEP 27
+        let public = !torrent.info.private;
+        let mut torrents = self.torrents.lock();
+        if torrents.contains_key(&info_hash) {
+            return Err(Error::Duplicate);
+        }
+
+        let storage = Arc::new(Storage::new(self.settings.output_dir.clone(), &torrent));
+        if let Some(&i) = options
+            .files
+            .iter()
+            .flatten()
+            .find(|i| **i >= storage.files.len())
+        {
+            return Err(Error::FileIndex(i));
+        }
+
+        let (control, control_rx) = watch::channel(RunState::Running);
+        let piece_dispatch =
+            PieceDispatch::new(&torrent, storage.clone(), options.files.as_deref());
+        let (msg_port_send, _) = flume::unbounded();
+        let peer_dispatch = PeerDispatch::run(
+            info_hash,
+            self.peer_id,
+            &piece_dispatch,
+            self.dht_dispatch
+                .as_ref()
+                .map(|d| d.msg_port_send.clone())
+                .unwrap_or(msg_port_send),
+            control_rx,
+            self.events.clone(),
+            PeerSettings {
+                max_peers: self.settings.max_peers,
+                pex: self.settings.pex && public,
+                encryption: self.settings.encryption,
+                transports: self.transports.clone(),
+                listen_port: self.listen_port,
+                metadata: Some(Arc::new(torrent.info_bytes.clone())),
+                piece_layers: Arc::new(torrent.piece_layers.clone()),
+                download_limit: self.download_limit.clone(),
+                upload_limit: self.upload_limit.clone(),
+            },
+        );
+
+        let mut tasks = vec![piece_dispatch.run(info_hash, self.events.clone())];
+        if self.settings.trackers {
+            if let Some(announce) = &torrent.announce {
+                let tracker_dispatch = TrackerDispatch::new(
+                    announce.clone(),
+                    info_hash,
+                    self.peer_id,
+                    self.listen_port,
+                );
+                tasks.push(
+                    tracker_dispatch.run(peer_dispatch.send_peer.clone(), self.events.clone()),
+                );
+            }
+        }
+        if self.settings.web_seeds {
+            let multi_file = torrent.info.files.is_some();
+            let url_list = web_seed_dispatch::url_list(&torrent)
+                .into_iter()
+                .map(|url| (url, Kind::UrlList { multi_file }));
+            let http_seeds = web_seed_dispatch::http_seeds(&torrent)
+                .into_iter()
+                .map(|url| (url, Kind::HttpSeed));
+            for (url, kind) in url_list.chain(http_seeds) {
+                let web_seed = WebSeedDispatch::new(url, info_hash, kind);
+                tasks.push(web_seed.run(
+                    &piece_dispatch,
+                    control.subscribe(),
+                    peer_dispatch.downloaded.clone(),
+                    self.download_limit.clone(),
+                    self.events.clone(),
+                ));
+            }
+        }
+        if let Some(dht_dispatch) = self.dht_dispatch.as_ref().filter(|_| public) {
+            dht_dispatch.add_torrent(info_hash, peer_dispatch.send_peer.clone(), true);
+        }
+        if let Some(lsd_dispatch) = self.lsd_dispatch.as_ref().filter(|_| public) {
+            lsd_dispatch.add_torrent(info_hash, peer_dispatch.send_peer.clone());
+        }
+
+        let handle = TorrentHandle {
+            entry: Arc::new(TorrentEntry {
+                info_hash,
+                name: torrent.info.name.clone(),
+                total_pieces: storage.piece_count(),
+                wanted_pieces: piece_dispatch.wanted_count(),
+                complete_piece: piece_dispatch.complete_piece.clone(),
+                storage,
+                peer_dispatch,
+                control,
+                tasks,
+            }),
+        };
+        torrents.insert(info_hash, handle.clone());
+        Ok(handle)
+    }
+
+    // fetches info dict from peers found by magnet trackers, x.pe peers
+    // and dht, returns .torrent file bytes
+    pub async fn fetch_metadata(&self, magnet: &Magnet) -> Result<Vec<u8>, Error> {
+        let info_hash = magnet.info_hash;
+        let metadata_dispatch = MetadataDispatch::new(
+            info_hash,
+            self.peer_id,
+            self.settings.encryption,
+            self.transports.clone(),
+        );
+
+        let mut tasks = Vec::new();
+        if self.settings.trackers {
+            for tr in &magnet.trackers {
+                let tracker_dispatch =
+                    TrackerDispatch::new(tr.clone(), info_hash, self.peer_id, self.listen_port);
+                tasks.push(
+                    tracker_dispatch.run(metadata_dispatch.send_peer.clone(), self.events.clone()),
+                );
+            }
+        }
+        for pe in magnet.peers.clone() {
+            let send_peer = metadata_dispatch.send_peer.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Ok(addrs) = tokio::net::lookup_host(pe).await {
+                    for addr in addrs {
+                        #[allow(unused_must_use)]
+                        {
+                            send_peer.send(addr);
+                        }
+                    }
+                }
+            }));
+        }
+        if let Some(dht_dispatch) = &self.dht_dispatch {
+            dht_dispatch.add_torrent(info_hash, metadata_dispatch.send_peer.clone(), false);
+        }
+        if let Some(lsd_dispatch) = &self.lsd_dispatch {
+            lsd_dispatch.add_torrent(info_hash, metadata_dispatch.send_peer.clone());
+        }
+
+        let info = metadata_dispatch.run().await;
+
+        if let Some(dht_dispatch) = &self.dht_dispatch {
+            dht_dispatch.remove_torrent(&info_hash);
+        }
+        if let Some(lsd_dispatch) = &self.lsd_dispatch {
+            lsd_dispatch.remove_torrent(&info_hash);
+        }
+        for t in tasks {
+            t.abort();
+        }
+        let (info, piece_layers) = info.ok_or(Error::Metadata)?;
+        self.events.emit(Event::MetadataReceived { info_hash });
+        let torrent = MetaInfo {
+            announce: magnet.trackers.first().cloned(),
+            // every magnet tracker is a tier of its own
+            announce_list: match magnet.trackers.len() {
+                0 | 1 => Vec::new(),
+                _ => magnet.trackers.iter().map(|t| vec![t.clone()]).collect(),
+            },
+            ..MetaInfo::from_info(info, piece_layers).map_err(|_| Error::Metadata)?
+        };
+        Ok(torrent.to_bytes())
+    }
+
+    pub async fn add_magnet(
+        &self,
+        magnet: &Magnet,
+        options: TorrentOptions,
+    ) -> Result<TorrentHandle, Error> {
+        let bytes = self.fetch_metadata(magnet).await?;
+        let torrent = MetaInfo::from_bytes(&bytes).map_err(|_| Error::Metadata)?;
+        self.add_torrent_with(torrent, options)
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        let handle = self
+            .torrents
+            .lock()
+            .remove(info_hash)
+            .ok_or(Error::NotFound)?;
+        if let Some(dht_dispatch) = &self.dht_dispatch {
+            dht_dispatch.remove_torrent(info_hash);
+        }
+        if let Some(lsd_dispatch) = &self.lsd_dispatch {
+            lsd_dispatch.remove_torrent(info_hash);
+        }
+        // handles kept by the caller stay readable, but nothing runs anymore
+        handle.entry.stop();
+        Ok(())
+    }
+
+    pub fn pause(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        self.set_state(info_hash, RunState::Paused)
+    }
+
+    pub fn resume(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        self.set_state(info_hash, RunState::Running)
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents.lock().get(info_hash).cloned()
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.torrents.lock().values().cloned().collect()
+    }
+
+    fn set_state(&self, info_hash: &[u8; 20], state: RunState) -> Result<(), Error> {
+        let torrents = self.torrents.lock();
+        let handle = torrents.get(info_hash).ok_or(Error::NotFound)?;
+        handle.entry.control.send_replace(state);
+        Ok(())
+    }
+
+    // ipv6 only, so it may take the port number of the ipv4 listener
+    fn bind_v6(port: u16) -> io::Result<TcpListener> {
+        let socket = socket2::Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(true)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    async fn listener(
+        listener: TcpListener,
+        peer_id: [u8; 20],
+        torrents: Torrents,
+        encryption: Encryption,
+    ) {
+        while let Ok((stream, addr)) = listener.accept().await {
+            tokio::spawn(Self::incoming(
+                stream,
+                addr,
+                Transport::Tcp,
+                peer_id,
+                torrents.clone(),
+                encryption,
+            ));
+        }
+    }
+
+    async fn utp_listener(
+        socket: Arc<UtpSocket>,
+        peer_id: [u8; 20],
+        torrents: Torrents,
+        encryption: Encryption,
+    ) {
+        while let Ok((stream, addr)) = socket.accept().await {
+            tokio::spawn(Self::incoming(
+                stream,
+                addr,
+                Transport::Utp,
+                peer_id,
+                torrents.clone(),
+                encryption,
+            ));
+        }
+    }
+
+    // routes incoming connection to the torrent the peer asks for
+    async fn incoming<S: Stream + 'static>(
+        stream: S,
+        addr: SocketAddr,
+        transport: Transport,
+        peer_id: [u8; 20],
+        torrents: Torrents,
+        encryption: Encryption,
+    ) -> Result<(), peer_proto::Error> {
+        let (stream, peer_handshake) = timeout(
+            HANDSHAKE_TIMEOUT,
+            Self::incoming_handshake(stream, &torrents, encryption),
+        )
+        .await
+        .map_err(|_| peer_proto::Error::Protocol)??;
+        let send_conn = match torrents.lock().get(&peer_handshake.info_hash) {
+            Some(t) if *t.entry.control.borrow() == RunState::Running => {
+                t.entry.peer_dispatch.send_conn.clone()
+            }
+            _ => return Err(peer_proto::Error::InfoHash),
+        };
+        let encrypted = stream.encrypted();
+        let mut p = PeerProto::accept(stream, addr, peer_handshake, peer_id).await?;
+        p.encrypted = encrypted;
+        p.transport = transport;
+        #[allow(unused_must_use)]
+        {
+            send_conn.send(p);
+        }
+        Ok(())
+    }
+
+    // plaintext handshake starts with the protocol string, anything else
+    // is taken for an encryption handshake
+    async fn incoming_handshake<S: Stream>(
+        mut stream: S,
+        torrents: &Torrents,
+        encryption: Encryption,
+    ) -> Result<(MseStream<S>, peer_proto::Handshake), peer_proto::Error> {
+        let mut head = [0; 20];
+        stream.read_exact(&mut head).await?;
+        let plain = head[0] as usize == PROTOCOL.len() && &head[1..] == PROTOCOL;
+        let (mut stream, mse_info_hash) = match (plain, encryption) {
+            (true, Encryption::Require) | (false, Encryption::Disabled) => {
+                return Err(peer_proto::Error::Protocol)
+            }
+            (true, _) => (MseStream::plain(stream, head.to_vec()), None),
+            (false, _) => {
+                let info_hashes = torrents.lock().keys().copied().collect::<Vec<_>>();
+                let (stream, info_hash) =
+                    mse::accept(stream, &head, &info_hashes, encryption).await?;
+                (stream, Some(info_hash))
+            }
+        };
+        let peer_handshake = PeerProto::read_handshake(&mut stream).await?;
+        if mse_info_hash.is_some_and(|ih| ih != peer_handshake.info_hash) {
+            return Err(peer_proto::Error::InfoHash);
+        }
+        Ok((stream, peer_handshake))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        for (_, handle) in self.torrents.lock().drain() {
+            handle.entry.stop();
+        }
+    }
+}
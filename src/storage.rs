@@ -0,0 +1,152 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{metainfo::MetaInfo, piece::Piece};
+
+// file position inside the torrent byte stream, path is relative to storage root;
+// pad files have none and their bytes are zero
+#[derive(Debug, Clone)]
+pub struct FileSpan {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+}
+
+// maps pieces onto files under the output directory, all io is blocking
+pub struct Storage {
+    pub root: PathBuf,
+    pub files: Vec<FileSpan>,
+    pub piece_length: u64,
+    pub length: u64,
+}
+
+impl Storage {
+    pub fn new(root: PathBuf, torrent: &MetaInfo) -> Storage {
+        let info = &torrent.info;
+        let name = sanitize(Path::new(&info.name));
+        let files = match &info.files {
+            Some(files) => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .filter_map(|f| {
+                        let span = FileSpan {
+                            path: name.join(sanitize(&f.path)),
+                            offset,
+                            len: f.length,
+                        };
+                        offset += f.length;
+                        (!f.pad).then_some(span)
+                    })
+                    .collect()
+            }
+            None => vec![FileSpan {
+                path: name,
+                offset: 0,
+                len: info.length,
+            }],
+        };
+        Storage {
+            root,
+            files,
+            piece_length: info.piece_length,
+            length: info.length,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.piece_length == 0 {
+            return 0;
+        }
+        self.length.div_ceil(self.piece_length) as usize
+    }
+
+    pub fn piece_len(&self, index: usize) -> u64 {
+        let offset = index as u64 * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(offset))
+    }
+
+    // pieces overlapping the file
+    pub fn file_pieces(&self, file: &FileSpan) -> Range<usize> {
+        if file.len == 0 || self.piece_length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.offset + file.len - 1) / self.piece_length;
+        first as usize..last as usize + 1
+    }
+
+    // split torrent byte range into (file, offset in file, len) parts, gaps
+    // of pad files are left out
+    pub fn parts(&self, offset: u64, len: u64) -> Vec<(&FileSpan, u64, u64)> {
+        let end = offset + len;
+        self.files
+            .iter()
+            .filter(|f| f.len > 0 && f.offset < end && f.offset + f.len > offset)
+            .map(|f| {
+                let start = offset.max(f.offset);
+                let stop = end.min(f.offset + f.len);
+                (f, start - f.offset, stop - start)
+            })
+            .collect()
+    }
+
+    pub fn write(&self, index: usize, begin: u64, data: &[u8]) -> io::Result<()> {
+        let offset = index as u64 * self.piece_length + begin;
+        for (file, file_offset, len) in self.parts(offset, data.len() as u64) {
+            let start = (file.offset + file_offset - offset) as usize;
+            let path = self.root.join(&file.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            f.seek(SeekFrom::Start(file_offset))?;
+            f.write_all(&data[start..start + len as usize])?;
+        }
+        Ok(())
+    }
+
+    // begin and len are within the piece, a read never reaches into the
+    // pieces after it
+    pub fn read(&self, index: usize, begin: u64, len: u64) -> io::Result<Vec<u8>> {
+        match begin.checked_add(len) {
+            Some(end) if end <= self.piece_len(index) => (),
+            _ => return Err(io::ErrorKind::InvalidInput.into()),
+        }
+        let offset = index as u64 * self.piece_length + begin;
+        let mut buf = vec![0; len as usize];
+        for (file, file_offset, len) in self.parts(offset, len) {
+            let start = (file.offset + file_offset - offset) as usize;
+            let mut f = File::open(self.root.join(&file.path))?;
+            f.seek(SeekFrom::Start(file_offset))?;
+            f.read_exact(&mut buf[start..start + len as usize])?;
+        }
+        Ok(buf)
+    }
+
+    // missing or short files simply fail verification
+    pub fn verify(&self, piece: &Piece) -> bool {
+        match self.read(piece.index, 0, self.piece_len(piece.index)) {
+            Ok(data) => piece.verify(&data),
+            Err(_) => false,
+        }
+    }
+}
+
+// torrent paths come from the network, never let them leave the root
+fn sanitize(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
@@ -1,42 +1,1314 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
 use crate::{
-    piece::{BlockParam, Piece},
+    bencode::{self, Value},
+    create::{self, CreateOptions},
+    dht_dispatch::DhtDispatch,
+    dht_item::{self, Item},
+    dht_proto::{self, Body, DhtProto, Node, Query},
+    lsd_dispatch::{self, LsdDispatch, LsdSocket},
+    magnet::{self, Magnet},
+    merkle,
+    metainfo::{self, MetaInfo},
+    mse::{self, Encryption},
+    peer_dispatch::{PexState, PEX_INTERVAL},
+    peer_proto::{message, Message},
+    piece::{AddError, Piece, PieceRoot},
+    piece_dispatch::PieceDispatch,
+    routing_table::{self, RoutingTable},
+    storage::{FileSpan, Storage},
+    tracker_dispatch::TrackerResponse,
+    utp::{self, UtpSocket},
+    web_seed_dispatch::{Kind, WebSeedDispatch},
     BLOCK_SIZE,
 };
 
 #[test]
 fn unfinished_blocks() {
-    let p = Piece::new([0; 20], 0);
+    let p = Piece::new(0, [0; 20], 0);
     let u = p.unfinished_blocks();
     assert_eq!(u.len(), 0);
 
-    let p = Piece::new([0; 20], BLOCK_SIZE - 123);
+    let p = Piece::new(0, [0; 20], BLOCK_SIZE - 123);
     let u = p.unfinished_blocks();
     assert_eq!(u.len(), 1);
-    let bp = u.get(0).unwrap();
+    let bp = u.first().unwrap();
     assert_eq!(bp.begin, 0);
     assert_eq!(bp.len, BLOCK_SIZE - 123);
 
-    let p = Piece::new([0; 20], BLOCK_SIZE);
+    let p = Piece::new(0, [0; 20], BLOCK_SIZE);
     let u = p.unfinished_blocks();
     assert_eq!(u.len(), 1);
-    let bp = u.get(0).unwrap();
+    let bp = u.first().unwrap();
     assert_eq!(bp.begin, 0);
     assert_eq!(bp.len, BLOCK_SIZE);
 
-    let p = Piece::new([0; 20], BLOCK_SIZE + 123);
+    let p = Piece::new(0, [0; 20], BLOCK_SIZE + 123);
     let u = p.unfinished_blocks();
     assert_eq!(u.len(), 2);
-    let bp = u.get(0).unwrap();
+    let bp = u.first().unwrap();
     assert_eq!(bp.begin, 0);
     assert_eq!(bp.len, BLOCK_SIZE);
     let bp = u.get(1).unwrap();
     assert_eq!(bp.begin, BLOCK_SIZE);
     assert_eq!(bp.len, 123);
 
-    let p = Piece::new([0; 20], BLOCK_SIZE * 5);
+    let p = Piece::new(0, [0; 20], BLOCK_SIZE * 5);
     let u = p.unfinished_blocks();
     assert_eq!(u.len(), 5);
     let bp = u.get(4).unwrap();
     assert_eq!(bp.begin, BLOCK_SIZE * 4);
     assert_eq!(bp.len, BLOCK_SIZE);
 }
+
+#[test]
+fn bencode_roundtrip() {
+    let raw = b"d3:bar4:spam3:fooi42e4:listli-1e0:ee";
+    let v = Value::decode(raw).unwrap();
+    assert_eq!(v.get("foo").and_then(Value::as_int), Some(42));
+    assert_eq!(v.get("bar").and_then(Value::as_str), Some("spam"));
+    assert_eq!(v.get("list").and_then(Value::as_list).unwrap().len(), 2);
+    assert_eq!(v.encode(), raw);
+
+    assert_eq!(Value::decode(b"i03e"), Err(bencode::Error::Int(1)));
+    assert_eq!(Value::decode(b"i-0e"), Err(bencode::Error::Int(1)));
+    assert_eq!(Value::decode(b"5:abc"), Err(bencode::Error::Eof));
+    assert_eq!(Value::decode(b"i1ei2e"), Err(bencode::Error::Trailing(3)));
+    assert_eq!(
+        Value::decode(b"d1:ai1e1:ai2ee"),
+        Err(bencode::Error::DuplicateKey(7))
+    );
+}
+
+#[test]
+fn peer_message_roundtrip() {
+    let request = message::HashRequest {
+        pieces_root: [7; 32],
+        base_layer: 1,
+        index: 512,
+        length: 512,
+        proof_layers: 3,
+    };
+    let msgs = [
+        Message::KeepAlive,
+        Message::Unchoke,
+        Message::Have(message::Have { piece_index: 7 }),
+        Message::Request(message::Request::new(1, BLOCK_SIZE, BLOCK_SIZE)),
+        Message::Piece(message::Piece {
+            index: 1,
+            begin: 0,
+            block: vec![1, 2, 3],
+        }),
+        Message::Port(message::Port { listen_port: 6881 }),
+        Message::Extended(message::Extended::UtPex(message::UtPex {
+            added: vec![
+                message::PexPeer {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    flags: message::PEX_SEED,
+                },
+                message::PexPeer {
+                    addr: "[::1]:6882".parse().unwrap(),
+                    flags: 0,
+                },
+            ],
+            dropped: vec!["10.0.0.2:1".parse().unwrap(), "[::2]:2".parse().unwrap()],
+        })),
+        Message::HashRequest(request),
+        Message::HashReject(request),
+        Message::Hashes(message::Hashes {
+            request,
+            hashes: vec![[1; 32], [2; 32]],
+        }),
+    ];
+    for msg in msgs {
+        let buf = msg.encode();
+        let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, buf.len() - 4);
+        assert_eq!(Message::decode(buf[4..].to_vec()).unwrap(), msg);
+    }
+
+    let mut bf = message::Bitfield::new(10);
+    bf.set(9, true);
+    assert_eq!(bf.as_bytes(), &[0, 0x40]);
+    assert_eq!(bf.get(9), Some(true));
+    assert_eq!(bf.get(8), Some(false));
+    assert_eq!(bf.get(16), None);
+}
+
+#[test]
+fn piece_hash_mismatch() {
+    use sha1::{Digest, Sha1};
+
+    let data = vec![7u8; BLOCK_SIZE as usize + 10];
+    let hash: [u8; 20] = Sha1::digest(&data).into();
+
+    let mut p = Piece::new(0, [0; 20], data.len() as u32);
+    p.add(0, data[..BLOCK_SIZE as usize].to_vec()).unwrap();
+    assert!(matches!(
+        p.add(BLOCK_SIZE, data[BLOCK_SIZE as usize..].to_vec()),
+        Err(AddError::HashMismatch)
+    ));
+    assert!(!p.complete);
+    assert_eq!(p.unfinished_blocks().len(), 2);
+
+    let mut p = Piece::new(0, hash, data.len() as u32);
+    p.add(0, data[..BLOCK_SIZE as usize].to_vec()).unwrap();
+    p.add(BLOCK_SIZE, data[BLOCK_SIZE as usize..].to_vec())
+        .unwrap();
+    assert!(p.complete);
+}
+
+#[test]
+fn storage_spans_files() {
+    let root = std::env::temp_dir().join(format!("get-torrent-test-{}", std::process::id()));
+    let storage = Storage {
+        root: root.clone(),
+        files: vec![
+            FileSpan {
+                path: "t/a".into(),
+                offset: 0,
+                len: 5,
+            },
+            FileSpan {
+                path: "t/b".into(),
+                offset: 5,
+                len: 7,
+            },
+        ],
+        piece_length: 8,
+        length: 12,
+    };
+    assert_eq!(storage.piece_len(1), 4);
+    assert_eq!(storage.file_pieces(&storage.files[1]), 0..2);
+
+    storage.write(0, 0, b"abcdefgh").unwrap();
+    storage.write(1, 0, b"ijkl").unwrap();
+    assert_eq!(std::fs::read(root.join("t/a")).unwrap(), b"abcde");
+    assert_eq!(storage.read(0, 3, 5).unwrap(), b"defgh");
+    assert_eq!(storage.read(1, 1, 3).unwrap(), b"jkl");
+    // reads stay within their piece
+    assert!(storage.read(0, 3, 6).is_err());
+    assert!(storage.read(1, 0, 5).is_err());
+    assert!(storage.read(2, 0, 1).is_err());
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn magnet_parse() {
+    let m = Magnet::parse(
+        "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=a%20b\
+         &tr=http%3A%2F%2Ftracker%2Fannounce&tr=udp://t2:80&x.pe=1.2.3.4:5",
+    )
+    .unwrap();
+    assert_eq!(m.info_hash[..2], [0xc1, 0x2f]);
+    assert_eq!(m.name.as_deref(), Some("a b"));
+    assert_eq!(m.trackers, ["http://tracker/announce", "udp://t2:80"]);
+    assert_eq!(m.peers, ["1.2.3.4:5"]);
+
+    let b32 = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+    assert_eq!(b32.info_hash, m.info_hash);
+
+    // v2 only links go by the truncated multihash
+    let v2 = Magnet::parse(&format!("magnet:?xt=urn:btmh:1220{}", "ab".repeat(32))).unwrap();
+    assert_eq!(v2.info_hash_v2, Some([0xab; 32]));
+    assert_eq!(v2.info_hash, [0xab; 20]);
+    assert!(Magnet::parse(&format!("magnet:?xt=urn:btmh:1114{}", "ab".repeat(20))).is_err());
+
+    assert_eq!(
+        Magnet::parse("magnet:?dn=x"),
+        Err(magnet::Error::MissingInfoHash)
+    );
+    assert!(Magnet::parse("http://x").is_err());
+}
+
+#[test]
+fn ut_metadata_roundtrip() {
+    let data = message::UtMetadata::Data {
+        piece: 1,
+        total_size: 16390,
+        data: b"d4:name1:xe".to_vec(),
+    };
+    let msg = Message::Extended(message::Extended::UtMetadata(data));
+    let encoded = msg.encode();
+    assert_eq!(Message::decode(encoded[4..].to_vec()).unwrap(), msg);
+}
+
+#[test]
+fn ext_handshake_negotiation() {
+    let mut h = message::ExtHandshake {
+        m: [("ut_pex".to_string(), 3), ("ut_metadata".to_string(), 7)].into(),
+        v: Some("x 1.0".to_string()),
+        p: Some(6881),
+        reqq: Some(100),
+        yourip: Some([10, 0, 0, 1].into()),
+        metadata_size: Some(1234),
+    };
+    let encoded = h.to_bytes();
+    assert_eq!(message::ExtHandshake::from_bytes(&encoded).unwrap(), h);
+
+    // later handshake disables pex
+    h.update(message::ExtHandshake {
+        m: [("ut_pex".to_string(), 0)].into(),
+        ..Default::default()
+    });
+    assert_eq!(h.id("ut_pex"), None);
+    assert_eq!(h.id("ut_metadata"), Some(7));
+    assert_eq!(h.reqq, Some(100));
+
+    // we send with the id the peer picked, it sends back with ours
+    let msg = Message::Extended(message::Extended::UtPex(message::UtPex::default()));
+    let sent = msg.encode_ext(7);
+    assert_eq!(sent[5], 7);
+    assert_eq!(Message::decode(msg.encode()[4..].to_vec()).unwrap(), msg);
+}
+
+#[test]
+fn pex_deltas() {
+    let addr = |i: u16| std::net::SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6881));
+    let mut current = (0..60)
+        .map(|i| (addr(i), message::PEX_SEED))
+        .collect::<std::collections::HashMap<_, _>>();
+    let mut state = PexState::default();
+    let start = std::time::Instant::now();
+
+    let first = state.delta(&current, start).unwrap();
+    assert_eq!(first.added.len(), message::PEX_MAX_PEERS);
+    assert!(first.dropped.is_empty());
+    assert!(first.added.iter().all(|p| p.flags == message::PEX_SEED));
+    // not again within a minute
+    assert!(state.delta(&current, start + PEX_INTERVAL / 2).is_none());
+
+    // the rest is added, and a peer we told about is gone
+    let gone = first.added[0].addr;
+    current.remove(&gone);
+    let second = state.delta(&current, start + PEX_INTERVAL).unwrap();
+    let mut added = second.added.iter().map(|p| p.addr).collect::<Vec<_>>();
+    added.sort();
+    let mut rest = (0..60)
+        .map(addr)
+        .filter(|a| !first.added.iter().any(|p| p.addr == *a))
+        .collect::<Vec<_>>();
+    rest.sort();
+    assert_eq!(added, rest);
+    assert_eq!(second.dropped, vec![gone]);
+
+    // nothing changed, nothing to send
+    assert!(state.delta(&current, start + PEX_INTERVAL * 2).is_none());
+}
+
+#[test]
+fn allowed_fast_set() {
+    // example from BEP 6
+    let set =
+        crate::peer_proto::allowed_fast_set("80.4.4.200".parse().unwrap(), [0xaa; 20], 1313, 9);
+    assert_eq!(set, [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+
+    let msgs = [
+        Message::HaveAll,
+        Message::HaveNone,
+        Message::SuggestPiece(3),
+        Message::AllowedFast(4),
+        Message::RejectRequest(message::Request::new(1, 2, 3)),
+    ];
+    for msg in msgs {
+        assert_eq!(Message::decode(msg.encode()[4..].to_vec()).unwrap(), msg);
+    }
+}
+
+#[tokio::test]
+async fn mse_roundtrip() {
+    let info_hash = [7; 20];
+    let known = [[1; 20], info_hash];
+    let (a, b) = tokio::io::duplex(4096);
+    let (out, inc) = tokio::join!(
+        mse::initiate(a, info_hash, Encryption::Require),
+        mse::accept(b, &[], &known, Encryption::Prefer),
+    );
+    let mut out = out.unwrap();
+    let (mut inc, ih) = inc.unwrap();
+    assert_eq!(ih, info_hash);
+    assert!(out.encrypted() && inc.encrypted());
+
+    out.write_all(b"hello").await.unwrap();
+    out.flush().await.unwrap();
+    let mut buf = [0; 5];
+    inc.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let (a, b) = tokio::io::duplex(4096);
+    let (out, inc) = tokio::join!(
+        mse::initiate(a, info_hash, Encryption::Prefer),
+        mse::accept(b, &[], &known[1..], Encryption::Require),
+    );
+    assert!(out.unwrap().encrypted() && inc.unwrap().0.encrypted());
+}
+
+#[test]
+fn utp_header_roundtrip() {
+    let header = utp::Header {
+        kind: utp::PacketType::Data,
+        conn_id: 0x1234,
+        timestamp: 1,
+        timestamp_diff: 2,
+        wnd_size: 3,
+        seq_nr: 4,
+        ack_nr: 0xffff,
+    };
+    let mut buf = header.to_bytes().to_vec();
+    buf.extend_from_slice(b"data");
+    assert_eq!(
+        utp::Header::from_bytes(&buf),
+        Some((header.clone(), &b"data"[..]))
+    );
+
+    // selective ack extension is skipped
+    buf[1] = 1;
+    buf.splice(utp::HEADER_LEN..utp::HEADER_LEN, [0, 4, 0, 0, 0, 0]);
+    assert_eq!(utp::Header::from_bytes(&buf), Some((header, &b"data"[..])));
+    assert_eq!(utp::Header::from_bytes(&buf[..25]), None);
+}
+
+#[tokio::test]
+async fn utp_transfer() {
+    let a = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let b = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let data = (0..200_000).map(|i| i as u8).collect::<Vec<_>>();
+
+    let mut out = a.connect(b.local_addr().unwrap()).await.unwrap();
+    let (mut inc, addr) = b.accept().await.unwrap();
+    assert_eq!(addr, a.local_addr().unwrap());
+
+    let send = data.clone();
+    tokio::spawn(async move {
+        out.write_all(&send).await.unwrap();
+        out.shutdown().await.unwrap();
+        // keep the connection until everything is read
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    });
+    let mut received = Vec::new();
+    inc.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, data);
+}
+
+#[test]
+fn krpc_roundtrip() {
+    let node = Node {
+        id: [3; 20],
+        addr: "10.0.0.1:6881".parse().unwrap(),
+    };
+    let msgs = [
+        Body::Query {
+            id: [1; 20],
+            query: Query::AnnouncePeer {
+                info_hash: [2; 20],
+                port: 6888,
+                token: b"tok".to_vec(),
+                implied_port: true,
+            },
+        },
+        Body::Query {
+            id: [1; 20],
+            query: Query::GetPeers {
+                info_hash: [2; 20],
+                want: dht_proto::Want { n4: true, n6: true },
+            },
+        },
+        Body::Response(dht_proto::Response {
+            id: [1; 20],
+            nodes: vec![node.clone()],
+            nodes6: vec![Node {
+                id: [4; 20],
+                addr: "[2001:db8::1]:6881".parse().unwrap(),
+            }],
+            values: vec![
+                "10.0.0.2:6888".parse().unwrap(),
+                "[2001:db8::2]:6888".parse().unwrap(),
+            ],
+            token: Some(b"tok".to_vec()),
+            ip: Some("10.0.0.3:6881".parse().unwrap()),
+            ..Default::default()
+        }),
+        Body::Error {
+            code: 203,
+            message: "Bad token".to_string(),
+        },
+    ];
+    for body in msgs {
+        let msg = dht_proto::Message {
+            t: b"aa".to_vec(),
+            body,
+        };
+        assert_eq!(dht_proto::Message::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    // BEP 5 example
+    let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    let msg = dht_proto::Message::decode(ping).unwrap();
+    assert_eq!(
+        msg.body,
+        Body::Query {
+            id: *b"abcdefghij0123456789",
+            query: Query::Ping
+        }
+    );
+    assert_eq!(msg.encode(), ping);
+}
+
+#[test]
+fn routing_table_closest() {
+    let mut table = RoutingTable::new([0; 20]);
+    for i in 0..40u8 {
+        let mut id = [0; 20];
+        id[0] = 0x80 | i;
+        let addr = format!("10.0.0.{}:6881", i).parse().unwrap();
+        table.insert(Node { id, addr });
+    }
+    // all share the bucket of ids differing in the first bit
+    assert_eq!(table.len(), routing_table::K);
+    assert!(!table.insert(Node {
+        id: [0xff; 20],
+        addr: "10.0.1.1:6881".parse().unwrap(),
+    }));
+
+    let mut target = [0; 20];
+    target[0] = 0x85;
+    let closest = table.closest(&target, 3);
+    assert_eq!(closest[0].id[0], 0x85);
+    assert!(
+        routing_table::distance(&closest[1].id, &target)
+            < routing_table::distance(&closest[2].id, &target)
+    );
+
+    // unanswered queries push a node out
+    let addr = closest[0].addr;
+    for _ in 0..3 {
+        table.failed(addr);
+    }
+    assert!(table.entries().all(|e| e.node.addr != addr));
+}
+
+#[tokio::test]
+async fn dht_shares_utp_socket() {
+    let utp = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let shared = std::sync::Arc::new(DhtProto::shared(&utp).unwrap());
+    let node = std::sync::Arc::new(DhtProto::bind("127.0.0.1:0").await.unwrap());
+    // answers are routed while waiting for queries
+    let client = node.clone();
+    tokio::spawn(async move { while client.recv().await.is_ok() {} });
+
+    let server = shared.clone();
+    tokio::spawn(async move {
+        while let Ok((msg, addr)) = server.recv().await {
+            let body = Body::Response(dht_proto::Response {
+                id: [1; 20],
+                ..Default::default()
+            });
+            let msg = dht_proto::Message { t: msg.t, body };
+            server.send(addr, &msg).await.unwrap();
+        }
+    });
+    let addr = utp.local_addr().unwrap();
+    let r = node.query(addr, [2; 20], Query::Ping).await.unwrap();
+    assert_eq!(r.id, [1; 20]);
+
+    // utp keeps working on the same port
+    let peer = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    peer.connect(addr).await.unwrap();
+    utp.accept().await.unwrap();
+}
+
+#[test]
+fn routing_table_state() {
+    let mut table = RoutingTable::new([7; 20]);
+    table.insert(Node {
+        id: [1; 20],
+        addr: "10.0.0.1:6881".parse().unwrap(),
+    });
+    table.insert(Node {
+        id: [2; 20],
+        addr: "[2001:db8::1]:6881".parse().unwrap(),
+    });
+    let (v4, v6) = routing_table::from_state(&routing_table::to_state(&[&table])).unwrap();
+    assert_eq!((v4.id, v6.id), (table.id, table.id));
+    // nodes go to the table of their address family
+    let addrs = |t: &RoutingTable| t.entries().map(|e| e.node.addr).collect::<Vec<_>>();
+    assert_eq!(addrs(&v4), ["10.0.0.1:6881".parse().unwrap()]);
+    assert_eq!(addrs(&v6), ["[2001:db8::1]:6881".parse().unwrap()]);
+    assert!(v4.questionable().is_empty());
+
+    // a node seen long ago comes back questionable, garbage is skipped
+    let state = bencode::dict([
+        ("id", Value::Bytes(vec![7; 20])),
+        (
+            "nodes",
+            Value::List(vec![
+                bencode::dict([
+                    ("addr", Value::Bytes(vec![10, 0, 0, 3, 0x1a, 0xe1])),
+                    ("id", Value::Bytes(vec![3; 20])),
+                    ("seen", Value::Int(0)),
+                ]),
+                bencode::dict([("id", Value::Bytes(vec![4; 3]))]),
+            ]),
+        ),
+    ]);
+    let (loaded, _) = routing_table::from_state(&state.encode()).unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded.questionable().len(), 1);
+    assert!(routing_table::from_state(b"garbage").is_none());
+}
+
+#[tokio::test]
+async fn dht_port_message_adds_node() {
+    let proto = DhtProto::bind("127.0.0.1:0").await.unwrap();
+    let a = DhtDispatch::new(proto, None, 0, false, None);
+    let proto = DhtProto::bind("127.0.0.1:0").await.unwrap();
+    let b = DhtDispatch::new(proto, None, 0, false, None);
+    a.run();
+    b.run();
+    let addr = b.local_addr().unwrap();
+    let peer = std::net::SocketAddr::new(addr.ip(), 6888);
+    let port = message::Port {
+        listen_port: addr.port(),
+    };
+    for _ in 0..3 {
+        a.msg_port_send.send((peer, port)).unwrap();
+    }
+    for _ in 0..50 {
+        if a.nodes() > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(a.nodes(), 1);
+}
+
+#[test]
+fn bep42_node_ids() {
+    // BEP 42 test vectors
+    let vectors = [
+        ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+        ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+        ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+        ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+        ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+    ];
+    for (ip, id) in vectors {
+        let ip = ip.parse().unwrap();
+        let id = data_encoding::HEXLOWER.decode(id.as_bytes()).unwrap();
+        let id = <[u8; 20]>::try_from(id).unwrap();
+        assert!(routing_table::is_secure(&id, ip));
+        assert!(!routing_table::is_secure(&id, "1.2.3.4".parse().unwrap()));
+        assert!(routing_table::is_secure(&routing_table::secure_id(ip), ip));
+    }
+    assert!(routing_table::is_secure(
+        &[0; 20],
+        "192.168.1.1".parse().unwrap()
+    ));
+
+    // a full bucket makes room for a secure node only
+    let mut table = RoutingTable::new([0; 20]);
+    let addr: std::net::SocketAddr = "124.31.75.21:6881".parse().unwrap();
+    for i in 0..routing_table::K as u8 {
+        let mut id = [0; 20];
+        id[0] = 0x40 | i;
+        table.insert(Node { id, addr });
+    }
+    let mut id = [0; 20];
+    id[0] = 0x7f;
+    assert!(!table.insert(Node { id, addr }));
+    let id = data_encoding::HEXLOWER
+        .decode(b"5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401")
+        .unwrap();
+    let id = <[u8; 20]>::try_from(id).unwrap();
+    assert!(table.insert(Node { id, addr }));
+    assert_eq!(table.len(), routing_table::K);
+}
+
+fn hex<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = data_encoding::HEXLOWER.decode(s.as_bytes()).unwrap();
+    bytes.try_into().unwrap()
+}
+
+#[test]
+fn bep44_items() {
+    // BEP 44 test vectors
+    let v = Value::from("Hello World!");
+    let item = Item::immutable(v.clone()).unwrap();
+    assert_eq!(
+        item.target(),
+        hex("e5f96f6f38320f0f33959cb4d3d656452117aadb")
+    );
+
+    let k = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+    let mut item = Item {
+        v: v.clone(),
+        mutable: Some(dht_item::Mutable {
+            k,
+            salt: Vec::new(),
+            seq: 1,
+            sig: hex(
+                "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                      1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+            ),
+        }),
+    };
+    assert_eq!(item.verify(), Ok(()));
+    assert_eq!(
+        item.target(),
+        hex("4a533d47ec9c7d95b1ad75f576cffc641853b750")
+    );
+    let m = item.mutable.as_mut().unwrap();
+    m.salt = b"foobar".to_vec();
+    m.sig = hex(
+        "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+                 df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+    );
+    assert_eq!(item.verify(), Ok(()));
+    assert_eq!(
+        item.target(),
+        hex("411eba73b6f087ca51a3795d9c8c938d365e32c1")
+    );
+    item.mutable.as_mut().unwrap().seq = 2;
+    assert_eq!(item.verify(), Err(dht_item::Error::Signature));
+
+    let big = Value::Bytes(vec![0; dht_item::MAX_VALUE_LEN]);
+    assert_eq!(Item::immutable(big), Err(dht_item::Error::TooBig));
+}
+
+#[tokio::test]
+async fn dht_put_get() {
+    let proto = DhtProto::bind("127.0.0.1:0").await.unwrap();
+    let a = DhtDispatch::new(proto, None, 0, false, None);
+    let proto = DhtProto::bind("127.0.0.1:0").await.unwrap();
+    let b = DhtDispatch::new(proto, None, 0, false, None);
+    a.run();
+    b.run();
+    let port = message::Port {
+        listen_port: b.local_addr().unwrap().port(),
+    };
+    a.msg_port_send
+        .send(("127.0.0.1:6888".parse().unwrap(), port))
+        .unwrap();
+    while a.nodes() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let v = Value::from("immutable");
+    let item = Item::immutable(v.clone()).unwrap();
+    assert_eq!(a.put(item.clone(), None).await, 1);
+    assert_eq!(a.get_immutable(item.target()).await, Some(v));
+
+    let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+    let k = key.verifying_key().to_bytes();
+    for seq in [1, 2] {
+        let item = Item::mutable(&key, b"salt".to_vec(), seq, Value::Int(seq)).unwrap();
+        assert_eq!(a.put(item, Some(seq - 1)).await, 1);
+    }
+    let item = a.get_mutable(k, b"salt").await.unwrap();
+    assert_eq!((item.seq(), item.v), (Some(2), Value::Int(2)));
+    // older seq and stale cas are refused
+    let old = Item::mutable(&key, b"salt".to_vec(), 1, Value::Int(1)).unwrap();
+    assert_eq!(a.put(old, None).await, 0);
+    let stale = Item::mutable(&key, b"salt".to_vec(), 3, Value::Int(3)).unwrap();
+    assert_eq!(a.put(stale, Some(1)).await, 0);
+}
+
+#[tokio::test]
+async fn dht_sample_infohashes() {
+    let proto = DhtProto::bind("127.0.0.1:0").await.unwrap();
+    let a = DhtDispatch::new(proto, None, 0, false, None);
+    let proto = DhtProto::bind("127.0.0.1:0").await.unwrap();
+    let b = DhtDispatch::new(proto, None, 0, false, None);
+    a.run();
+    b.run();
+    let addr = b.local_addr().unwrap();
+
+    // a client announces a torrent to b
+    let client = std::sync::Arc::new(DhtProto::bind("127.0.0.1:0").await.unwrap());
+    let recv = client.clone();
+    tokio::spawn(async move { while recv.recv().await.is_ok() {} });
+    let info_hash = [9; 20];
+    let query = Query::GetPeers {
+        info_hash,
+        want: Default::default(),
+    };
+    let r = client.query(addr, [3; 20], query).await.unwrap();
+    let query = Query::AnnouncePeer {
+        info_hash,
+        port: 6881,
+        token: r.token.unwrap(),
+        implied_port: false,
+    };
+    client.query(addr, [3; 20], query).await.unwrap();
+
+    // b answers with the torrent among its samples
+    let query = Query::SampleInfohashes {
+        target: [4; 20],
+        want: Default::default(),
+    };
+    let r = client.query(addr, [3; 20], query).await.unwrap();
+    assert_eq!((r.num, r.samples), (Some(1), vec![info_hash]));
+    assert!(r.interval.unwrap() > 0);
+
+    // a crawls into b once it knows b
+    let port = message::Port {
+        listen_port: addr.port(),
+    };
+    a.msg_port_send
+        .send(("127.0.0.1:6888".parse().unwrap(), port))
+        .unwrap();
+    while a.nodes() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let found = a.crawl();
+    let sampled = tokio::time::timeout(std::time::Duration::from_secs(5), found.recv_async());
+    assert_eq!(sampled.await.unwrap().unwrap(), info_hash);
+}
+
+#[tokio::test]
+async fn lsd_announce() {
+    let msg = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+        Infohash: 0102030405060708090A0B0C0D0E0F1011121314\r\ncookie: abc\r\n\r\n\r\n";
+    let announce = lsd_dispatch::Announce::decode(msg).unwrap();
+    assert_eq!(announce.port, 6881);
+    assert_eq!(announce.cookie.as_deref(), Some("abc"));
+    assert_eq!(
+        announce.info_hashes,
+        vec![hex("0102030405060708090a0b0c0d0e0f1011121314")]
+    );
+    assert_eq!(
+        lsd_dispatch::Announce::decode(&announce.encode()),
+        Some(announce)
+    );
+
+    // two clients on one host find each other over loopback multicast
+    let group = std::net::SocketAddrV4::new(*lsd_dispatch::LSD_GROUP.ip(), 16771);
+    let loopback = std::net::Ipv4Addr::LOCALHOST;
+    let a = LsdDispatch::new(vec![LsdSocket::bind_v4(group, loopback).unwrap()], 6001);
+    let b = LsdDispatch::new(vec![LsdSocket::bind_v4(group, loopback).unwrap()], 6002);
+    b.run();
+    let (send_peer, recv_peer) = flume::unbounded();
+    b.add_torrent([5; 20], send_peer);
+    let (send_peer, _recv_peer) = flume::unbounded();
+    a.run();
+    a.add_torrent([5; 20], send_peer);
+    let peer = tokio::time::timeout(std::time::Duration::from_secs(5), recv_peer.recv_async());
+    assert_eq!(
+        peer.await.unwrap().unwrap(),
+        "127.0.0.1:6001".parse().unwrap()
+    );
+}
+
+// answers every request with what respond makes of its lowercased head
+async fn http_server(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut b = [0; 1];
+                if stream.read_exact(&mut b).await.is_err() {
+                    break;
+                }
+                head.push(b[0]);
+            }
+            let head = String::from_utf8_lossy(&head).to_lowercase();
+            stream.write_all(&respond(&head)).await.unwrap();
+        }
+    });
+    addr
+}
+
+fn http_response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut r = format!(
+        "HTTP/1.1 {}\r\n{}content-length: {}\r\n\r\n",
+        status,
+        headers,
+        body.len()
+    )
+    .into_bytes();
+    r.extend_from_slice(body);
+    r
+}
+
+// pieces of the data as the piece queue of a torrent with the files
+fn seed_pieces(data: &[u8], files: &[(&str, u64)], piece_length: u64) -> PieceDispatch {
+    use sha1::Digest;
+    let mut offset = 0;
+    let files = files
+        .iter()
+        .map(|(path, len)| {
+            offset += len;
+            FileSpan {
+                path: path.into(),
+                offset: offset - len,
+                len: *len,
+            }
+        })
+        .collect();
+    let storage = std::sync::Arc::new(Storage {
+        root: std::env::temp_dir(),
+        files,
+        piece_length,
+        length: data.len() as u64,
+    });
+    let hashes = data
+        .chunks(piece_length as usize)
+        .map(|c| sha1::Sha1::digest(c).into())
+        .collect::<Vec<[u8; 20]>>();
+    let (tx, rx) = flume::unbounded();
+    let (verified_tx, verified_rx) = flume::unbounded();
+    let n = hashes.len();
+    for (i, (hash, chunk)) in hashes
+        .iter()
+        .zip(data.chunks(piece_length as usize))
+        .enumerate()
+    {
+        tx.send(Piece::new(i, *hash, chunk.len() as u32)).unwrap();
+    }
+    PieceDispatch {
+        tx,
+        rx,
+        complete_piece: std::sync::Arc::new(parking_lot::Mutex::new(message::Bitfield::new(n))),
+        verified_tx,
+        verified_rx,
+        have_tx: tokio::sync::broadcast::channel(n).0,
+        hashes: std::sync::Arc::new(hashes),
+        roots: std::sync::Arc::new(Vec::new()),
+        wanted: std::sync::Arc::new(message::Bitfield::new(n)),
+        storage,
+    }
+}
+
+// runs the seed until every queued piece is verified, returns the
+// downloaded bytes and the first failure
+async fn seed_all(
+    url: String,
+    kind: Kind,
+    piece_dispatch: &PieceDispatch,
+) -> (u64, Option<crate::Event>) {
+    let events = crate::events::Events::new();
+    let mut failed = events.subscribe();
+    let (_control, control_rx) = tokio::sync::watch::channel(crate::RunState::Running);
+    let downloaded = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let task = WebSeedDispatch::new(url, [7; 20], kind).run(
+        piece_dispatch,
+        control_rx,
+        downloaded.clone(),
+        std::sync::Arc::new(crate::rate_limit::RateLimiter::new(0)),
+        events,
+    );
+    let mut verified = Vec::new();
+    for _ in 0..piece_dispatch.hashes.len() {
+        let piece = piece_dispatch.verified_rx.recv_async();
+        let piece = tokio::time::timeout(std::time::Duration::from_secs(10), piece);
+        verified.push(piece.await.unwrap().unwrap().index);
+    }
+    task.abort();
+    verified.sort();
+    assert_eq!(
+        verified,
+        (0..piece_dispatch.hashes.len()).collect::<Vec<_>>()
+    );
+    let downloaded = downloaded.load(std::sync::atomic::Ordering::Relaxed);
+    (downloaded, failed.try_recv().ok())
+}
+
+#[tokio::test]
+async fn web_seed_pieces() {
+    // piece 0 spans both files
+    let data = (0..50000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let files = [("/t/a%20b", 0..20000), ("/t/c", 20000..50000)];
+    let busy = std::sync::atomic::AtomicBool::new(true);
+    let file_data = data.clone();
+    let addr = http_server(move |head| {
+        if busy.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            return http_response("503 Busy", "retry-after: 0\r\n", b"");
+        }
+        let path = head.split(' ').nth(1).unwrap_or_default();
+        let range = head
+            .lines()
+            .find_map(|l| l.strip_prefix("range: bytes="))
+            .and_then(|r| r.split_once('-'))
+            .map(|(a, b)| a.parse::<usize>().unwrap()..b.parse::<usize>().unwrap() + 1);
+        match (files.iter().find(|(p, _)| *p == path), range) {
+            (Some((_, file)), Some(range)) => {
+                let file = &file_data[file.clone()];
+                http_response("206 Partial Content", "", &file[range])
+            }
+            _ => http_response("404 Not Found", "", b""),
+        }
+    })
+    .await;
+
+    let piece_dispatch = seed_pieces(&data, &[("t/a b", 20000), ("t/c", 30000)], 32768);
+    let kind = Kind::UrlList { multi_file: true };
+    let (downloaded, failed) = seed_all(format!("http://{}", addr), kind, &piece_dispatch).await;
+    assert_eq!(downloaded, 50000);
+    assert!(matches!(
+        failed,
+        Some(crate::Event::WebSeedFailed { message, .. }) if message == "Server busy"
+    ));
+}
+
+#[tokio::test]
+async fn http_seed_pieces() {
+    let data = (0..40000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let busy = std::sync::atomic::AtomicBool::new(true);
+    let piece_data = data.clone();
+    let addr = http_server(move |head| {
+        if busy.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            return http_response("503 Service Unavailable", "", b"0");
+        }
+        let query = head.split(' ').nth(1).unwrap_or_default();
+        assert!(query.contains("info_hash=%07%07"));
+        assert!(query.contains("piece=0"));
+        // the middle block is already there
+        let ranges = query.split('&').find_map(|p| p.strip_prefix("ranges="));
+        assert_eq!(ranges, Some("0-16383%2c32768-39999"));
+        let body = [&piece_data[..16384], &piece_data[32768..]].concat();
+        http_response("200 OK", "", &body)
+    })
+    .await;
+
+    let piece_dispatch = seed_pieces(&data, &[("t", 40000)], 40000);
+    let mut piece = piece_dispatch.rx.recv().unwrap();
+    piece.add(BLOCK_SIZE, data[16384..32768].to_vec()).unwrap();
+    piece_dispatch.tx.send(piece).unwrap();
+    let url = format!("http://{}/seed", addr);
+    let (downloaded, failed) = seed_all(url, Kind::HttpSeed, &piece_dispatch).await;
+    assert_eq!(downloaded, 40000 - 16384);
+    assert!(failed.is_some());
+}
+
+#[test]
+fn metainfo_parse() {
+    let t = MetaInfo::read_from_file("torrent/debian.iso.torrent").unwrap();
+    assert_eq!(t.info_hash, hex("6d4795dee70aeb88e03e5336ca7c9fcf0a1e206d"));
+    assert_eq!(t.info.name, "debian-11.6.0-amd64-netinst.iso");
+    assert_eq!((t.info.length, t.info.piece_length), (406847488, 262144));
+    assert_eq!(t.info.pieces.len(), 1552);
+    assert_eq!(t.info.files, None);
+    assert_eq!(
+        t.announce.as_deref(),
+        Some("http://bttracker.debian.org:6969/announce")
+    );
+    assert_eq!(t.url_list.len(), 2);
+    assert_eq!(t.created_by.as_deref(), Some("mktorrent 1.1"));
+    assert_eq!(t.creation_date, Some(1671279444));
+
+    // info keys out of order keep their hash
+    let info = b"d5:filesld6:lengthi3e4:pathl1:a1:beed6:lengthi2e6:md5sum1:x4:pathl1:ceee\
+        4:name1:t12:piece lengthi4e6:pieces40:0123456789012345678901234567890123456789\
+        7:privatei1e6:source3:lane";
+    let mut buf = b"d13:announce-listll2:t1el2:t2ee8:url-list3:ws/4:info".to_vec();
+    buf.extend_from_slice(info);
+    buf.push(b'e');
+    let t = MetaInfo::from_bytes(&buf).unwrap();
+    assert_eq!(t.info_bytes, info);
+    assert_eq!(t.announce_list, [vec!["t1"], vec!["t2"]]);
+    assert_eq!(t.url_list, ["ws/"]);
+    assert!(t.info.private);
+    assert_eq!(t.info.source.as_deref(), Some("lan"));
+    let files = t.info.files.unwrap();
+    assert_eq!(files[0].path, std::path::Path::new("a/b"));
+    assert_eq!(files[1].md5sum.as_deref(), Some("x"));
+    assert_eq!(t.info.length, 5);
+
+    let err = |buf: &[u8]| MetaInfo::from_bytes(buf).unwrap_err().to_string();
+    assert_eq!(err(b"d4:infod6:lengthi1eee"), "Missing info.name");
+    assert_eq!(
+        err(b"d4:infod6:lengthi1e4:name1:t12:piece lengthi4e6:pieces3:abcee"),
+        "Invalid info.pieces"
+    );
+    assert!(matches!(
+        MetaInfo::from_bytes(b"d4:info"),
+        Err(metainfo::Error::Bencode(_))
+    ));
+}
+
+#[test]
+fn metainfo_v2() {
+    use sha1::{Digest, Sha1};
+
+    // sha256 of two zero leaves
+    assert_eq!(
+        merkle::pad_hash(1),
+        hex("f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b")
+    );
+
+    let piece_length = 2 * BLOCK_SIZE as u64;
+    let a = (0..70000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let b = vec![9u8; 100];
+    let layer = a
+        .chunks(piece_length as usize)
+        .map(|piece| merkle::data_root(piece, 2))
+        .collect::<Vec<_>>();
+    let root_a = merkle::layer_root(&layer, piece_length);
+    let root_b = merkle::data_root(&b, 1);
+    // files start at a piece, the gap is zero
+    let mut stream = a.clone();
+    stream.resize(3 * piece_length as usize, 0);
+    stream.extend_from_slice(&b);
+
+    let leaf = |len: usize, root: [u8; 32]| {
+        bencode::dict([(
+            "",
+            bencode::dict([
+                ("length", Value::Int(len as i64)),
+                ("pieces root", Value::Bytes(root.to_vec())),
+            ]),
+        )])
+    };
+    let tree = bencode::dict([
+        ("a", leaf(a.len(), root_a)),
+        ("d", bencode::dict([("b", leaf(b.len(), root_b))])),
+    ]);
+    let v2 = bencode::dict([
+        ("file tree", tree.clone()),
+        ("meta version", Value::Int(2)),
+        ("name", Value::from("t")),
+        ("piece length", Value::Int(piece_length as i64)),
+    ]);
+    let torrent = |info: &Value, layer: &[[u8; 32]]| {
+        let layers = Value::Dict([(root_a.to_vec(), Value::Bytes(layer.concat()))].into());
+        bencode::dict([("info", info.clone()), ("piece layers", layers)]).encode()
+    };
+
+    let t = MetaInfo::from_bytes(&torrent(&v2, &layer)).unwrap();
+    assert_eq!(t.info_hash_v2, Some(merkle::hash(&t.info_bytes)));
+    assert_eq!(t.info_hash[..], t.info_hash_v2.unwrap()[..20]);
+    assert_eq!(t.piece_layers[&root_a], layer);
+    // as rebuilt from a magnet link
+    let fetched = MetaInfo::from_info(t.info_bytes.clone(), t.piece_layers.clone()).unwrap();
+    assert_eq!(fetched.info_hash, t.info_hash);
+    assert_eq!(MetaInfo::from_bytes(&fetched.to_bytes()).unwrap(), t);
+    let files = t.info.files.as_ref().unwrap();
+    assert_eq!(
+        files.iter().map(|f| (f.length, f.pad)).collect::<Vec<_>>(),
+        [(70000, false), (28304, true), (100, false)]
+    );
+    assert_eq!(files[2].path, std::path::Path::new("d/b"));
+    assert_eq!(t.info.piece_count(), 4);
+
+    let root = std::env::temp_dir().join(format!("get-torrent-v2-{}", std::process::id()));
+    let storage = std::sync::Arc::new(Storage::new(root.clone(), &t));
+    assert_eq!(storage.files.len(), 2);
+    assert_eq!(storage.files[1].offset, 98304);
+    let pieces = PieceDispatch::new(&t, storage.clone(), None);
+    for (index, data) in stream.chunks(piece_length as usize).enumerate() {
+        let mut piece = pieces.piece(index);
+        assert_eq!(piece.hash, None);
+        for (i, block) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            piece.add(i as u32 * BLOCK_SIZE, block.to_vec()).unwrap();
+        }
+        assert!(piece.complete);
+        storage.write(index, 0, data).unwrap();
+    }
+    assert_eq!(std::fs::read(root.join("t/d/b")).unwrap(), b);
+    assert_eq!(std::fs::metadata(root.join("t/a")).unwrap().len(), 70000);
+    assert!(storage.verify(&pieces.piece(2)));
+    let mut piece = pieces.piece(0);
+    piece.add(0, vec![0; BLOCK_SIZE as usize]).unwrap();
+    assert!(matches!(
+        piece.add(BLOCK_SIZE, vec![0; BLOCK_SIZE as usize]),
+        Err(AddError::HashMismatch)
+    ));
+    std::fs::remove_dir_all(root).unwrap();
+
+    // without piece layers only the pieces of small files verify
+    let t = MetaInfo::from_bytes(&bencode::dict([("info", v2.clone())]).encode()).unwrap();
+    let pieces = PieceDispatch::new(&t, storage.clone(), None);
+    assert!(!pieces.piece(0).verify(&stream[..piece_length as usize]));
+    assert!(pieces.piece(3).verify(&b));
+    assert_eq!(
+        MetaInfo::from_bytes(&torrent(&v2, &layer[1..]))
+            .unwrap_err()
+            .to_string(),
+        "Invalid piece layers"
+    );
+
+    // hybrid torrents list the pad file and hash the padded stream
+    let file = |len: usize, path: &[&str], attr: &str| {
+        let path = path.iter().map(|p| Value::from(*p)).collect();
+        bencode::dict([
+            ("attr", Value::from(attr)),
+            ("length", Value::Int(len as i64)),
+            ("path", Value::List(path)),
+        ])
+    };
+    let hybrid = bencode::dict([
+        ("file tree", tree),
+        (
+            "files",
+            Value::List(vec![
+                file(a.len(), &["a"], ""),
+                file(28304, &[".pad", "28304"], "p"),
+                file(b.len(), &["d", "b"], ""),
+            ]),
+        ),
+        ("meta version", Value::Int(2)),
+        ("name", Value::from("t")),
+        ("piece length", Value::Int(piece_length as i64)),
+        (
+            "pieces",
+            Value::Bytes(
+                stream
+                    .chunks(piece_length as usize)
+                    .flat_map(|piece| Sha1::digest(piece).to_vec())
+                    .collect(),
+            ),
+        ),
+    ]);
+    let t = MetaInfo::from_bytes(&torrent(&hybrid, &layer)).unwrap();
+    assert_eq!(t.info_hash, <[u8; 20]>::from(Sha1::digest(&t.info_bytes)));
+    assert!(t.info_hash_v2.is_some());
+    let pieces = PieceDispatch::new(&t, storage, None);
+    for (index, data) in stream.chunks(piece_length as usize).enumerate() {
+        let piece = pieces.piece(index);
+        assert!(piece.hash.is_some() && piece.root.is_some());
+        assert!(piece.verify(data));
+    }
+    // both hashes must match
+    let piece = pieces.piece(3);
+    let wrong_root = PieceRoot {
+        root: [0; 32],
+        ..piece.root.unwrap()
+    };
+    assert!(!Piece::with_hashes(3, piece.hash, Some(wrong_root), piece.len).verify(&b));
+}
+
+#[test]
+fn merkle_hash_request() {
+    let piece_length = 2 * BLOCK_SIZE as u64;
+    let layer = (0..5u8).map(|i| merkle::hash(&[i])).collect::<Vec<_>>();
+    let root = merkle::layer_root(&layer, piece_length);
+    let pad = merkle::pad_hash(1);
+
+    // nodes 4 and 5 of the piece layer padded to 8, then their uncles
+    let hashes = merkle::hashes(&layer, piece_length, 1, 4, 2, 8).unwrap();
+    assert_eq!(hashes.len(), 4);
+    assert_eq!(hashes[..2], [layer[4], pad]);
+    let node = merkle::layer_up(&hashes[..2], &pad)[0];
+    let node = merkle::layer_up(&[node, hashes[2]], &pad)[0];
+    assert_eq!(merkle::layer_up(&[hashes[3], node], &pad)[0], root);
+
+    let whole = merkle::hashes(&layer, piece_length, 1, 0, 8, 0).unwrap();
+    assert_eq!(merkle::root(&whole, 8, pad), root);
+    assert_eq!(
+        merkle::hashes(&layer, piece_length, 2, 0, 2, 1)
+            .unwrap()
+            .len(),
+        3
+    );
+    // below the piece layer, unaligned or odd requests
+    assert_eq!(merkle::hashes(&layer, piece_length, 0, 0, 2, 0), None);
+    assert_eq!(merkle::hashes(&layer, piece_length, 1, 2, 4, 0), None);
+    assert_eq!(merkle::hashes(&layer, piece_length, 1, 0, 3, 0), None);
+    // above the root, a huge base must not hash its way up there
+    assert_eq!(
+        merkle::hashes(&layer, piece_length, 4, 0, 1, 0).unwrap(),
+        vec![root]
+    );
+    assert_eq!(merkle::hashes(&layer, piece_length, 5, 0, 1, 0), None);
+    assert_eq!(
+        merkle::hashes(&layer, piece_length, u32::MAX, 0, 1, 0),
+        None
+    );
+}
+
+#[test]
+fn create_torrent() {
+    use sha1::{Digest, Sha1};
+
+    let dir = std::env::temp_dir().join(format!("get-torrent-create-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("t/a")).unwrap();
+    std::fs::write(dir.join("t/b"), b"xyz").unwrap();
+    std::fs::write(dir.join("t/a/c"), vec![1u8; 20000]).unwrap();
+    let options = CreateOptions {
+        piece_length: Some(BLOCK_SIZE as u64),
+        trackers: vec![vec!["http://t1/a".into(), "http://t2/a".into()]],
+        web_seeds: vec!["http://ws/".into()],
+        comment: Some("c".into()),
+        creation_date: Some(1),
+        private: true,
+        source: Some("s".into()),
+        threads: 2,
+        ..Default::default()
+    };
+    let t = create::create(dir.join("t"), &options).unwrap();
+
+    // files sorted by path, as other tools make them
+    let mut stream = vec![1u8; 20000];
+    stream.extend_from_slice(b"xyz");
+    let file = |len: i64, path: &[&str]| {
+        let path = path.iter().map(|p| Value::from(*p)).collect();
+        bencode::dict([("length", Value::Int(len)), ("path", Value::List(path))])
+    };
+    let info = bencode::dict([
+        (
+            "files",
+            Value::List(vec![file(20000, &["a", "c"]), file(3, &["b"])]),
+        ),
+        ("name", Value::from("t")),
+        ("piece length", Value::Int(BLOCK_SIZE as i64)),
+        (
+            "pieces",
+            Value::Bytes(
+                stream
+                    .chunks(BLOCK_SIZE as usize)
+                    .flat_map(|piece| Sha1::digest(piece).to_vec())
+                    .collect(),
+            ),
+        ),
+        ("private", Value::Int(1)),
+        ("source", Value::from("s")),
+    ]);
+    assert_eq!(t.info_bytes, info.encode());
+    assert_eq!(t.info_hash, <[u8; 20]>::from(Sha1::digest(info.encode())));
+    assert_eq!(t.announce.as_deref(), Some("http://t1/a"));
+    assert_eq!(MetaInfo::from_bytes(&t.to_bytes()).unwrap(), t);
+
+    let t = create::create(dir.join("t/b"), &CreateOptions::default()).unwrap();
+    assert_eq!((t.info.length, t.info.files), (3, None));
+    assert_eq!(t.info.piece_length, BLOCK_SIZE as u64);
+    assert_eq!(t.announce_list, Vec::<Vec<String>>::new());
+    let bad = CreateOptions {
+        piece_length: Some(40000),
+        ..Default::default()
+    };
+    assert!(matches!(
+        create::create(dir.join("t"), &bad),
+        Err(create::Error::PieceLength)
+    ));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn tracker_response_peers() {
+    let r = b"d8:intervali900e5:peers6:\x01\x02\x03\x04\x1a\xe1\
+        6:peers618:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e";
+    let peers = vec![
+        "1.2.3.4:6881".parse().unwrap(),
+        "[::1]:6881".parse().unwrap(),
+    ];
+    assert_eq!(
+        TrackerResponse::from_bytes(r).unwrap(),
+        TrackerResponse::Success {
+            interval: 900,
+            peers
+        }
+    );
+    let r = b"d8:intervali60e5:peersld2:ip9:127.0.0.14:porti80eeee";
+    let TrackerResponse::Success { peers, .. } = TrackerResponse::from_bytes(r).unwrap() else {
+        panic!("no peers");
+    };
+    assert_eq!(peers, ["127.0.0.1:80".parse().unwrap()]);
+    assert_eq!(
+        TrackerResponse::from_bytes(b"d14:failure reason4:gonee").unwrap(),
+        TrackerResponse::Failure {
+            reason: "gone".to_string()
+        }
+    );
+}
@@ -0,0 +1,174 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use flume::Sender;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+use crate::{
+    bencode::Value,
+    events::{Event, Events},
+    peer_proto::message::{compact_v4, compact_v6},
+    NAME,
+};
+
+// used when tracker is unreachable or doesn't tell the interval
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum Err {
+    #[error("Bad announce url")]
+    Url,
+    #[error("Http request error")]
+    Http(#[from] reqwest::Error),
+    #[error("Cannot parse tracker response")]
+    Response,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerResponse {
+    Success {
+        interval: i64,
+        peers: Vec<SocketAddr>,
+    },
+    Failure {
+        reason: String,
+    },
+}
+
+impl TrackerResponse {
+    // peers come compact or as dicts, ipv6 ones also in peers6, see BEP 23
+    // and BEP 7
+    pub fn from_bytes(buf: &[u8]) -> Result<TrackerResponse, Err> {
+        let v = Value::decode(buf).map_err(|_| Err::Response)?;
+        if let Some(reason) = v.get("failure reason").and_then(Value::as_bytes) {
+            return Ok(TrackerResponse::Failure {
+                reason: String::from_utf8_lossy(reason).into_owned(),
+            });
+        }
+        let interval = v
+            .get("interval")
+            .and_then(Value::as_int)
+            .ok_or(Err::Response)?;
+        let mut peers = match v.get("peers") {
+            Some(Value::Bytes(peers)) => compact_v4(peers),
+            Some(Value::List(peers)) => peers
+                .iter()
+                .filter_map(|p| {
+                    let ip = p.get("ip")?.as_str()?.parse::<IpAddr>().ok()?;
+                    let port = u16::try_from(p.get("port")?.as_int()?).ok()?;
+                    Some(SocketAddr::new(ip, port))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if let Some(peers6) = v.get("peers6").and_then(Value::as_bytes) {
+            peers.extend(compact_v6(peers6));
+        }
+        Ok(TrackerResponse::Success { interval, peers })
+    }
+}
+
+pub struct TrackerDispatch {
+    pub announce: String,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+}
+
+impl TrackerDispatch {
+    pub fn new(announce: String, info_hash: [u8; 20], peer_id: [u8; 20], port: u16) -> Self {
+        TrackerDispatch {
+            announce,
+            info_hash,
+            peer_id,
+            port,
+        }
+    }
+
+    pub fn run(&self, send_peer: Sender<SocketAddr>, events: Events) -> JoinHandle<()> {
+        let announce = self.announce.clone();
+        let info_hash = self.info_hash;
+        let peer_id = self.peer_id;
+        let port = self.port;
+        tokio::spawn(Self::worker(
+            announce, info_hash, peer_id, port, send_peer, events,
+        ))
+    }
+
+    async fn worker(
+        announce: String,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        port: u16,
+        send_peer: Sender<SocketAddr>,
+        events: Events,
+    ) {
+        let client = match reqwest::Client::builder().user_agent(NAME).build() {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        let mut event = Some("started");
+        loop {
+            let (interval, result) =
+                match Self::announce(&client, &announce, info_hash, peer_id, port, event).await {
+                    Ok(TrackerResponse::Success { peers, interval }) => {
+                        event = None;
+                        let count = peers.len();
+                        for p in peers {
+                            if send_peer.send(p).is_err() {
+                                return;
+                            }
+                        }
+                        (
+                            Duration::from_secs(interval.max(0) as u64).max(MIN_INTERVAL),
+                            Ok(count),
+                        )
+                    }
+                    Ok(TrackerResponse::Failure { reason }) => (DEFAULT_INTERVAL, Err(reason)),
+                    Err(e) => (DEFAULT_INTERVAL, Err(e.to_string())),
+                };
+            events.emit(Event::TrackerAnnounce {
+                info_hash,
+                url: announce.clone(),
+                result,
+            });
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub async fn announce(
+        client: &reqwest::Client,
+        announce: &str,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        port: u16,
+        event: Option<&str>,
+    ) -> Result<TrackerResponse, Err> {
+        let peer_id = String::from_utf8_lossy(&peer_id).into_owned();
+        let port = port.to_string();
+        let mut params = vec![
+            ("peer_id", peer_id.as_str()),
+            ("port", port.as_str()),
+            ("downloaded", "0"),
+            ("uploaded", "0"),
+            ("left", "0"),
+            ("compact", "1"),
+        ];
+        if let Some(event) = event {
+            params.push(("event", event));
+        }
+
+        // info_hash is binary and must not be encoded by url serializer
+        let info_hash = urlencoding::encode_binary(&info_hash).into_owned();
+        let url = reqwest::Url::parse_with_params(announce, &params).map_err(|_| Err::Url)?;
+        let url = reqwest::Url::parse(&format!("{}&info_hash={}", url, info_hash))
+            .map_err(|_| Err::Url)?;
+
+        let resp = client.get(url).send().await?.bytes().await?;
+        TrackerResponse::from_bytes(&resp)
+    }
+}
@@ -0,0 +1,664 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, Sender};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{ToSocketAddrs, UdpSocket},
+    task::JoinHandle,
+    time::interval,
+};
+
+const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 20;
+// payload per packet, keeps datagrams below common path mtu
+const MSS: usize = 1400;
+const MIN_WINDOW: usize = MSS;
+// LEDBAT target queuing delay in microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+// window growth per rtt when there is no queuing delay at all
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const RECV_WINDOW: usize = 1 << 20;
+// packets further ahead are dropped instead of buffered
+const MAX_OUT_OF_ORDER: u16 = 1024;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const MAX_RESENDS: u32 = 6;
+// base delay is the minimum of two one minute buckets
+const DELAY_BUCKET: Duration = Duration::from_secs(60);
+const TICK: Duration = Duration::from_millis(50);
+// queued datagrams of other protocols, later ones are dropped
+const MAX_FOREIGN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+// see BEP 29
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub kind: PacketType,
+    pub conn_id: u16,
+    pub timestamp: u32,
+    pub timestamp_diff: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[0] = (self.kind as u8) << 4 | VERSION;
+        // no extensions
+        buf[2..4].copy_from_slice(&self.conn_id.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.timestamp_diff.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.wnd_size.to_be_bytes());
+        buf[16..18].copy_from_slice(&self.seq_nr.to_be_bytes());
+        buf[18..20].copy_from_slice(&self.ack_nr.to_be_bytes());
+        buf
+    }
+
+    // header and payload of a packet, extensions like selective ack are skipped
+    pub fn from_bytes(buf: &[u8]) -> Option<(Header, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = match buf[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        };
+        let mut ext = buf[1];
+        let mut payload = &buf[HEADER_LEN..];
+        while ext != 0 {
+            let (next, len) = (*payload.first()?, *payload.get(1)? as usize);
+            payload = payload.get(2 + len..)?;
+            ext = next;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let header = Header {
+            kind,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        Some((header, payload))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+// packet waiting for its ack
+struct Sent {
+    kind: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    resends: u32,
+}
+
+// datagrams go out through the socket driver
+pub type Outgoing = Sender<(Vec<u8>, SocketAddr)>;
+
+struct Conn {
+    outgoing: Outgoing,
+    // timestamps are microseconds since the socket was bound
+    start: Instant,
+    remote: SocketAddr,
+    state: State,
+    // why the connection broke, reported to readers and writers
+    error: Option<io::ErrorKind>,
+    recv_id: u16,
+    send_id: u16,
+    // next sequence number to send
+    seq_nr: u16,
+    // last sequence number received in order
+    ack_nr: u16,
+    unacked: VecDeque<Sent>,
+    in_flight: usize,
+    // congestion window in bytes, driven by LEDBAT
+    cwnd: f64,
+    peer_wnd: usize,
+    // smoothed rtt and its variance in milliseconds
+    rtt: f64,
+    rtt_var: f64,
+    rto: Duration,
+    base_delays: [u32; 2],
+    bucket_started: Instant,
+    // echoed to the peer so it can measure its one way delay
+    reply_micro: u32,
+    received: Vec<u8>,
+    out_of_order: HashMap<u16, (PacketType, Vec<u8>)>,
+    fin_received: bool,
+    fin_sent: bool,
+    // stream is gone, connection is kept until pending data is acked
+    dropped: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Conn {
+    fn new(
+        outgoing: Outgoing,
+        start: Instant,
+        remote: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        state: State,
+    ) -> Conn {
+        Conn {
+            outgoing,
+            start,
+            remote,
+            state,
+            error: None,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            cwnd: MIN_WINDOW as f64,
+            peer_wnd: RECV_WINDOW,
+            rtt: 0.0,
+            rtt_var: 0.0,
+            rto: INITIAL_RTO,
+            base_delays: [u32::MAX; 2],
+            bucket_started: Instant::now(),
+            reply_micro: 0,
+            received: Vec::new(),
+            out_of_order: HashMap::new(),
+            fin_received: false,
+            fin_sent: false,
+            dropped: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn now_micros(&self) -> u32 {
+        self.start.elapsed().as_micros() as u32
+    }
+
+    fn transmit(&self, kind: PacketType, seq_nr: u16, payload: &[u8]) {
+        let header = Header {
+            kind,
+            // syn is the only packet carrying our receive id
+            conn_id: match kind {
+                PacketType::Syn => self.recv_id,
+                _ => self.send_id,
+            },
+            timestamp: self.now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_WINDOW.saturating_sub(self.received.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        let packet = [&header.to_bytes()[..], payload].concat();
+        #[allow(unused_must_use)]
+        {
+            self.outgoing.send((packet, self.remote));
+        }
+    }
+
+    // sends a packet which takes a sequence number and must be acked
+    fn push(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(kind, seq_nr, &payload);
+        self.in_flight += payload.len();
+        self.unacked.push_back(Sent {
+            kind,
+            seq_nr,
+            payload,
+            sent_at: Instant::now(),
+            resends: 0,
+        });
+    }
+
+    fn close(&mut self, error: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error.get_or_insert(error);
+        self.unacked.clear();
+        self.in_flight = 0;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+
+    fn on_packet(&mut self, h: &Header, payload: &[u8]) {
+        self.peer_wnd = h.wnd_size as usize;
+        self.reply_micro = self.now_micros().wrapping_sub(h.timestamp);
+        match (h.kind, self.state) {
+            (PacketType::Reset, _) => return self.close(io::ErrorKind::ConnectionReset),
+            (_, State::Closed) => return,
+            (PacketType::State, State::SynSent) => {
+                self.state = State::Connected;
+                // first data packet of the peer carries its current seq_nr
+                self.ack_nr = h.seq_nr.wrapping_sub(1);
+            }
+            (_, State::SynSent) => return,
+            _ => (),
+        }
+        self.on_ack(h.ack_nr, h.timestamp_diff);
+        if matches!(h.kind, PacketType::Data | PacketType::Fin) {
+            self.on_data(h.kind, h.seq_nr, payload);
+        }
+        self.wake();
+    }
+
+    fn on_ack(&mut self, ack_nr: u16, delay: u32) {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut rtt_sample = None;
+        // everything up to ack_nr is acked, sequence numbers wrap
+        while let Some(p) = self.unacked.front() {
+            if ack_nr.wrapping_sub(p.seq_nr) >= 0x8000 {
+                break;
+            }
+            // resent packets give ambiguous samples, see Karn's algorithm
+            if p.resends == 0 {
+                rtt_sample = Some(now - p.sent_at);
+            }
+            acked += p.payload.len();
+            self.unacked.pop_front();
+        }
+        if let Some(sample) = rtt_sample {
+            let sample = sample.as_secs_f64() * 1000.0;
+            if self.rtt == 0.0 {
+                self.rtt = sample;
+                self.rtt_var = sample / 2.0;
+            } else {
+                self.rtt_var += ((self.rtt - sample).abs() - self.rtt_var) / 4.0;
+                self.rtt += (sample - self.rtt) / 8.0;
+            }
+            let rto = Duration::from_secs_f64((self.rtt + 4.0 * self.rtt_var) / 1000.0);
+            self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+        }
+        if acked > 0 {
+            self.in_flight -= acked;
+            self.ledbat(acked, delay);
+        }
+    }
+
+    // grows the window while the queuing delay the peer measures stays
+    // below target and shrinks it beyond, so bulk transfers yield to
+    // interactive traffic on the same link
+    fn ledbat(&mut self, acked: usize, delay: u32) {
+        // peer has no sample of ours yet
+        if delay == 0 {
+            return;
+        }
+        if self.bucket_started.elapsed() >= DELAY_BUCKET {
+            self.base_delays = [u32::MAX, self.base_delays[0]];
+            self.bucket_started = Instant::now();
+        }
+        self.base_delays[0] = self.base_delays[0].min(delay);
+        let base_delay = self.base_delays[0].min(self.base_delays[1]);
+        let queuing_delay = delay.saturating_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        let window_factor = acked as f64 / self.cwnd.max(acked as f64);
+        self.cwnd += MAX_CWND_INCREASE * off_target * window_factor;
+        self.cwnd = self.cwnd.max(MIN_WINDOW as f64);
+    }
+
+    fn on_data(&mut self, kind: PacketType, seq_nr: u16, payload: &[u8]) {
+        let offset = seq_nr.wrapping_sub(self.ack_nr.wrapping_add(1));
+        if offset == 0 {
+            self.deliver(kind, payload);
+            while let Some((kind, payload)) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1))
+            {
+                self.deliver(kind, &payload);
+            }
+        } else if offset < MAX_OUT_OF_ORDER && !self.fin_received {
+            self.out_of_order
+                .entry(seq_nr)
+                .or_insert_with(|| (kind, payload.to_vec()));
+        }
+        // duplicates are acked too, our previous ack may be lost
+        self.transmit(PacketType::State, self.seq_nr, &[]);
+    }
+
+    fn deliver(&mut self, kind: PacketType, payload: &[u8]) {
+        self.ack_nr = self.ack_nr.wrapping_add(1);
+        match kind {
+            PacketType::Fin => {
+                self.fin_received = true;
+                self.out_of_order.clear();
+            }
+            _ => self.received.extend_from_slice(payload),
+        }
+    }
+
+    // resends the oldest packet once its ack is overdue
+    fn on_tick(&mut self) {
+        let Some(p) = self.unacked.front_mut() else {
+            return;
+        };
+        if p.sent_at.elapsed() < self.rto {
+            return;
+        }
+        if p.resends >= MAX_RESENDS {
+            return self.close(io::ErrorKind::TimedOut);
+        }
+        p.resends += 1;
+        p.sent_at = Instant::now();
+        let (kind, seq_nr, payload) = (p.kind, p.seq_nr, p.payload.clone());
+        self.transmit(kind, seq_nr, &payload);
+        // loss, back off like tcp does
+        self.cwnd = MIN_WINDOW as f64;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+type Conns = Mutex<HashMap<(SocketAddr, u16), Arc<Mutex<Conn>>>>;
+
+struct Inner {
+    socket: UdpSocket,
+    start: Instant,
+    // keyed by remote address and the id its packets carry
+    conns: Conns,
+    incoming: Sender<(UtpStream, SocketAddr)>,
+    outgoing: Outgoing,
+    // dht packets start with 'd', which is no valid utp version
+    foreign: Sender<(Vec<u8>, SocketAddr)>,
+}
+
+impl Inner {
+    fn on_packet(self: &Arc<Self>, buf: &[u8], addr: SocketAddr) {
+        let Some((h, payload)) = Header::from_bytes(buf) else {
+            #[allow(unused_must_use)]
+            {
+                self.foreign.try_send((buf.to_vec(), addr));
+            }
+            return;
+        };
+        if h.kind == PacketType::Syn {
+            return self.on_syn(&h, addr);
+        }
+        let conn = self.conns.lock().get(&(addr, h.conn_id)).cloned();
+        if let Some(conn) = conn {
+            conn.lock().on_packet(&h, payload);
+        }
+    }
+
+    fn on_syn(self: &Arc<Self>, h: &Header, addr: SocketAddr) {
+        let key = (addr, h.conn_id.wrapping_add(1));
+        let mut conns = self.conns.lock();
+        // syn resent because our state packet got lost
+        if let Some(conn) = conns.get(&key) {
+            let c = conn.lock();
+            c.transmit(PacketType::State, c.seq_nr, &[]);
+            return;
+        }
+        let mut c = Conn::new(
+            self.outgoing.clone(),
+            self.start,
+            addr,
+            key.1,
+            h.conn_id,
+            State::Connected,
+        );
+        c.seq_nr = rand::random();
+        c.ack_nr = h.seq_nr;
+        c.peer_wnd = h.wnd_size as usize;
+        c.reply_micro = c.now_micros().wrapping_sub(h.timestamp);
+        c.transmit(PacketType::State, c.seq_nr, &[]);
+        let conn = Arc::new(Mutex::new(c));
+        conns.insert(key, conn.clone());
+        let stream = UtpStream { conn };
+        #[allow(unused_must_use)]
+        {
+            self.incoming.send((stream, addr));
+        }
+    }
+
+    fn on_tick(&self) {
+        let mut conns = self.conns.lock();
+        conns.retain(|_, c| {
+            let c = c.lock();
+            !c.dropped || (c.state == State::Connected && !c.unacked.is_empty())
+        });
+        for conn in conns.values() {
+            conn.lock().on_tick();
+        }
+    }
+
+    async fn drive(self: Arc<Self>, outgoing: Receiver<(Vec<u8>, SocketAddr)>) {
+        let mut buf = vec![0; 1 << 16];
+        let mut tick = interval(TICK);
+        loop {
+            tokio::select! {
+                res = self.socket.recv_from(&mut buf) => {
+                    // icmp errors of earlier sends show up here, skip them
+                    if let Ok((len, addr)) = res {
+                        self.on_packet(&buf[..len], addr);
+                    }
+                }
+                Ok((packet, addr)) = outgoing.recv_async() => {
+                    // lost datagrams are resent on timeout
+                    #[allow(unused_must_use)]
+                    {
+                        self.socket.send_to(&packet, addr).await;
+                    }
+                }
+                _ = tick.tick() => self.on_tick(),
+            }
+        }
+    }
+}
+
+// udp socket shared by all utp connections of the session, see BEP 29
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+    incoming: Receiver<(UtpStream, SocketAddr)>,
+    foreign: Receiver<(Vec<u8>, SocketAddr)>,
+    driver: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UtpSocket> {
+        let socket = UdpSocket::bind(addr).await?;
+        let (send_incoming, incoming) = flume::unbounded();
+        let (outgoing, get_outgoing) = flume::unbounded();
+        let (send_foreign, foreign) = flume::bounded(MAX_FOREIGN);
+        let inner = Arc::new(Inner {
+            socket,
+            start: Instant::now(),
+            conns: Mutex::new(HashMap::new()),
+            incoming: send_incoming,
+            outgoing,
+            foreign: send_foreign,
+        });
+        let driver = tokio::spawn(inner.clone().drive(get_outgoing));
+        Ok(UtpSocket {
+            inner,
+            incoming,
+            foreign,
+            driver,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    // lets another udp protocol share the socket, it gets every datagram
+    // which is not utp and sends its own through outgoing
+    pub fn share(&self) -> (Receiver<(Vec<u8>, SocketAddr)>, Outgoing) {
+        (self.foreign.clone(), self.inner.outgoing.clone())
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let conn = {
+            let mut conns = self.inner.conns.lock();
+            let recv_id = loop {
+                let id = rand::random();
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut c = Conn::new(
+                self.inner.outgoing.clone(),
+                self.inner.start,
+                addr,
+                recv_id,
+                recv_id.wrapping_add(1),
+                State::SynSent,
+            );
+            c.push(PacketType::Syn, Vec::new());
+            let conn = Arc::new(Mutex::new(c));
+            conns.insert((addr, recv_id), conn.clone());
+            conn
+        };
+        let stream = UtpStream { conn };
+        poll_fn(|cx| {
+            let mut c = stream.conn.lock();
+            match (c.state, c.error) {
+                (_, Some(e)) => Poll::Ready(Err(io::Error::from(e))),
+                (State::Connected, _) => Poll::Ready(Ok(())),
+                _ => {
+                    c.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        self.incoming
+            .recv_async()
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+pub struct UtpStream {
+    conn: Arc<Mutex<Conn>>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.conn.lock().remote
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut c = self.conn.lock();
+        if !c.received.is_empty() {
+            let len = buf.remaining().min(c.received.len());
+            buf.put_slice(&c.received[..len]);
+            c.received.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(e) = c.error {
+            return Poll::Ready(Err(e.into()));
+        }
+        // fin is in order, nothing more to come
+        if c.fin_received {
+            return Poll::Ready(Ok(()));
+        }
+        c.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut c = self.conn.lock();
+        if let Some(e) = c.error {
+            return Poll::Ready(Err(e.into()));
+        }
+        if c.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = buf.len().min(MSS);
+        let window = (c.cwnd as usize).min(c.peer_wnd);
+        // one packet is always allowed in flight, it probes a closed window
+        if c.in_flight > 0 && c.in_flight + len > window {
+            c.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        c.push(PacketType::Data, buf[..len].to_vec());
+        Poll::Ready(Ok(len))
+    }
+
+    // packets leave on write, nothing is buffered
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut c = self.conn.lock();
+        if c.state == State::Connected && !c.fin_sent {
+            c.fin_sent = true;
+            c.push(PacketType::Fin, Vec::new());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut c = self.conn.lock();
+        if c.state == State::Connected && !c.fin_sent {
+            c.fin_sent = true;
+            c.push(PacketType::Fin, Vec::new());
+        }
+        // driver forgets the connection once the fin is acked
+        c.dropped = true;
+    }
+}
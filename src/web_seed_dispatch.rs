@@ -0,0 +1,337 @@
+use std::{
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use flume::{Receiver, Sender};
+use reqwest::{header, StatusCode};
+use thiserror::Error;
+use tokio::{sync::watch, task::JoinHandle, time::sleep};
+
+use crate::{
+    events::{Event, Events},
+    metainfo::MetaInfo,
+    peer_dispatch::RunState,
+    piece::{AddError, Piece},
+    piece_dispatch::PieceDispatch,
+    rate_limit::RateLimiter,
+    storage::Storage,
+    BLOCK_SIZE, NAME,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// wait after a failed piece, doubled for every failure in a row
+const MIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Error, Debug)]
+pub enum Err {
+    #[error("Http request error")]
+    Http(#[from] reqwest::Error),
+    #[error("Http status {0}")]
+    Status(StatusCode),
+    #[error("Server busy")]
+    Busy(Option<Duration>),
+    #[error("Response length mismatch")]
+    Length,
+    #[error("Piece hash mismatch")]
+    HashMismatch,
+    #[error("Bad seed url")]
+    Url,
+}
+
+// how a seed finds the data of a piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    // files by path, see BEP 19; single file torrents may use the url
+    // of the file itself
+    UrlList { multi_file: bool },
+    // piece index and ranges in the query, see BEP 17
+    HttpSeed,
+}
+
+// http sources of the url-list, see BEP 19; ftp ones are skipped
+pub fn url_list(torrent: &MetaInfo) -> Vec<String> {
+    http_urls(&torrent.url_list)
+}
+
+// seeds of the httpseeds list, see BEP 17
+pub fn http_seeds(torrent: &MetaInfo) -> Vec<String> {
+    http_urls(&torrent.http_seeds)
+}
+
+fn http_urls(urls: &[String]) -> Vec<String> {
+    urls.iter()
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .cloned()
+        .collect()
+}
+
+// everything the worker of one web seed needs
+struct WebSeed {
+    url: String,
+    info_hash: [u8; 20],
+    kind: Kind,
+    get_piece: Receiver<Piece>,
+    return_piece: Sender<Piece>,
+    verified_piece: Sender<Piece>,
+    storage: Arc<Storage>,
+    control: watch::Receiver<RunState>,
+    downloaded: Arc<AtomicU64>,
+    download_limit: Arc<RateLimiter>,
+    events: Events,
+}
+
+pub struct WebSeedDispatch {
+    pub url: String,
+    pub info_hash: [u8; 20],
+    pub kind: Kind,
+}
+
+impl WebSeedDispatch {
+    pub fn new(url: String, info_hash: [u8; 20], kind: Kind) -> Self {
+        WebSeedDispatch {
+            url,
+            info_hash,
+            kind,
+        }
+    }
+
+    // takes pieces from the same queue as the peers, downloaded bytes
+    // count and are limited like those of peers
+    pub fn run(
+        &self,
+        piece_dispatch: &PieceDispatch,
+        control: watch::Receiver<RunState>,
+        downloaded: Arc<AtomicU64>,
+        download_limit: Arc<RateLimiter>,
+        events: Events,
+    ) -> JoinHandle<()> {
+        let seed = WebSeed {
+            url: self.url.clone(),
+            info_hash: self.info_hash,
+            kind: self.kind,
+            get_piece: piece_dispatch.rx.clone(),
+            return_piece: piece_dispatch.tx.clone(),
+            verified_piece: piece_dispatch.verified_tx.clone(),
+            storage: piece_dispatch.storage.clone(),
+            control,
+            downloaded,
+            download_limit,
+            events,
+        };
+        tokio::spawn(Self::worker(seed))
+    }
+
+    async fn worker(mut seed: WebSeed) {
+        let client = match reqwest::Client::builder()
+            .user_agent(NAME)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+        {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let state = *seed.control.borrow_and_update();
+            match state {
+                RunState::Running => {}
+                RunState::Stopped => return,
+                RunState::Paused => {
+                    if seed.control.changed().await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+            let mut piece = tokio::select! {
+                piece = seed.get_piece.recv_async() => match piece {
+                    Ok(piece) => piece,
+                    Err(_) => return,
+                },
+                changed = seed.control.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let fetched = match seed.kind {
+                Kind::UrlList { multi_file } => {
+                    Self::fetch_files(&seed, &client, &mut piece, multi_file).await
+                }
+                Kind::HttpSeed => Self::fetch_piece(&seed, &client, &mut piece).await,
+            };
+            let fetched = match fetched {
+                Ok(()) if !piece.complete => Err(Err::Length),
+                fetched => fetched,
+            };
+            match fetched {
+                Ok(()) => {
+                    backoff = MIN_BACKOFF;
+                    #[allow(unused_must_use)]
+                    {
+                        seed.verified_piece.send(piece);
+                    }
+                }
+                Err(e) => {
+                    #[allow(unused_must_use)]
+                    {
+                        seed.return_piece.send(piece);
+                    }
+                    seed.events.emit(Event::WebSeedFailed {
+                        info_hash: seed.info_hash,
+                        url: seed.url.clone(),
+                        message: e.to_string(),
+                    });
+                    let wait = match e {
+                        Err::Busy(Some(wait)) => wait.min(MAX_BACKOFF),
+                        _ => backoff,
+                    };
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    // one range request per file the piece spans
+    async fn fetch_files(
+        seed: &WebSeed,
+        client: &reqwest::Client,
+        piece: &mut Piece,
+        multi_file: bool,
+    ) -> Result<(), Err> {
+        let offset = piece.index as u64 * seed.storage.piece_length;
+        // pad files are zero and not on the server
+        let mut data = vec![0; piece.len as usize];
+        for (file, file_offset, len) in seed.storage.parts(offset, piece.len as u64) {
+            seed.download_limit.acquire(len).await;
+            let r = client
+                .get(file_url(&seed.url, multi_file, &file.path))
+                .header(
+                    header::RANGE,
+                    format!("bytes={}-{}", file_offset, file_offset + len - 1),
+                )
+                .send()
+                .await?;
+            match r.status() {
+                StatusCode::PARTIAL_CONTENT => {}
+                // servers ignoring the range send the whole file
+                StatusCode::OK if file_offset == 0 && len == file.len => {}
+                StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => {
+                    return Err(Err::Busy(retry_after(&r)))
+                }
+                status => return Err(Err::Status(status)),
+            }
+            if r.content_length().is_some_and(|l| l != len) {
+                return Err(Err::Length);
+            }
+            let body = r.bytes().await?;
+            if body.len() as u64 != len {
+                return Err(Err::Length);
+            }
+            seed.downloaded.fetch_add(len, Ordering::Relaxed);
+            let start = (file.offset + file_offset - offset) as usize;
+            data[start..start + len as usize].copy_from_slice(&body);
+        }
+        // blocks a peer left in the piece are replaced, so a bad one is
+        // not blamed on the seed
+        piece.blocks.clear();
+        add_blocks(piece, 0, &data)
+    }
+
+    // the blocks still missing, as ranges of the piece
+    async fn fetch_piece(
+        seed: &WebSeed,
+        client: &reqwest::Client,
+        piece: &mut Piece,
+    ) -> Result<(), Err> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for b in piece.unfinished_blocks() {
+            match ranges.last_mut() {
+                Some((_, end)) if *end == b.begin => *end += b.len,
+                _ => ranges.push((b.begin, b.begin + b.len)),
+            }
+        }
+        let len = ranges.iter().map(|(begin, end)| (end - begin) as u64).sum();
+        let ranges = ranges
+            .iter()
+            .map(|(begin, end)| format!("{}-{}", begin, end - 1))
+            .collect::<Vec<_>>()
+            .join(",");
+        let index = piece.index.to_string();
+        let params = [("piece", index.as_str()), ("ranges", ranges.as_str())];
+
+        // info_hash is binary and must not be encoded by url serializer
+        let info_hash = urlencoding::encode_binary(&seed.info_hash).into_owned();
+        let url = reqwest::Url::parse_with_params(&seed.url, &params).map_err(|_| Err::Url)?;
+        let url = reqwest::Url::parse(&format!("{}&info_hash={}", url, info_hash))
+            .map_err(|_| Err::Url)?;
+
+        seed.download_limit.acquire(len).await;
+        let r = client.get(url).send().await?;
+        match r.status() {
+            StatusCode::OK => {}
+            // the body tells the seconds to wait
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let header = retry_after(&r);
+                let body = r.text().await.unwrap_or_default();
+                let wait = body.trim().parse().ok().map(Duration::from_secs);
+                return Err(Err::Busy(wait.or(header)));
+            }
+            status => return Err(Err::Status(status)),
+        }
+        let body = r.bytes().await?;
+        if body.len() as u64 != len {
+            return Err(Err::Length);
+        }
+        seed.downloaded.fetch_add(len, Ordering::Relaxed);
+        let mut rest = &body[..];
+        for b in piece.unfinished_blocks() {
+            let (block, tail) = rest.split_at(b.len as usize);
+            add_blocks(piece, b.begin, block)?;
+            rest = tail;
+        }
+        Ok(())
+    }
+}
+
+// data starting at begin of the piece, cut into blocks
+fn add_blocks(piece: &mut Piece, begin: u32, data: &[u8]) -> Result<(), Err> {
+    for (i, block) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+        let begin = begin + i as u32 * BLOCK_SIZE;
+        if let Err(AddError::HashMismatch) = piece.add(begin, block.to_vec()) {
+            return Err(Err::HashMismatch);
+        }
+    }
+    Ok(())
+}
+
+// delay in seconds, http dates are not worth parsing here
+fn retry_after(r: &reqwest::Response) -> Option<Duration> {
+    let secs = r.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    secs.trim().parse().ok().map(Duration::from_secs)
+}
+
+// single file torrents are at the url itself unless it ends with a
+// slash, otherwise the file path is appended
+fn file_url(url: &str, multi_file: bool, path: &Path) -> String {
+    if !multi_file && !url.ends_with('/') {
+        return url.to_string();
+    }
+    let path = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(urlencoding::encode(&c.to_string_lossy()).into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{}/{}", url.trim_end_matches('/'), path)
+}